@@ -0,0 +1,73 @@
+use std::io::Cursor;
+use image::{AnimationDecoder, DynamicImage, Frames, ImageFormat, codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder}, imageops::FilterType};
+
+use crate::video::{FRAME_CNT, FRAME_SIDE};
+
+// how much longer one animation may play than another, or how many more frames it may have, for them to still match
+const LENGTH_TOLERANCE: f64 = 0.2;
+
+const ANIMATED_EXTENSIONS: &[&str] = &["apng", "gif", "png", "webp"];
+
+// what an animated GIF, APNG or WebP is hashed and matched by, besides its frames
+pub struct Animation {
+    pub frames: Vec<DynamicImage>, // at most FRAME_CNT, sampled evenly over the time it plays
+    pub frame_cnt: i64,
+    pub duration: f64, // seconds, 0 if it doesn't say how long frames show
+}
+
+// files which might hold more than one frame
+pub fn may_be_animated(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|x| ANIMATED_EXTENSIONS.contains(&x.to_string_lossy().to_lowercase().as_str()))
+}
+
+fn frames(file_bytes: &[u8]) -> Option<Frames<'_>> {
+    let cursor = Cursor::new(file_bytes);
+    match image::guess_format(file_bytes).ok()? {
+        ImageFormat::Gif => Some(GifDecoder::new(cursor).ok()?.into_frames()),
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(cursor).ok()?;
+            decoder.is_apng().then(|| decoder.apng().into_frames())
+        },
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(cursor).ok()?;
+            decoder.has_animation().then(|| decoder.into_frames())
+        },
+        _ => None,
+    }
+}
+
+// decodes at most two frames, most files being stills
+pub fn is_animated(file_bytes: &[u8]) -> bool {
+    frames(file_bytes).is_some_and(|x| x.take(2).filter(Result::is_ok).count() == 2)
+}
+
+// None for stills, which are hashed like any image
+pub fn decode(file_bytes: &[u8]) -> Option<Animation> {
+    // every frame shrunk to grey right away, hashes being of brightness only, along with when it starts showing
+    let mut shown: Vec<(f64, DynamicImage)> = vec![];
+    let mut start = 0.0;
+    // a truncated animation still plays up to where it breaks off
+    for frame in frames(file_bytes)?.map_while(Result::ok) {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let small = DynamicImage::ImageRgba8(frame.into_buffer()).resize_exact(FRAME_SIDE, FRAME_SIDE, FilterType::Triangle).into_luma8();
+        shown.push((start, DynamicImage::ImageLuma8(small)));
+        start += numer as f64 / denom as f64 / 1000.0;
+    }
+    if shown.len() < 2 {
+        return None
+    }
+    let (frame_cnt, duration) = (shown.len(), start);
+    // frames showing in the middle of equal parts of the time it plays, so those dropped or merged by re-encoding don't shift the rest
+    let sampled: Vec<usize> = match (frame_cnt > FRAME_CNT, duration > 0.0) {
+        (false, _) => (0..frame_cnt).collect(),
+        (true, true) => (0..FRAME_CNT).map(|idx| shown.partition_point(|x| x.0 <= duration * (idx as f64 + 0.5) / FRAME_CNT as f64) - 1).collect(),
+        (true, false) => (0..FRAME_CNT).map(|idx| (2 * idx + 1) * frame_cnt / (2 * FRAME_CNT)).collect(),
+    };
+    Some(Animation { frames: sampled.into_iter().map(|idx| shown[idx].1.clone()).collect(), frame_cnt: frame_cnt as i64, duration })
+}
+
+// animations are only the same if they play about as long, or have about as many frames where either doesn't say how long
+pub fn lengths_agree(a: (i64, f64), b: (i64, f64)) -> bool {
+    let close = |x: f64, y: f64| (x - y).abs() <= LENGTH_TOLERANCE * x.max(y);
+    if a.1 > 0.0 && b.1 > 0.0 { close(a.1, b.1) } else { close(a.0 as f64, b.0 as f64) }
+}
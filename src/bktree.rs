@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+// bits differing between two phashes, None unless they are the same length, as hashes of different sizes are of different kinds
+pub fn hamming(a: &[u8], b: &[u8]) -> Option<u32> {
+    (a.len() == b.len()).then(|| a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones()).sum())
+}
+
+struct Node {
+    phash: Box<[u8]>,
+    entry_ids: Vec<i64>,
+    children: Vec<(u32, usize)>, // (distance to this node, index into BkTree::nodes)
+}
+
+// BK-tree over phashes of one length, keyed by entry_id.
+// Nodes are never removed; replaced or removed entry_ids are only dropped from `live`
+// and filtered out at query time until the tree is compacted.
+pub struct BkTree {
+    nodes: Vec<Node>,
+    live: HashMap<i64, Box<[u8]>>,
+    stale: usize,
+}
+
+impl BkTree {
+    pub fn new() -> Self {
+        BkTree { nodes: vec![], live: HashMap::new(), stale: 0 }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.live.len()
+    }
+
+    // phashes of another length than those already in the tree are rejected, having no distance to them
+    pub fn insert(&mut self, entry_id: i64, phash: Box<[u8]>) {
+        if self.nodes.first().is_some_and(|x| x.phash.len() != phash.len()) {
+            eprintln!("Not indexing phash of entry {}, it is {} bytes where the others are {}", entry_id, phash.len(), self.nodes[0].phash.len());
+            return;
+        }
+        match self.live.get(&entry_id) {
+            Some(old) if *old == phash => return,
+            Some(_) => self.stale += 1,
+            None => (),
+        }
+        self.live.insert(entry_id, phash.clone());
+        self.insert_node(entry_id, phash);
+        self.maybe_compact();
+    }
+
+    pub fn remove(&mut self, entry_id: i64) {
+        if self.live.remove(&entry_id).is_some() {
+            self.stale += 1;
+            self.maybe_compact();
+        }
+    }
+
+    // all live entries within max_dist of phash, as (entry_id, distance), none if phash is of another length
+    pub fn find_within(&self, phash: &[u8], max_dist: u32) -> Vec<(i64, u32)> {
+        let mut found = vec![];
+        match self.nodes.first() {
+            Some(root) if root.phash.len() == phash.len() => (),
+            _ => return found,
+        }
+        let mut to_visit = vec![0];
+        while let Some(idx) = to_visit.pop() {
+            let node = &self.nodes[idx];
+            let dist = hamming(&node.phash, phash).unwrap();
+            if dist <= max_dist {
+                for entry_id in &node.entry_ids {
+                    if self.live.get(entry_id).is_some_and(|x| *x == node.phash) {
+                        found.push((*entry_id, dist));
+                    }
+                }
+            }
+            for (child_dist, child_idx) in &node.children {
+                if dist.abs_diff(*child_dist) <= max_dist {
+                    to_visit.push(*child_idx);
+                }
+            }
+        }
+        found
+    }
+
+    fn insert_node(&mut self, entry_id: i64, phash: Box<[u8]>) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node { phash, entry_ids: vec![entry_id], children: vec![] });
+            return;
+        }
+        let mut idx = 0;
+        loop {
+            let dist = hamming(&self.nodes[idx].phash, &phash).unwrap();
+            if dist == 0 {
+                if !self.nodes[idx].entry_ids.contains(&entry_id) {
+                    self.nodes[idx].entry_ids.push(entry_id);
+                }
+                return;
+            }
+            match self.nodes[idx].children.iter().find(|(child_dist, _)| *child_dist == dist) {
+                Some((_, child_idx)) => idx = *child_idx,
+                None => {
+                    let new_idx = self.nodes.len();
+                    self.nodes.push(Node { phash, entry_ids: vec![entry_id], children: vec![] });
+                    self.nodes[idx].children.push((dist, new_idx));
+                    return;
+                }
+            }
+        }
+    }
+
+    // rebuild once dead ids outnumber live ones
+    fn maybe_compact(&mut self) {
+        if self.stale > 1024 && self.stale > self.live.len() {
+            let live = std::mem::take(&mut self.live);
+            self.nodes = vec![];
+            self.stale = 0;
+            for (entry_id, phash) in live.iter() {
+                self.insert_node(*entry_id, phash.clone());
+            }
+            self.live = live;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut found: Vec<(i64, u32)>) -> Vec<(i64, u32)> {
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn hamming_counts_differing_bits() {
+        assert_eq!(hamming(&[0b1010, 0xff], &[0b0110, 0xff]), Some(2));
+        assert_eq!(hamming(&[0x00, 0x00], &[0xff, 0x0f]), Some(12));
+        assert_eq!(hamming(&[0x00], &[0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn finds_within_distance() {
+        let mut tree = BkTree::new();
+        tree.insert(1, Box::new([0b0000_0000, 0]));
+        tree.insert(2, Box::new([0b0000_0001, 0]));
+        tree.insert(3, Box::new([0b0000_0111, 0]));
+        tree.insert(4, Box::new([0b1111_1111, 0xff]));
+        tree.insert(5, Box::new([0b0000_0000, 0])); // same phash as 1
+        assert_eq!(tree.len(), 5);
+        assert_eq!(sorted(tree.find_within(&[0, 0], 0)), vec![(1, 0), (5, 0)]);
+        assert_eq!(sorted(tree.find_within(&[0, 0], 1)), vec![(1, 0), (2, 1), (5, 0)]);
+        assert_eq!(sorted(tree.find_within(&[0, 0], 3)), vec![(1, 0), (2, 1), (3, 3), (5, 0)]);
+        assert_eq!(sorted(tree.find_within(&[0b0000_0111, 0], 2)), vec![(2, 2), (3, 0)]);
+        assert_eq!(tree.find_within(&[0xff, 0xff], 12), vec![(4, 0)]);
+        assert_eq!(sorted(tree.find_within(&[0xff, 0xff], 15)), vec![(2, 15), (3, 13), (4, 0)]);
+        assert_eq!(tree.find_within(&[0xff, 0xff], 16).len(), 5);
+    }
+
+    #[test]
+    fn removed_and_replaced_entries_are_not_found() {
+        let mut tree = BkTree::new();
+        tree.insert(1, Box::new([0b0000_0000]));
+        tree.insert(2, Box::new([0b0000_0011]));
+        tree.insert(3, Box::new([0b0000_1111]));
+        tree.remove(2);
+        assert_eq!(sorted(tree.find_within(&[0], 8)), vec![(1, 0), (3, 4)]);
+        // replacing moves the entry to its new phash
+        tree.insert(3, Box::new([0b1111_1111]));
+        assert_eq!(sorted(tree.find_within(&[0], 4)), vec![(1, 0)]);
+        assert_eq!(sorted(tree.find_within(&[0], 8)), vec![(1, 0), (3, 8)]);
+        tree.remove(1);
+        tree.remove(1);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.find_within(&[0], 8), vec![(3, 8)]);
+    }
+
+    #[test]
+    fn survives_compaction() {
+        let mut tree = BkTree::new();
+        for entry_id in 0..3000 {
+            tree.insert(entry_id, Box::new([(entry_id % 256) as u8, 0]));
+        }
+        for entry_id in 0..2000 {
+            tree.remove(entry_id);
+        }
+        assert_eq!(tree.len(), 1000);
+        let found = tree.find_within(&[0, 0], 0);
+        assert_eq!(sorted(found).iter().map(|x| x.0).collect::<Vec<_>>(), (2000..3000).filter(|x| x % 256 == 0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn mismatched_lengths_are_rejected() {
+        let mut tree = BkTree::new();
+        tree.insert(1, Box::new([0, 0]));
+        tree.insert(2, Box::new([0, 0, 0, 0]));
+        assert_eq!(tree.len(), 1);
+        assert!(tree.find_within(&[0, 0, 0, 0], 32).is_empty());
+        assert_eq!(tree.find_within(&[0, 0], 0), vec![(1, 0)]);
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::OnceLock;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::settings::{get_setting, set_setting};
+
+// how much of the machine updates running at once may take, so a folder of huge images doesn't run it out of memory
+#[derive(Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub memory_mb: u32, // files read and images decoded at once, those that would take more than all of it are skipped
+    pub decodes: u32, // images decoded at once
+    pub max_megapixels: u32, // larger images are skipped without decoding them
+    pub downscale: bool, // JPEGs decoded at a fraction of their size where they are only hashed
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(4, |x| x.get() as u32);
+        Budget { memory_mb: 2048, decodes: cpus, max_megapixels: 250, downscale: false }
+    }
+}
+
+impl Budget {
+    pub async fn load(db_pool: &sqlx::SqlitePool) -> Self {
+        let default = Budget::default();
+        let get = |key| async move { get_setting(db_pool, key).await.and_then(|x| x.parse::<u32>().ok()).filter(|x| *x > 0) };
+        Budget {
+            memory_mb: get("memory_budget_mb").await.unwrap_or(default.memory_mb),
+            decodes: get("max_decodes").await.unwrap_or(default.decodes),
+            max_megapixels: get("max_megapixels").await.unwrap_or(default.max_megapixels),
+            downscale: get_setting(db_pool, "downscale_jpegs").await.map_or(default.downscale, |x| x == "true"),
+        }
+    }
+
+    pub async fn save(&self, db_pool: &sqlx::SqlitePool) {
+        set_setting(db_pool, "memory_budget_mb", &self.memory_mb.to_string()).await;
+        set_setting(db_pool, "max_decodes", &self.decodes.to_string()).await;
+        set_setting(db_pool, "max_megapixels", &self.max_megapixels.to_string()).await;
+        set_setting(db_pool, "downscale_jpegs", if self.downscale { "true" } else { "false" }).await;
+    }
+
+    // stored with files too large for it, so those are tried again once it changes
+    pub fn signature(&self) -> String {
+        format!("budget {} MiB {} MP{}", self.memory_mb, self.max_megapixels, if self.downscale { " downscaled" } else { "" })
+    }
+
+    // for image's decoders, which check it against what they are about to allocate
+    pub fn limits(&self) -> image::io::Limits {
+        let mut limits = image::io::Limits::default();
+        limits.max_alloc = Some(self.memory_mb as u64 * 1024 * 1024);
+        limits
+    }
+}
+
+struct Limiter {
+    budget: Budget,
+    memory: Semaphore, // a permit per MiB
+    decodes: Semaphore,
+}
+
+static LIMITER: OnceLock<Limiter> = OnceLock::new();
+
+// sets the budget for the rest of the process, before any update runs, so changing it takes a restart of the GUI
+// external decoders (ffmpeg, magick and the like) run outside of it: a decode slot is held while one runs and what it
+// writes out is held to the pixel and allocation limits, but the memory the process itself takes is not counted
+pub fn configure(budget: Budget) {
+    let _ = LIMITER.set(Limiter { budget, memory: Semaphore::new(budget.memory_mb as usize), decodes: Semaphore::new(budget.decodes as usize) });
+}
+
+fn limiter() -> &'static Limiter {
+    LIMITER.get_or_init(|| {
+        let budget = Budget::default();
+        Limiter { budget, memory: Semaphore::new(budget.memory_mb as usize), decodes: Semaphore::new(budget.decodes as usize) }
+    })
+}
+
+pub fn budget() -> Budget {
+    limiter().budget
+}
+
+// memory for reading and decoding a file, taken all at once so updates can't each hold part of what the others wait for,
+// None if it would take more than the whole budget
+pub async fn reserve(bytes: u64) -> Option<SemaphorePermit<'static>> {
+    let limiter = limiter();
+    let mb = bytes.div_ceil(1024 * 1024).max(1);
+    if mb > limiter.budget.memory_mb as u64 {
+        return None
+    }
+    Some(limiter.memory.acquire_many(mb as u32).await.expect("Memory budget closed!"))
+}
+
+pub async fn decode_slot() -> SemaphorePermit<'static> {
+    limiter().decodes.acquire().await.expect("Decode slots closed!")
+}
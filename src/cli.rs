@@ -0,0 +1,639 @@
+use std::{path::{Path, PathBuf}, collections::HashSet, sync::{Arc, RwLock, mpsc}};
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use sqlx::{Row, Acquire};
+
+use crate::bktree::BkTree;
+use crate::budget::Budget;
+use crate::dispose::{DisposalMethod, dispose_journaled, restore_quarantine, verify_dupes};
+use crate::gui::{BinDupeMessage, HashDupeMessage, KeepWhichFile};
+use crate::hashkind::{HashAlgorithm, HashKind, Transform, match_transforms, set_match_transforms, stored_kinds};
+use crate::index::{HashIndexer, HashIndexError};
+use crate::journal::{Journal, list_operations, undo_last};
+use crate::pipeline::{self, Throughput};
+use crate::volume;
+use crate::watcher::{DirWatcher, WatchEvent, apply_events};
+
+#[derive(Parser)]
+#[command(name = "refsto", version, about = "Refsto -- refine your storage", long_about = "Refsto -- refine your storage\n\nRuns the GUI when no subcommand is given.")]
+pub struct Cli {
+    /// Print one JSON object per line instead of human-readable output
+    #[arg(long, global = true)]
+    pub json: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Manage watched directories
+    #[command(subcommand)]
+    Watch(WatchCommand),
+    /// Walk watched directories and update/re-hash their files into the database
+    Scan,
+    /// Remove database entries for files that no longer exist, skipping watched directories that are unreachable
+    CleanMissing {
+        /// Only list the entries that would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Find duplicated images and videos
+    #[command(subcommand)]
+    Dupes(DupesCommand),
+    /// Move quarantined files back to where they were disposed of from
+    Restore {
+        /// Quarantine directory, defaults to the one last used
+        dir: Option<PathBuf>,
+    },
+    /// Reverse the last destructive operation wherever its files can still be recovered
+    Undo,
+    /// List past destructive operations and the files they touched, latest first
+    Log {
+        /// Number of operations to list
+        #[arg(long, default_value_t = 10)]
+        limit: i64,
+    },
+    /// Show or change the perceptual hash similar images are found by, rehashing happens on the next scan
+    Hashing {
+        #[arg(long, value_enum)]
+        alg: Option<HashAlgorithm>,
+        /// Hash is size x size bits
+        #[arg(long)]
+        size: Option<u32>,
+        /// Preprocess with a DCT, holds up better against recompression and worse against crops
+        #[arg(long)]
+        dct: bool,
+        #[arg(long, conflicts_with = "dct")]
+        no_dct: bool,
+        /// Also match rotated and mirrored copies, hashing every variant of each image
+        #[arg(long)]
+        transforms: bool,
+        #[arg(long, conflicts_with = "transforms")]
+        no_transforms: bool,
+    },
+    /// Show or change how much memory and how many decodes hashing may take at once, applying from the next scan or start of the GUI
+    Budget {
+        /// MiB for files being read and decoded at once, files that would take more than all of it are skipped,
+        /// what external decoders like ffmpeg and magick take themselves is not counted
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        memory: Option<u32>,
+        /// Images decoded at once, the number of CPUs unless set
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        decodes: Option<u32>,
+        /// Images with more megapixels are skipped without decoding them, until the budget changes
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        max_megapixels: Option<u32>,
+        /// Decode JPEGs at a fraction of their size when hashing them, their hashes differing slightly from full size ones
+        #[arg(long)]
+        downscale: bool,
+        #[arg(long, conflicts_with = "downscale")]
+        no_downscale: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum WatchCommand {
+    /// Add a directory to the library
+    Add { dir: PathBuf },
+    /// Remove a directory from the library, keeping its entries
+    Remove {
+        dir: PathBuf,
+        /// Also remove the entries beneath it that no other watched directory covers
+        #[arg(long)]
+        purge: bool,
+    },
+    /// List watched directories
+    List,
+    /// Keep the database up to date with changes in watched directories until interrupted
+    Run,
+}
+
+#[derive(Subcommand)]
+pub enum DupesCommand {
+    /// List sets of byte-identical files
+    Exact {
+        /// Which duplicate should be kept, listed first in each set
+        #[arg(long, value_enum, default_value = "created-first")]
+        keep: KeepWhichFile,
+        /// Switches keep order, i.e.: created-first -> created last
+        #[arg(long)]
+        reverse: bool,
+        /// Also include duplicated non-image files hashed in database
+        #[arg(long)]
+        include_ignored: bool,
+        /// Dispose of every file of each set but the kept one
+        #[arg(long)]
+        delete: bool,
+        /// How to dispose of files, defaults to the method last used
+        #[arg(long, value_enum, requires = "delete")]
+        method: Option<DisposalArg>,
+        /// Directory disposed files are moved beneath, mirroring their original paths
+        #[arg(long, requires = "delete")]
+        quarantine_dir: Option<PathBuf>,
+    },
+    /// List sets of perceptually similar images
+    Similar {
+        /// Maximum Hamming distance between perceptual hashes
+        #[arg(long, default_value_t = 0)]
+        distance: u32,
+        /// Only list indexed images similar to this one, or videos if it is a video, closest first
+        #[arg(long)]
+        to: Option<PathBuf>,
+        /// Start each set with the file this would keep, transforms being relative to it
+        #[arg(long, value_enum, conflicts_with = "to")]
+        keep: Option<KeepWhichFile>,
+        /// Switches keep order, i.e.: created-first -> created last
+        #[arg(long, requires = "keep")]
+        reverse: bool,
+    },
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum DisposalArg {
+    Trash,
+    Quarantine,
+    Delete,
+    Link,
+}
+
+// returns the process exit code
+pub async fn run(command: Command, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    for (from, to) in volume::reconcile(&db_pool).await {
+        eprintln!("Volume of {} is now mounted at {}, following it", from.to_string_lossy(), to.to_string_lossy());
+    }
+    match command {
+        Command::Watch(WatchCommand::Add { dir }) => watch_add(dir, json, db_pool).await,
+        Command::Watch(WatchCommand::Remove { dir, purge }) => watch_remove(dir, purge, json, db_pool).await,
+        Command::Watch(WatchCommand::List) => watch_list(json, db_pool).await,
+        Command::Watch(WatchCommand::Run) => watch_run(json, db_pool).await,
+        Command::Scan => scan(json, db_pool).await,
+        Command::CleanMissing { dry_run } => clean_missing(dry_run, json, db_pool).await,
+        Command::Dupes(DupesCommand::Exact { keep, reverse, include_ignored, delete, method, quarantine_dir }) => {
+            let disposal = if delete {
+                match disposal_method(method, quarantine_dir, &db_pool).await {
+                    Ok(disposal_method) => Some(disposal_method),
+                    Err(e) => { eprintln!("{}", e); return 1 },
+                }
+            } else {
+                None
+            };
+            dupes_exact(keep, reverse, include_ignored, disposal, json, db_pool).await
+        },
+        Command::Dupes(DupesCommand::Similar { distance, to: Some(file), .. }) => similar_to(file, distance, json, db_pool).await,
+        Command::Dupes(DupesCommand::Similar { distance, to: None, keep, reverse }) => dupes_similar(distance, keep.map(|x| (x, reverse)), json, db_pool).await,
+        Command::Restore { dir } => restore(dir, json, db_pool).await,
+        Command::Undo => undo(json, db_pool).await,
+        Command::Log { limit } => log(limit, json, db_pool).await,
+        Command::Hashing { alg, size, dct, no_dct, transforms, no_transforms } => hashing(alg, size, (dct || no_dct).then_some(dct), (transforms || no_transforms).then_some(transforms), json, db_pool).await,
+        Command::Budget { memory, decodes, max_megapixels, downscale, no_downscale } => decode_budget(memory, decodes, max_megapixels, (downscale || no_downscale).then_some(downscale), json, db_pool).await,
+    }
+}
+
+// resolves the requested disposal method against the saved one, saving the result
+async fn disposal_method(method: Option<DisposalArg>, quarantine_dir: Option<PathBuf>, db_pool: &sqlx::SqlitePool) -> Result<DisposalMethod, String> {
+    let saved = DisposalMethod::load(db_pool).await;
+    let quarantine_dir = match quarantine_dir {
+        Some(dir) => Some(std::path::absolute(&dir).map_err(|e| format!("Invalid quarantine directory {}: {}", dir.to_string_lossy(), e))?),
+        None => crate::settings::get_setting(db_pool, "quarantine_dir").await.map(PathBuf::from),
+    };
+    let method = match (method, quarantine_dir) {
+        (None, Some(dir)) if matches!(saved, DisposalMethod::Quarantine(_)) => DisposalMethod::Quarantine(dir),
+        (None, _) => saved,
+        (Some(DisposalArg::Trash), _) => DisposalMethod::Trash,
+        (Some(DisposalArg::Quarantine), Some(dir)) => DisposalMethod::Quarantine(dir),
+        (Some(DisposalArg::Quarantine), None) => return Err("--method quarantine needs --quarantine-dir".into()),
+        (Some(DisposalArg::Delete), _) => DisposalMethod::Delete,
+        (Some(DisposalArg::Link), _) => DisposalMethod::Link,
+    };
+    method.save(db_pool).await;
+    Ok(method)
+}
+
+async fn get_watched_dirs(db_pool: &sqlx::SqlitePool) -> Vec<PathBuf> {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    sqlx::query("SELECT fullpath FROM watched_dirs ORDER BY fullpath").fetch_all(conn.acquire().await.unwrap()).await
+        .expect("SELECT watched_dirs from database failed!")
+        .iter().map(|x| PathBuf::from(x.get::<String,_>("fullpath"))).collect()
+}
+
+async fn watch_add(dir: PathBuf, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let dir = match dir.canonicalize() {
+        Ok(dir) if dir.is_dir() => dir,
+        _ => {
+            eprintln!("{} is not a directory!!", dir.to_string_lossy());
+            return 1
+        },
+    };
+    let (volume_id, volume_path) = volume::locate(&dir).unzip();
+    let mut conn = db_pool.acquire().await.unwrap();
+    match sqlx::query("INSERT INTO watched_dirs (fullpath, volume_id, volume_path) VALUES (?, ?, ?)").bind(dir.to_str()).bind(volume_id).bind(volume_path.map(|x| x.to_string_lossy().to_string())).execute(conn.acquire().await.unwrap()).await {
+        Ok(_) => {
+            if json {
+                println!("{}", json!({"added": dir.to_string_lossy()}));
+            } else {
+                println!("Added {}", dir.to_string_lossy());
+            }
+            0
+        },
+        Err(e) => {
+            eprintln!("Could not add {}: {}", dir.to_string_lossy(), e);
+            1
+        },
+    }
+}
+
+async fn watch_remove(dir: PathBuf, purge: bool, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let dir = dir.canonicalize().unwrap_or(dir);
+    let mut conn = db_pool.acquire().await.unwrap();
+    match sqlx::query("DELETE FROM watched_dirs WHERE fullpath=?").bind(dir.to_str()).execute(conn.acquire().await.unwrap()).await {
+        Ok(x) if x.rows_affected() > 0 => {
+            let mut purged = 0;
+            if purge {
+                let hi = HashIndexer::new(db_pool.clone());
+                let other_dirs: HashSet<PathBuf> = get_watched_dirs(&db_pool).await.into_iter().collect();
+                purged = hi.forget(&hi.purgeable(&dir, &other_dirs).await, &Journal::begin(&db_pool, "purge")).await;
+            }
+            if json {
+                println!("{}", json!({"removed": dir.to_string_lossy(), "purged": purged}));
+            } else if purge {
+                println!("Removed {}, purged {} entries beneath it", dir.to_string_lossy(), purged);
+            } else {
+                println!("Removed {}", dir.to_string_lossy());
+            }
+            0
+        },
+        Ok(_) => {
+            eprintln!("{} is not a watched directory", dir.to_string_lossy());
+            1
+        },
+        Err(e) => {
+            eprintln!("Could not remove {}: {}", dir.to_string_lossy(), e);
+            1
+        },
+    }
+}
+
+async fn watch_list(json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let offline = volume::offline_dirs(&db_pool).await;
+    for dir in get_watched_dirs(&db_pool).await {
+        if json {
+            println!("{}", json!({"dir": dir.to_string_lossy(), "available": dir.is_dir() && !offline.contains(&dir), "offline": offline.contains(&dir)}));
+        } else if offline.contains(&dir) {
+            println!("{} (offline)", dir.to_string_lossy());
+        } else if dir.is_dir() {
+            println!("{}", dir.to_string_lossy());
+        } else {
+            println!("{} (unavailable)", dir.to_string_lossy());
+        }
+    }
+    0
+}
+
+async fn watch_run(json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let watcher = match DirWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("Could not watch directories: {}", e);
+            return 1
+        },
+    };
+    let offline = volume::offline_dirs(&db_pool).await;
+    watcher.add_dirs(&db_pool, get_watched_dirs(&db_pool).await.into_iter().filter(|x| !offline.contains(x)).collect()).await;
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let cancel_token = tokio_util::sync::CancellationToken::new();
+    let running_watcher = watcher.clone();
+    let volumes_tx = tx.clone();
+    let volumes_cancel = cancel_token.clone();
+    std::thread::spawn(move || running_watcher.run(tx, cancel_token));
+    tokio::spawn(volume::follow_volumes(db_pool.clone(), Some(watcher), volumes_tx, volumes_cancel, move |moved, offline| {
+        for (from, to) in moved {
+            if json {
+                println!("{}", json!({"volume_moved": from.to_string_lossy(), "to": to.to_string_lossy()}));
+            } else {
+                println!("VOLUME  {} -> {}", from.to_string_lossy(), to.to_string_lossy());
+            }
+        }
+        for dir in offline {
+            if json {
+                println!("{}", json!({"offline": dir.to_string_lossy()}));
+            } else {
+                println!("OFFLINE {}", dir.to_string_lossy());
+            }
+        }
+    }));
+    apply_events(db_pool, Arc::new(RwLock::new(BkTree::new())), rx, |event, image_count| {
+        match (event, json) {
+            (WatchEvent::Changed(path), true) => println!("{}", json!({"changed": path.to_string_lossy(), "images": image_count})),
+            (WatchEvent::Changed(path), false) => println!("CHANGED {}", path.to_string_lossy()),
+            (WatchEvent::Removed(path), true) => println!("{}", json!({"removed": path.to_string_lossy(), "images": image_count})),
+            (WatchEvent::Removed(path), false) => println!("REMOVED {}", path.to_string_lossy()),
+            (WatchEvent::Moved(from, to), true) => println!("{}", json!({"moved": from.to_string_lossy(), "to": to.to_string_lossy(), "images": image_count})),
+            (WatchEvent::Moved(from, to), false) => println!("MOVED   {} -> {}", from.to_string_lossy(), to.to_string_lossy()),
+        }
+    }).await;
+    0
+}
+
+async fn scan(json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let dirs = volume::scanned_dirs(&db_pool, get_watched_dirs(&db_pool).await).await;
+    let throughput = Arc::new(Throughput::default());
+    let (mut updated, mut skipped, mut errors) = (0, 0, 0);
+    pipeline::scan(db_pool, Arc::new(RwLock::new(BkTree::new())), dirs, throughput.clone(), tokio_util::sync::CancellationToken::new(), |entry, result| {
+        match result {
+            Ok(_) => updated += 1,
+            Err(HashIndexError::Format) => skipped += 1,
+            Err(HashIndexError::FileNotFound) => { eprintln!("Skipping missing file '{}'", entry.to_string_lossy()); skipped += 1 },
+            Err(HashIndexError::TooLarge) => { eprintln!("Skipping too large file '{}'", entry.to_string_lossy()); skipped += 1 },
+            Err(HashIndexError::Encoding) => { eprintln!("Encoding Error on file '{}'", entry.to_string_lossy()); errors += 1 },
+            Err(HashIndexError::MalformedDB) => { eprintln!("MalformedDB Error on file '{}'", entry.to_string_lossy()); errors += 1 },
+            Err(HashIndexError::InsertDB) => { eprintln!("InsertDB Error on file '{}'", entry.to_string_lossy()); errors += 1 },
+            Err(HashIndexError::Other) => { eprintln!("Other Error on file '{}'", entry.to_string_lossy()); errors += 1 },
+        }
+    }).await;
+    let discovered = throughput.walk.files();
+    if json {
+        println!("{}", json!({"discovered": discovered, "updated": updated, "skipped": skipped, "errors": errors, "throughput": throughput.json()}));
+    } else {
+        println!("Updated {}/{} files, skipped {} non-image, missing or too large files, {} errors", updated, discovered, skipped, errors);
+        println!("{}", throughput.summary());
+    }
+    if errors > 0 { 1 } else { 0 }
+}
+
+async fn clean_missing(dry_run: bool, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let watched_dirs: HashSet<PathBuf> = get_watched_dirs(&db_pool).await.into_iter().collect();
+    let hi = HashIndexer::new(db_pool.clone());
+    let (missing, unreachable) = hi.find_missing(&watched_dirs).await;
+    for dir in &unreachable {
+        if json {
+            println!("{}", json!({"unreachable": dir.to_string_lossy()}));
+        } else {
+            eprintln!("Skipping {}, it is unreachable or empty", dir.to_string_lossy());
+        }
+    }
+    if dry_run {
+        for file in &missing {
+            if json {
+                println!("{}", json!({"missing": file.to_string_lossy()}));
+            } else {
+                println!("{}", file.to_string_lossy());
+            }
+        }
+        return 0
+    }
+    let deleted = hi.forget(&missing, &Journal::begin(&db_pool, "clean_missing")).await;
+    if json {
+        println!("{}", json!({"deleted": deleted}));
+    } else {
+        println!("Removed {} missing entries from database", deleted);
+    }
+    0
+}
+
+// disposes of the non-kept files of each set if given a disposal method
+async fn dupes_exact(keep: KeepWhichFile, reverse: bool, include_ignored: bool, disposal: Option<DisposalMethod>, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let (tx, rx) = mpsc::channel();
+    let journal = Journal::begin(&db_pool, "dedup");
+    HashIndexer::new(db_pool.clone()).find_bindupes(include_ignored, keep, reverse, tx).await;
+    let mut bin_dupes: Vec<Vec<PathBuf>> = vec![];
+    for msg in rx.try_iter() {
+        match msg {
+            BinDupeMessage::NewSet => bin_dupes.push(vec![]),
+            BinDupeMessage::Entry(path) => bin_dupes.last_mut().expect("Tried inserting to bin_dupes before creating set").push(path),
+        }
+    }
+    let offline = volume::offline_dirs(&db_pool).await;
+    let mut exit_code = 0;
+    if disposal.is_some() {
+        let (verified, unverified) = verify_dupes(&db_pool, &bin_dupes).await;
+        for reason in unverified {
+            if json {
+                println!("{}", json!({"skipped": reason}));
+            } else {
+                eprintln!("Skipping set that failed verification, {}", reason);
+            }
+            exit_code = 1;
+        }
+        bin_dupes = verified;
+    }
+    for set in bin_dupes.iter() {
+        let mut set_iter = set.iter();
+        let kept = set_iter.next().unwrap();
+        let mut disposed = vec![];
+        if let Some(method) = &disposal {
+            for file in set_iter {
+                match dispose_journaled(file, kept, method, &journal).await {
+                    Ok(dest) => disposed.push((file, dest)),
+                    Err(e) => { eprintln!("Could not dispose of {}: {}", file.to_string_lossy(), e); exit_code = 1 },
+                }
+            }
+        }
+        if json {
+            if let Some(method) = &disposal {
+                println!("{}", json!({"keep": kept.to_string_lossy(), "method": method.name(), "disposed": disposed.iter().map(|(file, dest)| json!({"file": file.to_string_lossy(), "moved_to": dest.as_ref().map(|x| x.to_string_lossy())})).collect::<Vec<_>>()}));
+            } else {
+                println!("{}", json!({"keep": kept.to_string_lossy(), "duplicates": set[1..].iter().map(|x| x.to_string_lossy()).collect::<Vec<_>>(), "offline": set.iter().filter(|x| volume::is_offline(x, &offline)).map(|x| x.to_string_lossy()).collect::<Vec<_>>()}));
+            }
+        } else {
+            println!("KEEP   {}{}", kept.to_string_lossy(), offline_note(kept, &offline));
+            for file in set[1..].iter() {
+                match (&disposal, disposed.iter().find(|(x, _)| x == &file)) {
+                    (None, _) => println!("       {}{}", file.to_string_lossy(), offline_note(file, &offline)),
+                    (Some(DisposalMethod::Link), Some(_)) => println!("LINK   {}", file.to_string_lossy()),
+                    (Some(_), Some((_, Some(dest)))) => println!("MOVE   {} -> {}", file.to_string_lossy(), dest.to_string_lossy()),
+                    (Some(_), Some((_, None))) => println!("DELETE {}", file.to_string_lossy()),
+                    (Some(_), None) => (),
+                }
+            }
+            println!();
+        }
+    }
+    exit_code
+}
+
+async fn dupes_similar(distance: u32, keep: Option<(KeepWhichFile, bool)>, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let (tx, rx) = mpsc::channel();
+    HashIndexer::new(db_pool.clone()).cluster(distance, keep, tx).await;
+    let offline = volume::offline_dirs(&db_pool).await;
+    let mut hash_dupes: Vec<Vec<(PathBuf, Transform)>> = vec![];
+    for msg in rx.try_iter() {
+        match msg {
+            HashDupeMessage::NewSet => hash_dupes.push(vec![]),
+            HashDupeMessage::Entry(path, transform) => hash_dupes.last_mut().expect("Tried inserting to hash_dupes before creating set").push((path, transform)),
+        }
+    }
+    for set in hash_dupes.iter() {
+        if json {
+            let mut result = json!({"distance": distance, "files": set.iter().map(|x| x.0.to_string_lossy()).collect::<Vec<_>>(), "transforms": set.iter().map(|x| x.1.name()).collect::<Vec<_>>(), "offline": set.iter().filter(|x| volume::is_offline(&x.0, &offline)).map(|x| x.0.to_string_lossy()).collect::<Vec<_>>()});
+            if keep.is_some() {
+                result["keep"] = json!(set[0].0.to_string_lossy());
+            }
+            println!("{}", result);
+        } else {
+            for (idx, (file, transform)) in set.iter().enumerate() {
+                let prefix = match (keep, idx) {
+                    (None, _) => "",
+                    (Some(_), 0) => "KEEP   ",
+                    (Some(_), _) => "       ",
+                };
+                println!("{}{}{}{}", prefix, file.to_string_lossy(), transform_note(*transform), offline_note(file, &offline));
+            }
+            println!();
+        }
+    }
+    0
+}
+
+async fn similar_to(file: PathBuf, distance: u32, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let file = file.canonicalize().unwrap_or(file);
+    let similar = match HashIndexer::new(db_pool.clone()).similar_to(&file, distance).await {
+        Ok(similar) => similar,
+        Err(HashIndexError::FileNotFound) => { eprintln!("{} not found", file.to_string_lossy()); return 1 },
+        Err(HashIndexError::Format) => { eprintln!("{} is not a supported image or video", file.to_string_lossy()); return 1 },
+        Err(_) => { eprintln!("Querying similar images failed"); return 1 },
+    };
+    let offline = volume::offline_dirs(&db_pool).await;
+    for (similar_file, similar_distance, transform) in similar.iter() {
+        if json {
+            println!("{}", json!({"file": similar_file.to_string_lossy(), "distance": similar_distance, "transform": transform.name(), "offline": volume::is_offline(similar_file, &offline)}));
+        } else {
+            println!("{:>3} {}{}{}", similar_distance, similar_file.to_string_lossy(), transform_note(*transform), offline_note(similar_file, &offline));
+        }
+    }
+    0
+}
+
+fn transform_note(transform: Transform) -> String {
+    if transform == Transform::Identity { String::new() } else { format!(" (same image, {})", transform.describe()) }
+}
+
+fn offline_note(file: &Path, offline: &HashSet<PathBuf>) -> &'static str {
+    if volume::is_offline(file, offline) { " (offline)" } else { "" }
+}
+
+async fn restore(dir: Option<PathBuf>, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let dir = match dir.or(crate::settings::get_setting(&db_pool, "quarantine_dir").await.map(PathBuf::from)) {
+        Some(dir) => dir,
+        None => {
+            eprintln!("No quarantine directory given or saved");
+            return 1
+        },
+    };
+    let dir = match dir.canonicalize() {
+        Ok(dir) if dir.is_dir() => dir,
+        _ => {
+            eprintln!("{} is not a directory!!", dir.to_string_lossy());
+            return 1
+        },
+    };
+    let mut exit_code = 0;
+    for (original, res) in tokio::task::spawn_blocking(move || restore_quarantine(&dir)).await.unwrap() {
+        match res {
+            Ok(_) if json => println!("{}", json!({"restored": original.to_string_lossy()})),
+            Ok(_) => println!("Restored {}", original.to_string_lossy()),
+            Err(e) => { eprintln!("Could not restore {}: {}", original.to_string_lossy(), e); exit_code = 1 },
+        }
+    }
+    exit_code
+}
+
+async fn undo(json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let Some((kind, results)) = undo_last(&db_pool).await else {
+        eprintln!("Nothing to undo");
+        return 1
+    };
+    let mut exit_code = 0;
+    for (fullpath, res) in results {
+        match res {
+            Ok(_) if json => println!("{}", json!({"operation": kind, "restored": fullpath.to_string_lossy()})),
+            Ok(_) => println!("Restored {}", fullpath.to_string_lossy()),
+            Err(e) => { eprintln!("Could not restore {}: {}", fullpath.to_string_lossy(), e); exit_code = 1 },
+        }
+    }
+    exit_code
+}
+
+async fn log(limit: i64, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    for op in list_operations(&db_pool, limit).await {
+        let time = chrono::TimeZone::timestamp_opt(&chrono::Local, op.time, 0).single().map(|x| x.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default();
+        if json {
+            println!("{}", json!({
+                "operation": op.kind,
+                "time": op.time,
+                "undone": op.undone,
+                "files": op.records.iter().map(|x| json!({"action": x.action, "file": x.fullpath.to_string_lossy(), "dest": x.dest.as_ref().map(|x| x.to_string_lossy()), "undone": x.undone})).collect::<Vec<_>>(),
+            }));
+        } else {
+            println!("{} {}{}", time, op.kind, if op.undone { " (undone)" } else { "" });
+            for record in op.records {
+                match record.dest {
+                    Some(dest) => println!("  {:<10} {} -> {}", record.action, record.fullpath.to_string_lossy(), dest.to_string_lossy()),
+                    None => println!("  {:<10} {}", record.action, record.fullpath.to_string_lossy()),
+                }
+            }
+            println!();
+        }
+    }
+    0
+}
+
+// dct and transforms are None where left as they are
+async fn hashing(alg: Option<HashAlgorithm>, size: Option<u32>, dct: Option<bool>, transforms: Option<bool>, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let current = HashKind::load(&db_pool).await;
+    let hash_kind = HashKind::new(alg.unwrap_or(current.alg), size.unwrap_or(current.size), dct.unwrap_or(current.dct));
+    let mut unhashed = 0;
+    if hash_kind != current {
+        match hash_kind.save(&db_pool).await {
+            Ok(x) => unhashed = x,
+            Err(e) => {
+                eprintln!("Could not switch to {}: {:?}", hash_kind.name(), e);
+                return 1
+            },
+        }
+    }
+    let transforms = match transforms {
+        Some(transforms) => transforms,
+        None => match_transforms(&db_pool).await,
+    };
+    let unvaried = set_match_transforms(&db_pool, transforms).await;
+    let stored = stored_kinds(&db_pool).await;
+    if json {
+        println!("{}", json!({"kind": hash_kind.name(), "bits": hash_kind.bits(), "unhashed": unhashed, "transforms": transforms, "unvaried": unvaried, "stored": stored.iter().map(|(kind, cnt)| json!({"kind": kind, "entries": cnt})).collect::<Vec<_>>()}));
+    } else {
+        println!("Hashing with {} ({} bits){}", hash_kind.name(), hash_kind.bits(), if transforms { ", matching rotated and mirrored copies" } else { "" });
+        if unhashed > 0 {
+            println!("{} entries have no hash of this kind yet, run `refsto scan` to hash them", unhashed);
+        }
+        if unvaried > unhashed {
+            println!("{} entries have no hashes of rotated and mirrored variants yet, run `refsto scan` to hash them", unvaried);
+        }
+        for (kind, cnt) in stored {
+            println!("  {:<24} {} entries", kind, cnt);
+        }
+    }
+    0
+}
+
+async fn decode_budget(memory: Option<u32>, decodes: Option<u32>, max_megapixels: Option<u32>, downscale: Option<bool>, json: bool, db_pool: sqlx::SqlitePool) -> i32 {
+    let current = Budget::load(&db_pool).await;
+    let budget = Budget {
+        memory_mb: memory.unwrap_or(current.memory_mb),
+        decodes: decodes.unwrap_or(current.decodes),
+        max_megapixels: max_megapixels.unwrap_or(current.max_megapixels),
+        downscale: downscale.unwrap_or(current.downscale),
+    };
+    if budget != current {
+        budget.save(&db_pool).await;
+    }
+    if json {
+        println!("{}", json!({"memory_mb": budget.memory_mb, "decodes": budget.decodes, "max_megapixels": budget.max_megapixels, "downscale": budget.downscale}));
+    } else {
+        println!("Hashing reads and decodes files within {} MiB, decoding at most {} at once", budget.memory_mb, budget.decodes);
+        println!("Images over {} megapixels are skipped{}", budget.max_megapixels, if budget.downscale { ", JPEGs are decoded downscaled" } else { "" });
+    }
+    0
+}
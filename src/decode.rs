@@ -0,0 +1,236 @@
+use std::{io::{Cursor, Read}, path::{Path, PathBuf}, process::{Command, ExitStatus, Stdio}, sync::{OnceLock, atomic::{AtomicUsize, Ordering::Relaxed}}, time::{Duration, Instant}};
+use image::{DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageResult, codecs::jpeg::JpegDecoder, error::{ImageFormatHint, LimitError, LimitErrorKind, UnsupportedError}};
+
+use crate::budget;
+use crate::exif::{Exif, raf_preview};
+use crate::video;
+
+// camera RAWs are TIFF underneath, which image would take the tiny first thumbnail of if it managed at all
+const RAW_EXTENSIONS: &[&str] = &["3fr", "arw", "cr2", "crw", "dcr", "dng", "erf", "kdc", "mef", "mos", "mrw", "nef", "nrw", "orf", "pef", "raf", "rw2", "sr2", "srf", "srw", "x3f"];
+// brands of ISO media files holding HEIF images, AVIF among them
+const HEIF_BRANDS: &[&[u8]] = &[b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1", b"avif", b"avis"];
+// size downscaled JPEGs are decoded at least at, well above what any hash needs
+const DOWNSCALE_SIDE: u16 = 256;
+// how long an external decoder may take converting one image before it is killed
+const EXTERNAL_TIMEOUT: Duration = Duration::from_secs(60);
+// how often a running external program is checked on
+const EXTERNAL_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Raw,
+    Heif,
+    Jxl,
+    Video,
+    Other, // whatever image can guess from the contents
+}
+
+#[derive(Clone, Copy)]
+enum Decoder {
+    RawPreview, // largest JPEG embedded in a RAW
+    Image,
+    // command line tools converting what image can't read to PNG, used if installed
+    External { program: &'static str, formats: &'static [Format] },
+    Video, // middle frame through ffmpeg, if installed along with ffprobe
+}
+
+const EXTERNAL_DECODERS: &[Decoder] = &[
+    Decoder::External { program: "heif-dec", formats: &[Format::Heif] },
+    Decoder::External { program: "heif-convert", formats: &[Format::Heif] },
+    Decoder::External { program: "djxl", formats: &[Format::Jxl] },
+    Decoder::External { program: "magick", formats: &[Format::Heif, Format::Jxl, Format::Raw] },
+];
+
+impl Decoder {
+    fn name(&self) -> &'static str {
+        match self {
+            Decoder::RawPreview => "raw_preview",
+            Decoder::Image => "image",
+            Decoder::External { program, .. } => program,
+            Decoder::Video => "ffmpeg",
+        }
+    }
+
+    fn accepts(&self, format: Format) -> bool {
+        match self {
+            Decoder::RawPreview => format == Format::Raw,
+            Decoder::Image => true,
+            Decoder::External { formats, .. } => formats.contains(&format),
+            Decoder::Video => format == Format::Video,
+        }
+    }
+
+    fn decode(&self, path: &Path, file_bytes: &[u8], downscale: bool) -> ImageResult<DynamicImage> {
+        match self {
+            Decoder::RawPreview => {
+                let previews = match file_bytes.starts_with(b"FUJIFILM") {
+                    true => raf_preview(file_bytes).into_iter().collect(),
+                    false => Exif::parse(file_bytes).map(|x| x.jpeg_previews()).unwrap_or_default(),
+                };
+                previews.iter().find_map(|x| load_image(x, downscale).ok()).ok_or_else(unsupported)
+            },
+            Decoder::Image => load_image(file_bytes, downscale),
+            Decoder::External { program, .. } => {
+                static CONVERSION_CNT: AtomicUsize = AtomicUsize::new(0);
+                let converted = std::env::temp_dir().join(format!("refsto-{}-{}.png", std::process::id(), CONVERSION_CNT.fetch_add(1, Relaxed)));
+                let mut command = Command::new(program);
+                if *program == "magick" {
+                    // the first frame or page only
+                    command.arg(format!("{}[0]", path.to_string_lossy()));
+                } else {
+                    command.arg(path);
+                }
+                let img = match run_within(command.arg(&converted), EXTERNAL_TIMEOUT) {
+                    Some((status, _)) if status.success() => load_converted(&converted),
+                    _ => Err(unsupported()),
+                };
+                let _ = std::fs::remove_file(&converted);
+                img
+            },
+            Decoder::Video => video::poster(path).ok_or_else(unsupported),
+        }
+    }
+}
+
+// image within the budget's allocation limit, JPEGs at a fraction of their size if downscaling
+fn load_image(file_bytes: &[u8], downscale: bool) -> ImageResult<DynamicImage> {
+    let limits = budget::budget().limits();
+    if downscale && image::guess_format(file_bytes).ok() == Some(ImageFormat::Jpeg) {
+        let mut decoder = JpegDecoder::new(Cursor::new(file_bytes))?;
+        decoder.scale(DOWNSCALE_SIDE, DOWNSCALE_SIDE)?;
+        decoder.set_limits(limits)?;
+        return DynamicImage::from_decoder(decoder)
+    }
+    let mut reader = image::io::Reader::new(Cursor::new(file_bytes)).with_guessed_format()?;
+    reader.limits(limits);
+    reader.decode()
+}
+
+// runs an external program to the end, with what it wrote to stdout, or kills it once it takes longer than timeout,
+// as a truncated or hostile file can keep it busy for good
+pub fn run_within(command: &mut Command, timeout: Duration) -> Option<(ExitStatus, Vec<u8>)> {
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().ok()?;
+    // read alongside, so a program writing more than a pipe holds isn't left waiting on it
+    let mut stdout = child.stdout.take()?;
+    let reading = std::thread::spawn(move || {
+        let mut output = vec![];
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some((status, reading.join().ok()?.ok()?)),
+            Ok(None) if started.elapsed() < timeout => std::thread::sleep(EXTERNAL_POLL),
+            _ => {
+                eprintln!("Killed {:?} after {}s", command.get_program(), timeout.as_secs());
+                let _ = child.kill();
+                let _ = child.wait();
+                return None
+            },
+        }
+    }
+}
+
+// what an external decoder wrote out, held to the budget like any image, its size only being known once it is written
+fn load_converted(path: &Path) -> ImageResult<DynamicImage> {
+    let budget = budget::budget();
+    let (width, height) = image::image_dimensions(path)?;
+    if width as u64 * height as u64 > budget.max_megapixels as u64 * 1_000_000 {
+        return Err(ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError)))
+    }
+    let mut reader = image::io::Reader::open(path)?.with_guessed_format()?;
+    reader.limits(budget.limits());
+    reader.decode()
+}
+
+// the size jpeg-decoder picks when asked for DOWNSCALE_SIDE, the smallest of 1/8, 1/4, 1/2 and full size that is at least that wide or high
+fn jpeg_downscaled(width: u32, height: u32) -> (u32, u32) {
+    let scaled = |len: u32, scale: u32| (len * scale - 1) / 8 + 1;
+    let scale = [1, 2, 4].into_iter().find(|x| scaled(width, *x) >= DOWNSCALE_SIDE as u32 || scaled(height, *x) >= DOWNSCALE_SIDE as u32).unwrap_or(8);
+    (scaled(width, scale), scaled(height, scale))
+}
+
+// pixels in an image as its header says and as it will be decoded, read without reading the rest of the file,
+// None where it isn't image decoding it or the header can't be made sense of
+pub fn pixels(path: &Path, downscale: bool) -> Option<(u64, u64)> {
+    if matches!(format(path, &[]), Format::Raw | Format::Video) {
+        return None
+    }
+    let reader = image::io::Reader::open(path).ok()?.with_guessed_format().ok()?;
+    let jpeg = reader.format() == Some(ImageFormat::Jpeg);
+    let (width, height) = reader.into_dimensions().ok()?;
+    let (decoded_width, decoded_height) = if downscale && jpeg { jpeg_downscaled(width, height) } else { (width, height) };
+    Some((width as u64 * height as u64, decoded_width as u64 * decoded_height as u64))
+}
+
+pub fn unsupported() -> ImageError {
+    ImageError::Unsupported(UnsupportedError::from(ImageFormatHint::Unknown))
+}
+
+fn format(path: &Path, file_bytes: &[u8]) -> Format {
+    let extension = path.extension().map(|x| x.to_string_lossy().to_lowercase()).unwrap_or_default();
+    if RAW_EXTENSIONS.contains(&extension.as_str()) {
+        Format::Raw
+    } else if file_bytes.get(4..8) == Some(b"ftyp") && file_bytes.get(8..12).is_some_and(|x| HEIF_BRANDS.contains(&x)) {
+        Format::Heif
+    } else if file_bytes.starts_with(&[0xFF, 0x0A]) || file_bytes.starts_with(b"\0\0\0\x0CJXL \r\n\x87\n") {
+        Format::Jxl
+    } else if video::is_video(path) {
+        Format::Video
+    } else {
+        Format::Other
+    }
+}
+
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|paths| std::env::split_paths(&paths).any(|dir: PathBuf| dir.join(program).is_file()))
+}
+
+// decoders in the order they are tried, looked up once
+fn decoders() -> &'static [Decoder] {
+    static DECODERS: OnceLock<Vec<Decoder>> = OnceLock::new();
+    DECODERS.get_or_init(|| {
+        [Decoder::RawPreview, Decoder::Image].into_iter()
+            .chain(EXTERNAL_DECODERS.iter().copied().filter(|x| in_path(x.name())))
+            .chain((in_path("ffmpeg") && in_path("ffprobe")).then_some(Decoder::Video))
+            .collect()
+    })
+}
+
+// which decoders there are, stored with files none of them could read so those are retried once there are others
+pub fn signature() -> &'static str {
+    static SIGNATURE: OnceLock<String> = OnceLock::new();
+    SIGNATURE.get_or_init(|| decoders().iter().map(|x| x.name()).collect::<Vec<_>>().join(","))
+}
+
+// Unsupported only if every decoder that could have read the file says so, so that it is ignored rather than reported
+// downscale only where the image is hashed and nothing else
+pub fn decode(path: &Path, file_bytes: &[u8], downscale: bool) -> ImageResult<DynamicImage> {
+    let format = format(path, file_bytes);
+    let mut res = Err(unsupported());
+    for decoder in decoders().iter().filter(|x| x.accepts(format)) {
+        match decoder.decode(path, file_bytes, downscale) {
+            Ok(img) => return Ok(img),
+            Err(ImageError::Unsupported(_)) => (),
+            // image choking on the TIFF structure of a RAW without usable previews says nothing about the file
+            Err(_) if format == Format::Raw => (),
+            Err(e) => res = Err(e),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn external_programs_are_killed_past_their_timeout() {
+        let (status, output) = run_within(Command::new("sh").args(["-c", "echo converted"]), Duration::from_secs(5)).unwrap();
+        assert!(status.success());
+        assert_eq!(output, b"converted\n");
+        let started = Instant::now();
+        assert!(run_within(Command::new("sh").args(["-c", "sleep 10"]), Duration::from_millis(200)).is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,292 @@
+use std::{path::{Path, PathBuf}, ffi::OsString, fs, io, io::{Read, Write}, time::SystemTime, os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd}};
+
+use sqlx::{Row, Acquire};
+
+use crate::journal::Journal;
+use crate::rules::ScanRules;
+use crate::settings::{get_setting, set_setting};
+use crate::walk::walk_dir;
+
+#[derive(Clone, PartialEq)]
+pub enum DisposalMethod {
+    Trash, // freedesktop.org trash of the current user
+    Quarantine(PathBuf), // moved beneath this directory, mirroring the original path
+    Delete,
+    Link, // replaced by a reflink or hardlink to the kept file
+}
+
+impl DisposalMethod {
+    pub fn past_tense(&self) -> &'static str {
+        match self {
+            Self::Trash => "Trashed",
+            Self::Quarantine(_) => "Quarantined",
+            Self::Delete => "Deleted",
+            Self::Link => "Linked",
+        }
+    }
+
+    pub async fn load(db_pool: &sqlx::SqlitePool) -> Self {
+        let quarantine_dir = get_setting(db_pool, "quarantine_dir").await.map(PathBuf::from);
+        match (get_setting(db_pool, "disposal_method").await.as_deref(), quarantine_dir) {
+            (Some("delete"), _) => Self::Delete,
+            (Some("link"), _) => Self::Link,
+            (Some("quarantine"), Some(dir)) => Self::Quarantine(dir),
+            _ => Self::Trash,
+        }
+    }
+
+    pub async fn save(&self, db_pool: &sqlx::SqlitePool) {
+        set_setting(db_pool, "disposal_method", self.name()).await;
+        if let Self::Quarantine(dir) = self {
+            set_setting(db_pool, "quarantine_dir", &dir.to_string_lossy()).await;
+        }
+    }
+
+    // as stored in settings
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Trash => "trash",
+            Self::Quarantine(_) => "quarantine",
+            Self::Delete => "delete",
+            Self::Link => "link",
+        }
+    }
+}
+
+// disposes of every file but the first (kept) one in each set
+// passing each disposed file to on_disposed as it goes, with where it was moved or linked to, if anywhere
+pub async fn dispose_dupes(sets: &[Vec<PathBuf>], method: &DisposalMethod, journal: &Journal, mut on_disposed: impl FnMut(&Path, io::Result<Option<PathBuf>>)) {
+    // sets too short to have a duplicate are reported by verify_dupes
+    for set in sets.iter().filter(|x| x.len() >= 2) {
+        let mut set_iter = set.iter();
+        let kept = set_iter.next().unwrap();
+        eprintln!("KEEP {}", kept.to_string_lossy());
+        for file in set_iter {
+            on_disposed(file, dispose_journaled(file, kept, method, journal).await);
+        }
+    }
+}
+
+// drops every set with a file changed since indexing or not byte-identical to the kept file
+// returns the sets safe to dispose of and why each dropped set was dropped
+pub async fn verify_dupes(db_pool: &sqlx::SqlitePool, sets: &Vec<Vec<PathBuf>>) -> (Vec<Vec<PathBuf>>, Vec<String>) {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    let conn = conn.acquire().await.unwrap();
+    let (mut verified, mut dropped) = (vec![], vec![]);
+    'sets: for set in sets {
+        // a set left with only the file to keep has nothing to dispose of
+        if set.len() < 2 {
+            dropped.push(format!("{}: no duplicates left", set.first().map_or(String::new(), |x| x.to_string_lossy().to_string())));
+            continue
+        }
+        for file in set {
+            let row = sqlx::query("SELECT filesize, mtime FROM entries WHERE fullpath = ?").bind(file.to_string_lossy()).fetch_optional(&mut *conn).await
+                .expect("SELECT from entries failed!");
+            if let Err(reason) = unchanged_since_indexed(file, row.map(|x| (x.get::<Option<i64>,_>("filesize"), x.get::<Option<i64>,_>("mtime")))) {
+                dropped.push(format!("{}: {}", file.to_string_lossy(), reason));
+                continue 'sets
+            }
+        }
+        for file in &set[1..] {
+            if same_inode(&set[0], file) {
+                dropped.push(format!("{}: hardlinked to {}, disposing of it frees nothing", file.to_string_lossy(), set[0].to_string_lossy()));
+                continue 'sets
+            }
+            match same_contents(&set[0], file) {
+                Ok(true) => (),
+                Ok(false) => { dropped.push(format!("{}: contents differ from {}", file.to_string_lossy(), set[0].to_string_lossy())); continue 'sets },
+                Err(e) => { dropped.push(format!("{}: {}", file.to_string_lossy(), e)); continue 'sets },
+            }
+        }
+        verified.push(set.to_owned());
+    }
+    (verified, dropped)
+}
+
+fn unchanged_since_indexed(file: &Path, indexed: Option<(Option<i64>, Option<i64>)>) -> Result<(), String> {
+    let (filesize, mtime) = indexed.ok_or("no longer indexed")?;
+    // restored by undoing a clean up without them, until it is scanned again
+    let (Some(filesize), Some(mtime)) = (filesize, mtime) else { return Err("not scanned since it was restored".into()) };
+    let meta = file.symlink_metadata().map_err(|e| e.to_string())?;
+    if !meta.is_file() {
+        return Err("not a regular file".into())
+    }
+    let modified = meta.modified().map_err(|e| e.to_string())?.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    if meta.len() as i64 != filesize || modified != mtime {
+        return Err("changed since it was indexed".into())
+    }
+    Ok(())
+}
+
+// hardlinked since the last scan
+fn same_inode(a: &Path, b: &Path) -> bool {
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (io::BufReader::new(fs::File::open(a)?), io::BufReader::new(fs::File::open(b)?));
+    let (mut buf_a, mut buf_b) = (vec![0; 1 << 16], vec![0; 1 << 16]);
+    loop {
+        let len = a.read(&mut buf_a)?;
+        if len == 0 {
+            return Ok(b.read(&mut buf_b[..1])? == 0)
+        }
+        match b.read_exact(&mut buf_b[..len]) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            res => res?,
+        }
+        if buf_a[..len] != buf_b[..len] {
+            return Ok(false)
+        }
+    }
+}
+
+// disposes of file, journaling where its data can still be found
+pub async fn dispose_journaled(file: &Path, kept: &Path, method: &DisposalMethod, journal: &Journal) -> io::Result<Option<PathBuf>> {
+    let res = dispose(file, kept, method);
+    if let Ok(dest) = &res {
+        journal.record(method.name(), file, Some(dest.as_deref().unwrap_or(kept))).await;
+    }
+    res
+}
+
+fn dispose(file: &Path, kept: &Path, method: &DisposalMethod) -> io::Result<Option<PathBuf>> {
+    if !file.is_absolute() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "refusing to dispose of relative path"))
+    }
+    match method {
+        DisposalMethod::Trash => trash(file).map(Some),
+        DisposalMethod::Quarantine(dir) => quarantine(file, dir).map(Some),
+        DisposalMethod::Delete => fs::remove_file(file).map(|_| None),
+        DisposalMethod::Link => link(file, kept).map(|_| Some(kept.to_owned())),
+    }
+}
+
+// moves every file beneath dir back to the path it was quarantined from, never overwriting
+pub fn restore_quarantine(dir: &Path) -> Vec<(PathBuf, io::Result<()>)> {
+    let mut quarantined: Vec<PathBuf> = vec![];
+    walk_dir(dir.to_owned(), &ScanRules::default(), |file| { quarantined.push(file); true });
+    quarantined.sort();
+    quarantined.into_iter().map(|file| {
+        let original = Path::new("/").join(file.strip_prefix(dir).unwrap());
+        let res = if original.symlink_metadata().is_ok() {
+            Err(io::Error::new(io::ErrorKind::AlreadyExists, "a file exists at the original path"))
+        } else {
+            fs::create_dir_all(original.parent().unwrap()).and_then(|_| move_file(&file, &original))
+        };
+        (original, res)
+    }).collect()
+}
+
+fn quarantine(file: &Path, dir: &Path) -> io::Result<PathBuf> {
+    let dest = dir.join(file.strip_prefix("/").unwrap());
+    if dest.symlink_metadata().is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already quarantined", dest.to_string_lossy())))
+    }
+    fs::create_dir_all(dest.parent().unwrap())?;
+    move_file(file, &dest)?;
+    Ok(dest)
+}
+
+// replaces file with a reflink of kept, or a hardlink where reflinks are unsupported
+// the link is built next to file and renamed over it, so file is left untouched on failure
+fn link(file: &Path, kept: &Path) -> io::Result<()> {
+    if fs::canonicalize(file)? == fs::canonicalize(kept)? {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "file and kept file are the same"))
+    }
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file.file_name().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?);
+    tmp_name.push(".refsto-link");
+    let tmp = file.with_file_name(tmp_name);
+    reflink(kept, &tmp).or_else(|_| fs::hard_link(kept, &tmp))?;
+    if let Err(e) = fs::rename(&tmp, file) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e)
+    }
+    Ok(())
+}
+
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    let src = fs::File::open(from)?;
+    let dest = fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        let e = io::Error::last_os_error();
+        drop(dest);
+        let _ = fs::remove_file(to);
+        return Err(e)
+    }
+    dest.set_modified(src.metadata()?.modified()?)
+}
+
+// https://specifications.freedesktop.org/trash-spec/trashspec-latest.html
+fn trash(file: &Path) -> io::Result<PathBuf> {
+    let trash_dir = dirs::data_dir().ok_or(io::Error::new(io::ErrorKind::NotFound, "no user data directory"))?.join("Trash");
+    fs::create_dir_all(trash_dir.join("files"))?;
+    fs::create_dir_all(trash_dir.join("info"))?;
+    let file_name = file.file_name().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    for n in 0.. {
+        let trash_name = if n == 0 {
+            file_name.to_os_string()
+        } else {
+            let mut name = Path::new(file_name).file_stem().unwrap().to_os_string();
+            name.push(format!(".{}", n));
+            if let Some(ext) = Path::new(file_name).extension() {
+                name.push(".");
+                name.push(ext);
+            }
+            name
+        };
+        let mut info_name = trash_name.clone();
+        info_name.push(".trashinfo");
+        let info_path = trash_dir.join("info").join(info_name);
+        let dest = trash_dir.join("files").join(&trash_name);
+        // creating the info file first reserves the name
+        let mut info = match fs::OpenOptions::new().write(true).create_new(true).open(&info_path) {
+            Ok(info) => info,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+        if dest.symlink_metadata().is_ok() {
+            let _ = fs::remove_file(&info_path);
+            continue
+        }
+        let res = write!(info, "[Trash Info]\nPath={}\nDeletionDate={}\n", percent_encode(file.as_os_str().to_owned()), chrono::Local::now().format("%Y-%m-%dT%H:%M:%S"))
+            .and_then(|_| move_file(file, &dest));
+        if let Err(e) = res {
+            let _ = fs::remove_file(&info_path);
+            return Err(e)
+        }
+        return Ok(dest)
+    }
+    unreachable!()
+}
+
+fn percent_encode(path: OsString) -> String {
+    path.as_bytes().iter().map(|&b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// rename, falling back to copy and remove across filesystems
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let mtime = from.metadata()?.modified()?;
+            if let Err(e) = fs::copy(from, to) {
+                let _ = fs::remove_file(to);
+                return Err(e)
+            }
+            fs::File::options().write(true).open(to)?.set_modified(mtime)?;
+            fs::remove_file(from)
+        },
+        res => res,
+    }
+}
@@ -0,0 +1,443 @@
+use std::path::Path;
+
+// just enough of EXIF to read what refsto needs, from the TIFF structure embedded in JPEG, PNG and WebP files or TIFF files themselves
+
+const TAG_NEW_SUBFILE_TYPE: u16 = 0x00FE;
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014A;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+const TAG_DATETIME: u16 = 0x0132;
+const TAG_RATING: u16 = 0x4746;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+// in the EXIF IFD
+const TAG_DATETIME_ORIGINAL: u16 = 0x9003;
+const TAG_LENS_MODEL: u16 = 0xA434;
+// in the GPS IFD
+const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+
+pub struct Exif<'a> {
+    tiff: &'a [u8],
+    big_endian: bool,
+    ifd0: usize,
+}
+
+// an IFD entry, its value being inline at value_pos when it fits in 4 bytes and at the offset stored there otherwise
+struct Field {
+    kind: u16,
+    count: u32,
+    value_pos: usize,
+}
+
+impl<'a> Exif<'a> {
+    pub fn parse(file_bytes: &'a [u8]) -> Option<Self> {
+        let tiff = find_tiff(file_bytes)?;
+        let big_endian = match tiff.get(..4)? {
+            // TIFF, and the variants Olympus and Panasonic RAWs have
+            b"II*\0" | b"IIRO" | b"IIU\0" => false,
+            b"MM\0*" | b"MMOR" => true,
+            _ => return None,
+        };
+        let mut exif = Exif { tiff, big_endian, ifd0: 0 };
+        exif.ifd0 = exif.u32_at(4)? as usize;
+        Some(exif)
+    }
+
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.tiff.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn field(&self, ifd: usize, tag: u16) -> Option<Field> {
+        let entry_cnt = self.u16_at(ifd)? as usize;
+        (0..entry_cnt).map(|idx| ifd + 2 + idx * 12).find(|pos| self.u16_at(*pos) == Some(tag)).and_then(|pos| {
+            let kind = self.u16_at(pos + 2)?;
+            let count = self.u32_at(pos + 4)?;
+            let size = match kind {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                _ => 8,
+            } * count as usize;
+            let value_pos = if size <= 4 { pos + 8 } else { self.u32_at(pos + 8)? as usize };
+            Some(Field { kind, count, value_pos })
+        })
+    }
+
+    fn short(&self, ifd: usize, tag: u16) -> Option<u16> {
+        let field = self.field(ifd, tag)?;
+        match (field.kind, field.count) {
+            (3, 1..) => self.u16_at(field.value_pos),
+            _ => None,
+        }
+    }
+
+    fn long(&self, ifd: usize, tag: u16) -> Option<u32> {
+        let field = self.field(ifd, tag)?;
+        match (field.kind, field.count) {
+            (3, 1..) => self.u16_at(field.value_pos).map(u32::from),
+            (4, 1..) => self.u32_at(field.value_pos),
+            _ => None,
+        }
+    }
+
+    // None when missing or blank, which cameras fill unused fields with
+    fn ascii(&self, ifd: usize, tag: u16) -> Option<String> {
+        let field = self.field(ifd, tag)?;
+        if field.kind != 2 {
+            return None
+        }
+        let bytes = self.tiff.get(field.value_pos..field.value_pos + field.count as usize)?;
+        let text = String::from_utf8_lossy(bytes).trim_end_matches('\0').trim().to_string();
+        (!text.is_empty()).then_some(text)
+    }
+
+    fn rationals(&self, ifd: usize, tag: u16) -> Option<Vec<f64>> {
+        let field = self.field(ifd, tag)?;
+        if field.kind != 5 {
+            return None
+        }
+        (0..field.count as usize).map(|idx| {
+            let (num, den) = (self.u32_at(field.value_pos + idx * 8)?, self.u32_at(field.value_pos + idx * 8 + 4)?);
+            (den != 0).then(|| num as f64 / den as f64)
+        }).collect()
+    }
+
+    fn longs(&self, ifd: usize, tag: u16) -> Vec<u32> {
+        let Some(field) = self.field(ifd, tag) else { return vec![] };
+        // counts are whatever the file says, but no more values fit than there are bytes left
+        let fitting = |size: usize| (field.count as usize).min(self.tiff.len().saturating_sub(field.value_pos) / size);
+        match field.kind {
+            3 => (0..fitting(2)).filter_map(|idx| self.u16_at(field.value_pos + idx * 2).map(u32::from)).collect(),
+            4 | 13 => (0..fitting(4)).filter_map(|idx| self.u32_at(field.value_pos + idx * 4)).collect(),
+            _ => vec![],
+        }
+    }
+
+    fn sub_ifd(&self, tag: u16) -> Option<usize> {
+        self.long(self.ifd0, tag).map(|x| x as usize)
+    }
+
+    // 1 to 8 as in the EXIF spec, 1 being upright
+    pub fn orientation(&self) -> Option<u16> {
+        self.short(self.ifd0, TAG_ORIENTATION).filter(|x| (1..=8).contains(x))
+    }
+
+    pub fn make(&self) -> Option<String> {
+        self.ascii(self.ifd0, TAG_MAKE)
+    }
+
+    pub fn model(&self) -> Option<String> {
+        self.ascii(self.ifd0, TAG_MODEL)
+    }
+
+    pub fn lens(&self) -> Option<String> {
+        self.ascii(self.sub_ifd(TAG_EXIF_IFD)?, TAG_LENS_MODEL)
+    }
+
+    // when the shutter fired, falling back to when the file was written, as YYYY-MM-DD HH:MM:SS
+    pub fn captured(&self) -> Option<String> {
+        let datetime = self.sub_ifd(TAG_EXIF_IFD).and_then(|ifd| self.ascii(ifd, TAG_DATETIME_ORIGINAL)).or_else(|| self.ascii(self.ifd0, TAG_DATETIME))?;
+        // unset dates are left as 0000:00:00 00:00:00 or blanked with spaces
+        if datetime.len() < 19 || !datetime.is_ascii() || datetime.starts_with("0000") || datetime.starts_with(' ') {
+            return None
+        }
+        Some(format!("{} {}", datetime[..10].replace(':', "-"), &datetime[11..19]))
+    }
+
+    pub fn rating(&self) -> Option<u16> {
+        self.short(self.ifd0, TAG_RATING)
+    }
+
+    // baseline JPEGs a camera RAW embeds as previews, largest first
+    pub fn jpeg_previews(&self) -> Vec<&'a [u8]> {
+        // IFD0 and the ones chained after it, and their sub-IFDs
+        let mut ifds: Vec<usize> = vec![];
+        let mut next = self.ifd0;
+        while next != 0 && !ifds.contains(&next) && ifds.len() < 16 {
+            ifds.push(next);
+            next = self.u16_at(next).and_then(|entry_cnt| self.u32_at(next + 2 + entry_cnt as usize * 12)).unwrap_or(0) as usize;
+        }
+        for ifd in ifds.clone() {
+            ifds.extend(self.longs(ifd, TAG_SUB_IFDS).into_iter().map(|x| x as usize));
+        }
+        let mut previews: Vec<&[u8]> = ifds.iter().flat_map(|ifd| {
+            let mut found = vec![];
+            if let (Some(offset), Some(len)) = (self.long(*ifd, TAG_JPEG_OFFSET), self.long(*ifd, TAG_JPEG_LENGTH)) {
+                found.extend(self.tiff.get(offset as usize..offset as usize + len as usize));
+            }
+            // DNG marks previews as reduced resolution, the full resolution one being the sensor data
+            let preview = match self.short(*ifd, TAG_COMPRESSION) {
+                Some(6) => true,
+                Some(7) => self.long(*ifd, TAG_NEW_SUBFILE_TYPE) == Some(1),
+                _ => false,
+            };
+            if let ([offset], [len]) = (&self.longs(*ifd, TAG_STRIP_OFFSETS)[..], &self.longs(*ifd, TAG_STRIP_BYTE_COUNTS)[..]) {
+                if preview {
+                    found.extend(self.tiff.get(*offset as usize..*offset as usize + *len as usize));
+                }
+            }
+            found
+        }).filter(|x| is_lossy_jpeg(x)).collect();
+        previews.sort_by_key(|x| std::cmp::Reverse(x.len()));
+        previews.dedup();
+        previews
+    }
+
+    // latitude and longitude in degrees, south and west being negative
+    pub fn gps(&self) -> Option<(f64, f64)> {
+        let ifd = self.sub_ifd(TAG_GPS_IFD)?;
+        let degrees = |tag| self.rationals(ifd, tag).filter(|x| x.len() == 3).map(|x| x[0] + x[1] / 60. + x[2] / 3600.);
+        let lat = degrees(TAG_GPS_LATITUDE)? * if self.ascii(ifd, TAG_GPS_LATITUDE_REF).as_deref() == Some("S") { -1. } else { 1. };
+        let lon = degrees(TAG_GPS_LONGITUDE)? * if self.ascii(ifd, TAG_GPS_LONGITUDE_REF).as_deref() == Some("W") { -1. } else { 1. };
+        Some((lat, lon))
+    }
+}
+
+fn find_tiff(file_bytes: &[u8]) -> Option<&[u8]> {
+    match file_bytes {
+        [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] | [b'I', b'I', b'R', b'O', ..] | [b'I', b'I', b'U', 0, ..] | [b'M', b'M', b'O', b'R', ..] => Some(file_bytes),
+        [b'F', b'U', b'J', b'I', b'F', b'I', b'L', b'M', ..] => find_tiff_jpeg(raf_preview(file_bytes)?),
+        [0xFF, 0xD8, ..] => find_tiff_jpeg(file_bytes),
+        [0x89, b'P', b'N', b'G', ..] => find_tiff_png(file_bytes),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => find_tiff_webp(file_bytes),
+        _ => None,
+    }
+}
+
+// APP1 segment starting with Exif\0\0, which comes before the image data
+fn find_tiff_jpeg(file_bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 2;
+    loop {
+        if *file_bytes.get(pos)? != 0xFF {
+            return None
+        }
+        let marker = *file_bytes.get(pos + 1)?;
+        match marker {
+            0xFF => { pos += 1; continue }, // fill byte
+            0x01 | 0xD0..=0xD7 => { pos += 2; continue }, // no length
+            0xD9 | 0xDA => return None, // end of image or start of scan
+            _ => (),
+        }
+        let len = u16::from_be_bytes(file_bytes.get(pos + 2..pos + 4)?.try_into().ok()?) as usize;
+        let segment = file_bytes.get(pos + 4..pos + 2 + len)?;
+        if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+            return Some(&segment[6..])
+        }
+        pos += 2 + len;
+    }
+}
+
+// sensor data of lossless JPEG RAWs is stored the same way as previews, only baseline and progressive JPEGs are images
+fn is_lossy_jpeg(jpeg: &[u8]) -> bool {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return false
+    }
+    let mut pos = 2;
+    while let (Some(0xFF), Some(marker)) = (jpeg.get(pos), jpeg.get(pos + 1)) {
+        match marker {
+            0xC0..=0xC2 => return true,
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xD9 | 0xDA => return false,
+            0xFF => pos += 1,
+            0x01 | 0xD0..=0xD7 => pos += 2,
+            _ => match jpeg.get(pos + 2..pos + 4) {
+                Some(len) => pos += 2 + u16::from_be_bytes([len[0], len[1]]) as usize,
+                None => return false,
+            },
+        }
+    }
+    false
+}
+
+// Fujifilm RAFs aren't TIFF, but point to a JPEG preview carrying the EXIF in their header
+pub fn raf_preview(file_bytes: &[u8]) -> Option<&[u8]> {
+    let offset = u32::from_be_bytes(file_bytes.get(84..88)?.try_into().ok()?) as usize;
+    let len = u32::from_be_bytes(file_bytes.get(88..92)?.try_into().ok()?) as usize;
+    file_bytes.get(offset..offset + len)
+}
+
+fn find_tiff_png(file_bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 8;
+    loop {
+        let len = u32::from_be_bytes(file_bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        match file_bytes.get(pos + 4..pos + 8)? {
+            b"eXIf" => return file_bytes.get(pos + 8..pos + 8 + len),
+            b"IEND" => return None,
+            _ => pos += 12 + len,
+        }
+    }
+}
+
+fn find_tiff_webp(file_bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 12;
+    loop {
+        let len = u32::from_le_bytes(file_bytes.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        if file_bytes.get(pos..pos + 4)? == b"EXIF" {
+            // some writers keep the JPEG APP1 prefix
+            let chunk = file_bytes.get(pos + 8..pos + 8 + len)?;
+            return Some(chunk.strip_prefix(b"Exif\0\0").unwrap_or(chunk))
+        }
+        pos += 8 + len + len % 2;
+    }
+}
+
+pub fn orientation(file_bytes: &[u8]) -> Option<u16> {
+    Exif::parse(file_bytes)?.orientation()
+}
+
+// decodes an image turned the way it is meant to be seen, along with the orientation that took, 1 if it was stored upright
+pub fn load_upright(path: &Path, file_bytes: &[u8], downscale: bool) -> image::ImageResult<(image::DynamicImage, u16)> {
+    let img = crate::decode::decode(path, file_bytes, downscale)?;
+    match orientation(file_bytes).unwrap_or(1) {
+        1 => Ok((img, 1)),
+        orientation => Ok((crate::hashkind::Transform::from_orientation(orientation).apply(&img), orientation)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a little-endian TIFF with one IFD of (tag, kind, count, inline value or offset) entries, then the offset of the next IFD
+    fn tiff(entries: &[(u16, u16, u32, u32)], next_ifd: u32) -> Vec<u8> {
+        let mut bytes = b"II*\0".to_vec();
+        bytes.extend(8u32.to_le_bytes());
+        bytes.extend((entries.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            bytes.extend(tag.to_le_bytes());
+            bytes.extend(kind.to_le_bytes());
+            bytes.extend(count.to_le_bytes());
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend(next_ifd.to_le_bytes());
+        bytes
+    }
+
+    fn rotated(orientation: u32) -> Vec<u8> {
+        tiff(&[(TAG_ORIENTATION, 3, 1, orientation)], 0)
+    }
+
+    // a JPEG of the given (marker, payload) segments, ending where the image data would start
+    fn jpeg(segments: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xD8];
+        for (marker, payload) in segments {
+            bytes.extend([0xFF, *marker]);
+            bytes.extend((payload.len() as u16 + 2).to_be_bytes());
+            bytes.extend(*payload);
+        }
+        bytes.extend([0xFF, 0xDA, 0x00, 0x02]);
+        bytes
+    }
+
+    fn app1(tiff: &[u8]) -> Vec<u8> {
+        [b"Exif\0\0".as_slice(), tiff].concat()
+    }
+
+    #[test]
+    fn reads_every_orientation() {
+        for orientation in 1..=8 {
+            assert_eq!(super::orientation(&rotated(orientation)), Some(orientation as u16));
+        }
+        assert_eq!(super::orientation(&rotated(0)), None);
+        assert_eq!(super::orientation(&rotated(9)), None);
+        // a long where a short belongs
+        assert_eq!(super::orientation(&tiff(&[(TAG_ORIENTATION, 4, 1, 6)], 0)), None);
+        let mut big_endian = b"MM\0*".to_vec();
+        big_endian.extend(8u32.to_be_bytes());
+        big_endian.extend(1u16.to_be_bytes());
+        big_endian.extend(TAG_ORIENTATION.to_be_bytes());
+        big_endian.extend(3u16.to_be_bytes());
+        big_endian.extend(1u32.to_be_bytes());
+        big_endian.extend([0, 8, 0, 0]);
+        assert_eq!(super::orientation(&big_endian), Some(8));
+    }
+
+    #[test]
+    fn finds_exif_in_jpeg_png_and_webp() {
+        let exif = app1(&rotated(6));
+        assert_eq!(super::orientation(&jpeg(&[(0xE0, b"JFIF\0"), (0xE1, &exif)])), Some(6));
+        // APP1 of XMP comes first in some files
+        assert_eq!(super::orientation(&jpeg(&[(0xE1, b"http://ns.adobe.com/xap/1.0/\0"), (0xE1, &exif)])), Some(6));
+        assert_eq!(super::orientation(&jpeg(&[(0xE0, b"JFIF\0")])), None);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, data) in [(b"IHDR", &[0; 13][..]), (b"eXIf", &rotated(3)[..]), (b"IEND", &[][..])] {
+            png.extend((data.len() as u32).to_be_bytes());
+            png.extend(kind);
+            png.extend(data);
+            png.extend([0; 4]);
+        }
+        assert_eq!(super::orientation(&png), Some(3));
+
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        for (kind, data) in [(b"VP8X", vec![0; 10]), (b"EXIF", app1(&rotated(5)))] {
+            webp.extend(kind);
+            webp.extend((data.len() as u32).to_le_bytes());
+            webp.extend(&data);
+            if data.len() % 2 == 1 {
+                webp.push(0);
+            }
+        }
+        assert_eq!(super::orientation(&webp), Some(5));
+    }
+
+    #[test]
+    fn truncated_files_are_read_no_further_than_they_go() {
+        let whole = jpeg(&[(0xE0, b"JFIF\0"), (0xE1, &app1(&rotated(6)))]);
+        for len in 0..whole.len() {
+            let orientation = super::orientation(&whole[..len]);
+            assert!(orientation.is_none() || len >= whole.len() - 4, "read orientation from {} of {} bytes", len, whole.len());
+            let _ = Exif::parse(&whole[..len]).map(|x| (x.jpeg_previews(), x.gps(), x.captured()));
+        }
+        // segments claiming more than there is
+        let mut overlong = jpeg(&[(0xE1, &app1(&rotated(6)))]);
+        overlong[4..6].copy_from_slice(&0xFFFFu16.to_be_bytes());
+        assert_eq!(super::orientation(&overlong), None);
+        assert_eq!(super::orientation(&rotated(6)[..18]), None);
+    }
+
+    #[test]
+    fn zero_length_segments_end_the_search() {
+        assert_eq!(super::orientation(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x00, 0xFF, 0xE1]), None);
+        assert!(!is_lossy_jpeg(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x00, 0xFF, 0xC0]));
+        assert!(is_lossy_jpeg(&jpeg(&[(0xE0, b"JFIF\0"), (0xC0, &[0; 6])])));
+        assert!(!is_lossy_jpeg(&jpeg(&[(0xC3, &[0; 6])])));
+    }
+
+    #[test]
+    fn ifd_loops_and_huge_counts_are_cut_short() {
+        // IFD0 names itself as the next IFD
+        let looped = tiff(&[(TAG_COMPRESSION, 3, 1, 6)], 8);
+        assert!(Exif::parse(&looped).unwrap().jpeg_previews().is_empty());
+        // billions of sub-IFDs said to be at the start of the file
+        let started = std::time::Instant::now();
+        let crafted = tiff(&[(TAG_SUB_IFDS, 4, u32::MAX, 0)], 0);
+        assert!(Exif::parse(&crafted).unwrap().jpeg_previews().is_empty());
+        assert_eq!(Exif::parse(&crafted).unwrap().longs(8, TAG_SUB_IFDS).len(), crafted.len() / 4);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn finds_embedded_previews() {
+        let preview = jpeg(&[(0xC0, &[0; 6])]);
+        let mut raw = tiff(&[(TAG_JPEG_OFFSET, 4, 1, 0), (TAG_JPEG_LENGTH, 4, 1, preview.len() as u32)], 0);
+        let offset = raw.len() as u32;
+        raw[8 + 2 + 8..8 + 2 + 12].copy_from_slice(&offset.to_le_bytes());
+        raw.extend(&preview);
+        assert_eq!(Exif::parse(&raw).unwrap().jpeg_previews(), vec![preview.as_slice()]);
+    }
+}
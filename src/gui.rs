@@ -2,13 +2,21 @@
 use eframe::{egui, egui::{RichText, Color32, Vec2, Rect, Ui}};
 use egui_extras::RetainedImage;
 use tokio::runtime;
-use tokio_stream::StreamExt;
 use tokio_util::sync::CancellationToken;
-use std::{sync::{{Arc, RwLock}, atomic::{Ordering::Relaxed, AtomicI64}, mpsc, mpsc::TryRecvError}, path::PathBuf, collections::HashSet};
-use futures::stream::futures_unordered::FuturesUnordered;
+use std::{sync::{{Arc, RwLock}, atomic::{Ordering::Relaxed, AtomicI64}, mpsc, mpsc::TryRecvError}, path::{Path, PathBuf}, collections::{HashSet, HashMap}};
 use sqlx::{Row,Acquire};
 
+use crate::hashkind::{HashAlgorithm, HashKind, Transform, match_transforms, set_match_transforms};
 use crate::index::{HashIndexer, HashIndexError};
+use crate::bktree::BkTree;
+use crate::rules::ScanRules;
+use crate::volume;
+use crate::budget;
+use crate::dispose::{DisposalMethod, dispose_dupes, verify_dupes};
+use crate::journal::{Journal, undo_last};
+use crate::pipeline::{self, Throughput};
+use crate::metadata::{MetadataField, matching};
+use crate::watcher::{DirWatcher, apply_events};
 
 const CHECKMARK: &[u8] = include_bytes!("../assets/checkmark.png");
 
@@ -17,16 +25,19 @@ enum PopOvers {
     LibraryManager,
     HashingDbUpdate,
     BinaryDedup(BinDedupStep),
+    CleanMissing,
     None
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, clap::ValueEnum)]
 pub enum KeepWhichFile {
     CreatedFirst, // ctime
     ModifiedFirst, // mtime
     PathShortest, // char count of full path
     NameShortest, // char count of filename
     PathShallowest, // least directories deep
+    MostMetadata, // EXIF and XMP fields present
+    CapturedFirst, // capture date, files without one last
 }
 
 impl KeepWhichFile {
@@ -37,6 +48,8 @@ impl KeepWhichFile {
             Self::PathShortest => "LENGTH(fullpath)",
             Self::NameShortest => "LENGTH(filename)",
             Self::PathShallowest => "dircnt",
+            Self::MostMetadata => "-IFNULL(field_cnt, 0)",
+            Self::CapturedFirst => "captured IS NULL, captured",
         }.to_string()
     }
 }
@@ -56,6 +69,49 @@ pub enum BinDupeMessage {
     Entry(PathBuf),
 }
 
+// a thumbnail is only asked for once, whether it loads or not
+enum Thumbnail {
+    Loading,
+    Loaded(RetainedImage),
+    Failed,
+}
+
+// what was changed, with how many entries are left without variant hashes or to rehash
+struct HashSettingsSaved {
+    transforms: Option<(bool, i64)>,
+    kind: Option<(HashKind, anyhow::Result<i64>)>,
+}
+
+enum DisposalMessage {
+    Unverified(Vec<String>), // why sets were skipped, sent before anything is disposed of
+    Disposed(PathBuf, std::io::Result<Option<PathBuf>>),
+}
+
+pub enum HashDupeMessage {
+    NewSet,
+    Entry(PathBuf, Transform), // how the entry is transformed from the first of its set
+}
+
+// scan rules being edited in the library manager, dir being None for the global ones
+struct RulesEditor {
+    dir: Option<PathBuf>,
+    includes: String, // one glob per line
+    excludes: String,
+    limit_depth: bool,
+    max_depth: usize,
+    skip_hidden: bool,
+    follow_symlinks: bool,
+    one_filesystem: bool,
+}
+
+// watched directory waiting on confirmation of its removal
+struct DirRemoval {
+    dir: PathBuf,
+    purgeable: Option<usize>, // entries beneath it no other watched directory covers, None until counted
+    purgeable_recv: mpsc::Receiver<usize>,
+    purge_imgs: bool,
+}
+
 pub struct IndexingGui {
     watched_dirs: Arc<RwLock<HashSet<PathBuf>>>,
     rt: Option<Arc<runtime::Runtime>>,
@@ -63,12 +119,21 @@ pub struct IndexingGui {
     hashing_cancelled: CancellationToken,
     hashing_complete: bool,
     checkmark: RetainedImage,
-    set_images: Vec<RetainedImage>,
-    set_images_recv: mpsc::Receiver<RetainedImage>,
-    filelist_recv: Option<mpsc::Receiver<PathBuf>>,
+    thumbnails: HashMap<PathBuf, Thumbnail>,
+    thumbnails_tx: mpsc::Sender<(PathBuf, Option<RetainedImage>)>,
+    thumbnails_recv: mpsc::Receiver<(PathBuf, Option<RetainedImage>)>,
+    thumbnails_pending: usize,
+    scan_walking: bool, // a scan is spawned and still walking the watched directories
+    scan_throughput: Arc<Throughput>, // of the last scan
     db_pool: sqlx::SqlitePool,
+    phash_index: Arc<RwLock<BkTree>>,
     hamming_proximity: usize,
-    filelist: HashSet<PathBuf>,
+    hash_dupes: Vec<Vec<(PathBuf, Transform)>>,
+    hash_dupes_recv: Option<mpsc::Receiver<HashDupeMessage>>,
+    metadata_filter: (MetadataField, String),
+    metadata_matches: Option<HashSet<PathBuf>>, // sets are only shown if they have one of these
+    metadata_matches_recv: Option<mpsc::Receiver<HashSet<PathBuf>>>,
+    which_hash_set: usize,
     filelist_loaded: bool,
     rehashed_cnt: usize,
     rehashed_cnt_recv: Option<mpsc::Receiver<usize>>,
@@ -82,12 +147,32 @@ pub struct IndexingGui {
     incl_ignored: bool,
     which_set: usize,
     deleted_file_cnt: usize,
+    disposal_method: DisposalMethod,
+    quarantine_dir: Option<PathBuf>,
+    disposal_errors: Vec<String>,
+    disposal_recv: Option<mpsc::Receiver<DisposalMessage>>,
+    unverified_sets: Vec<String>,
+    dedup_journal: Option<Journal>, // so the clean up after deduplicating is undone along with it
+    undo_report: Option<Vec<String>>,
+    undo_recv: Option<mpsc::Receiver<Vec<String>>>,
+    watcher: Option<DirWatcher>,
+    rules_editor: Option<RulesEditor>,
+    rules_editor_recv: Option<mpsc::Receiver<RulesEditor>>,
+    dir_removal: Option<DirRemoval>,
+    hash_kind: HashKind,
+    match_transforms: bool,
+    hash_editor: Option<(HashKind, bool)>, // kind being chosen in the library manager, and whether to match transforms
+    hash_settings_recv: Option<mpsc::Receiver<HashSettingsSaved>>,
+    offline_dirs: Arc<RwLock<HashSet<PathBuf>>>, // watched directories on volumes not mounted right now
+    missing_recv: Option<mpsc::Receiver<(Vec<PathBuf>, Vec<PathBuf>)>>,
+    missing_files: Vec<PathBuf>,
+    unreachable_dirs: Vec<PathBuf>, // watched directories skipped while looking for missing files
     // bin_dedup_step: BinDedupStep,
 }
 
 impl IndexingGui {
-    pub fn new(_cc: &eframe::CreationContext<'_>, rt: Arc<runtime::Runtime>, db_pool: sqlx::SqlitePool) -> Self {
-        let (set_images_tx, set_images_rx) = std::sync::mpsc::channel();
+    pub fn new(cc: &eframe::CreationContext<'_>, rt: Arc<runtime::Runtime>, db_pool: sqlx::SqlitePool) -> Self {
+        let (thumbnails_tx, thumbnails_rx) = std::sync::mpsc::channel();
         let mut ig = IndexingGui {
             watched_dirs: Arc::new(RwLock::new(HashSet::new())),
             watched_image_count: Arc::new(AtomicI64::new(0)),
@@ -95,12 +180,21 @@ impl IndexingGui {
             cancel_token: CancellationToken::new(),
             hashing_cancelled: CancellationToken::new(),
             checkmark: RetainedImage::from_image_bytes("checkmark", CHECKMARK).unwrap(),
-            set_images: vec![],
-            set_images_recv: set_images_rx,
+            thumbnails: HashMap::new(),
+            thumbnails_tx,
+            thumbnails_recv: thumbnails_rx,
+            thumbnails_pending: 0,
             db_pool: db_pool.clone(),
+            phash_index: Arc::new(RwLock::new(BkTree::new())),
             hamming_proximity: 0,
-            filelist: HashSet::new(),
-            filelist_recv: None,
+            hash_dupes: vec![],
+            hash_dupes_recv: None,
+            metadata_filter: (MetadataField::Camera, String::new()),
+            metadata_matches: None,
+            metadata_matches_recv: None,
+            which_hash_set: 0,
+            scan_walking: false,
+            scan_throughput: Arc::new(Throughput::default()),
             filelist_loaded: false,
             hashing_complete: true,
             rehashed_cnt: 0,
@@ -114,47 +208,90 @@ impl IndexingGui {
             incl_ignored: false,
             which_set: 0,
             deleted_file_cnt: 0,
+            disposal_method: DisposalMethod::Trash,
+            quarantine_dir: None,
+            disposal_errors: vec![],
+            disposal_recv: None,
+            unverified_sets: vec![],
+            dedup_journal: None,
+            undo_report: None,
+            undo_recv: None,
+            watcher: None,
+            rules_editor: None,
+            rules_editor_recv: None,
+            dir_removal: None,
+            hash_kind: HashKind::default(),
+            match_transforms: false,
+            hash_editor: None,
+            hash_settings_recv: None,
+            offline_dirs: Arc::new(RwLock::new(HashSet::new())),
+            missing_recv: None,
+            missing_files: vec![],
+            unreachable_dirs: vec![],
             // bin_dedup_step: BinDedupStep::SelectMethod,
         };
 
-        // dummy code that loads images from disk into ui
         let wic = ig.watched_image_count.clone();
         let conn = ig.db_pool.clone();
         ig.rt.as_ref().unwrap().spawn(async move {
             wic.store(sqlx::query("SELECT COUNT(*) FROM entries WHERE ignored = 0;").fetch_one(conn.acquire().await.unwrap().acquire().await.unwrap()).await.unwrap().get::<i64,_>(0), Relaxed);
-            let mut fut_set = FuturesUnordered::new();
-            let mut rd_iter = std::fs::read_dir("./test_data").unwrap();
-            while let Some(Ok(entry)) = rd_iter.next() {
-                if let Ok(ftype) = entry.file_type() {
-                    if ftype.is_file() {
-                        fut_set.push(async move {
-                            if let Ok(success) = image::load_from_memory(&std::fs::read(entry.path()).unwrap()) {
-                                let image = success.thumbnail(128, 128);
-                                let color_image = egui::ColorImage::from_rgba_unmultiplied([image.width().try_into().unwrap(), image.height().try_into().unwrap()], image.to_rgba8().as_flat_samples().as_slice());
-                                return Some(RetainedImage::from_color_image(entry.file_name().to_string_lossy(), color_image))
-                            } else {
-                                eprintln!("Resize failed on image {}...", entry.path().to_string_lossy());
-                                return None
-                            }
-                        });
-                    }
-                }
-            }
-            while let Some(ri) = fut_set.next().await {
-                if let Some(ri) = ri {
-                    let _ = set_images_tx.send(ri);
-                }
-            }
         });
+        ig.disposal_method = ig.rt.as_ref().unwrap().block_on(DisposalMethod::load(&ig.db_pool));
+        ig.hash_kind = ig.rt.as_ref().unwrap().block_on(HashKind::load(&ig.db_pool));
+        ig.match_transforms = ig.rt.as_ref().unwrap().block_on(match_transforms(&ig.db_pool));
+        ig.quarantine_dir = ig.rt.as_ref().unwrap().block_on(crate::settings::get_setting(&ig.db_pool, "quarantine_dir")).map(PathBuf::from);
+        ig.spawn_watcher(cc.egui_ctx.clone());
         ig.get_watched_dirs();
         ig
     }
 
+    // keeps the database up to date with changes beneath watched_dirs while running
+    fn spawn_watcher(&mut self, ctx: egui::Context) {
+        let watcher = match DirWatcher::new() {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                eprintln!("Could not watch directories for changes, RELOAD to pick them up: {}", e);
+                return
+            },
+        };
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let cancel_token = self.cancel_token.clone();
+        let running_watcher = watcher.clone();
+        let volumes_tx = tx.clone();
+        std::thread::spawn(move || running_watcher.run(tx, cancel_token));
+        let wd_lock = self.watched_dirs.clone();
+        let offline_dirs = self.offline_dirs.clone();
+        let volumes_ctx = ctx.clone();
+        self.rt.as_ref().unwrap().spawn(volume::follow_volumes(self.db_pool.clone(), Some(watcher.clone()), volumes_tx, self.cancel_token.clone(), move |moved, offline| {
+            if let Ok(mut wd_lock) = wd_lock.write() {
+                for (from, to) in moved {
+                    wd_lock.remove(from);
+                    wd_lock.insert(to.to_owned());
+                }
+            }
+            *offline_dirs.write().unwrap() = offline.clone();
+            volumes_ctx.request_repaint();
+        }));
+        let wic = self.watched_image_count.clone();
+        self.rt.as_ref().unwrap().spawn(apply_events(self.db_pool.clone(), self.phash_index.clone(), rx, move |_, image_count| {
+            wic.store(image_count, Relaxed);
+            ctx.request_repaint();
+        }));
+        self.watcher = Some(watcher);
+    }
+
     fn get_watched_dirs(&mut self) {
         println!("Loading watched_dirs from database...");
         let db_pool = self.db_pool.clone();
         let wd_lock = self.watched_dirs.clone();
+        let watcher = self.watcher.clone();
+        let offline_dirs = self.offline_dirs.clone();
         self.rt.as_ref().unwrap().spawn(async move {
+            for (from, to) in volume::reconcile(&db_pool).await {
+                println!("Volume of {} is now mounted at {}, following it", from.to_string_lossy(), to.to_string_lossy());
+            }
+            let offline = volume::offline_dirs(&db_pool).await;
+            *offline_dirs.write().unwrap() = offline.clone();
             let mut conn = loop {
                 if let Ok(acquisition) = db_pool.acquire().await {
                     break acquisition;
@@ -170,33 +307,56 @@ impl IndexingGui {
                     }
                 }
             }
+            if let Some(watcher) = watcher {
+                let dirs: Vec<PathBuf> = wd_lock.read().unwrap().iter().filter(|x| !offline.contains(*x)).cloned().collect();
+                watcher.add_dirs(&db_pool, dirs).await;
+            }
             println!("watched_dirs loaded from database");
         });
     }
 
     fn add_watched_dir(&mut self, dir: PathBuf) {
-        if dir.is_dir() && dir.is_absolute() && !(&self.watched_dirs.read().unwrap()).iter().any(|x| {x == &dir}) {
+        if dir.is_dir() && dir.is_absolute() && !self.watched_dirs.read().unwrap().iter().any(|x| {x == &dir}) {
             let db_pool = self.db_pool.clone();
             let wd_lock = self.watched_dirs.clone();
+            let watcher = self.watcher.clone();
             self.rt.as_ref().unwrap().spawn(async move {
                 let mut conn = loop {
                     if let Ok(acquisition) = db_pool.acquire().await {
                         break acquisition;
                     }
                 };
-                if sqlx::query("INSERT INTO watched_dirs (fullpath) VALUES (?)").bind(dir.to_str()).execute(conn.acquire().await.unwrap()).await.is_ok() {
+                let (volume_id, volume_path) = volume::locate(&dir).unzip();
+                if sqlx::query("INSERT INTO watched_dirs (fullpath, volume_id, volume_path) VALUES (?, ?, ?)").bind(dir.to_str()).bind(volume_id).bind(volume_path.map(|x| x.to_string_lossy().to_string())).execute(conn.acquire().await.unwrap()).await.is_ok() {
                     if let Ok(mut wd_lock) = wd_lock.write() {
-                        (*wd_lock).insert(dir);
+                        (*wd_lock).insert(dir.to_owned());
+                    }
+                    if let Some(watcher) = watcher {
+                        watcher.add_dirs(&db_pool, vec![dir]).await;
                     }
                 }
             });
         }
     }
 
+    fn confirm_dir_removal(&mut self, dir: PathBuf) {
+        let other_dirs: HashSet<PathBuf> = self.watched_dirs.read().unwrap().iter().filter(|x| *x != &dir).cloned().collect();
+        let (tx, rx) = mpsc::channel();
+        let hi = HashIndexer::new(self.db_pool.clone());
+        let counted = dir.clone();
+        self.rt.as_ref().unwrap().spawn(async move {
+            let _ = tx.send(hi.purgeable(&counted, &other_dirs).await.len());
+        });
+        self.dir_removal = Some(DirRemoval { dir, purgeable: None, purgeable_recv: rx, purge_imgs: false });
+    }
+
     fn del_watched_dir(&mut self, dir: PathBuf, purge_imgs: bool) {
-        if (&self.watched_dirs.read().unwrap()).iter().any(|x| {x == dir.as_os_str()}) {
+        if self.watched_dirs.read().unwrap().iter().any(|x| {x == dir.as_os_str()}) {
             let db_pool = self.db_pool.clone();
             let wd_lock = self.watched_dirs.clone();
+            let watcher = self.watcher.clone();
+            let hi = HashIndexer::with_phash_index(self.db_pool.clone(), self.phash_index.clone());
+            let wic = self.watched_image_count.clone();
             self.rt.as_ref().unwrap().spawn(async move {
                 let mut conn = loop {
                     if let Ok(acquisition) = db_pool.acquire().await {
@@ -204,101 +364,513 @@ impl IndexingGui {
                     }
                 };
                 if sqlx::query("DELETE FROM watched_dirs WHERE fullpath=?").bind(dir.to_str().unwrap()).execute(conn.acquire().await.unwrap()).await.is_ok() {
+                    let mut overlapping = vec![];
+                    let mut other_dirs = HashSet::new();
                     if let Ok(mut wd_lock) = wd_lock.write() {
                         wd_lock.remove::<PathBuf>(&dir);
-                        if purge_imgs {
-                            todo!("remove all images from database covered by {} and not covered by any other watched_dirs entries", dir.to_string_lossy());
-                        }
+                        overlapping = wd_lock.iter().filter(|x| x.starts_with(&dir) || dir.starts_with(x)).cloned().collect();
+                        other_dirs = wd_lock.clone();
+                    }
+                    if purge_imgs {
+                        let purgeable = hi.purgeable(&dir, &other_dirs).await;
+                        hi.forget(&purgeable, &Journal::begin(&db_pool, "purge")).await;
+                        wic.store(sqlx::query("SELECT COUNT(*) FROM entries WHERE ignored = 0;").fetch_one(conn.acquire().await.unwrap()).await.unwrap().get::<i64,_>(0), Relaxed);
+                    }
+                    if let Some(watcher) = watcher {
+                        // keeps watching what other watched_dirs still cover
+                        watcher.remove_dir(&dir);
+                        watcher.add_dirs(&db_pool, overlapping).await;
                     }
                 }
             });
         }
     }
 
-    fn get_set_images(&mut self) -> &Vec<RetainedImage> {
-        while let Ok(rx) = self.set_images_recv.try_recv() {
-            self.set_images.push(rx);
+    fn dir_removal_frame(&mut self, ui: &mut Ui) {
+        let Some(removal) = &mut self.dir_removal else { return };
+        if removal.purgeable.is_none() {
+            removal.purgeable = removal.purgeable_recv.try_recv().ok();
+            ui.ctx().request_repaint();
+        }
+        let mut close = false;
+        let mut remove = false;
+        egui::Frame::none()
+            .fill(Color32::from_rgb(200, 190, 164))
+            .inner_margin(egui::Margin::symmetric(5., 5.))
+            .outer_margin(egui::Margin::symmetric(2., 4.))
+            .show(ui, |ui| {
+                ui.label(RichText::new(format!("Remove {} from the library?", removal.dir.to_string_lossy())).strong().color(Color32::BLACK));
+                if removal.purgeable.is_none() {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.colored_label(Color32::BLACK, "Counting entries beneath it...");
+                    });
+                } else if removal.purgeable > Some(0) {
+                    ui.radio_value(&mut removal.purge_imgs, false, RichText::new("Keep its entries").color(Color32::BLACK))
+                        .on_hover_text_at_pointer("Files beneath it stay in the database and in duplicate sets");
+                    ui.radio_value(&mut removal.purge_imgs, true, RichText::new(format!("Purge {} entries", removal.purgeable.unwrap_or(0))).color(Color32::BLACK))
+                        .on_hover_text_at_pointer("Forgets every file beneath it that no other watched directory covers\nFiles themselves are left alone, UNDO LAST brings the entries back");
+                } else {
+                    ui.colored_label(Color32::BLACK, "No entries would be left behind by it");
+                }
+                ui.horizontal(|ui| {
+                    remove = ui.add_enabled(removal.purgeable.is_some(), egui::Button::new("Remove")).clicked();
+                    close = ui.button("Cancel").clicked();
+                });
+            });
+        if remove {
+            let removal = self.dir_removal.take().unwrap();
+            self.del_watched_dir(removal.dir, removal.purge_imgs);
+        } else if close {
+            self.dir_removal = None;
+        }
+    }
+
+    // the editor opens once the rules are loaded
+    fn edit_rules(&mut self, dir: Option<PathBuf>) {
+        self.rules_editor = None;
+        let (tx, rx) = mpsc::channel();
+        self.rules_editor_recv = Some(rx);
+        let db_pool = self.db_pool.clone();
+        self.rt.as_ref().unwrap().spawn(async move {
+            let rules = ScanRules::load_own(&db_pool, dir.as_deref()).await;
+            let _ = tx.send(RulesEditor {
+                dir,
+                includes: rules.includes.join("\n"),
+                excludes: rules.excludes.join("\n"),
+                limit_depth: rules.max_depth.is_some(),
+                max_depth: rules.max_depth.unwrap_or(0),
+                skip_hidden: rules.skip_hidden,
+                follow_symlinks: rules.follow_symlinks,
+                one_filesystem: rules.one_filesystem,
+            });
+        });
+    }
+
+    fn save_rules(&mut self, editor: RulesEditor) {
+        let rules = ScanRules {
+            includes: editor.includes.lines().map(|x| x.to_string()).collect(),
+            excludes: editor.excludes.lines().map(|x| x.to_string()).collect(),
+            max_depth: editor.limit_depth.then_some(editor.max_depth),
+            skip_hidden: editor.skip_hidden,
+            follow_symlinks: editor.follow_symlinks,
+            one_filesystem: editor.one_filesystem,
         };
-        &self.set_images
-    }
-
-    fn spawn_load_filelist(&mut self) {
-        println!("Spawned filelist loading");
-        self.filelist = HashSet::new();
-        let lock = self.watched_dirs.read().unwrap(); 
-        let (tx,rx) = std::sync::mpsc::channel::<PathBuf>();
-        self.filelist_recv = Some(rx);
-        let mut fut_set = FuturesUnordered::new();
-        for dir in lock.iter() {
-            if !dir.is_dir() {
-                eprintln!("{} is not a directory!!", dir.to_string_lossy());
-                continue
+        let db_pool = self.db_pool.clone();
+        let watcher = self.watcher.clone();
+        // global rules apply to every watched directory
+        let affected: Vec<PathBuf> = match &editor.dir {
+            Some(dir) => vec![dir.to_owned()],
+            None => self.watched_dirs.read().unwrap().iter().cloned().collect(),
+        };
+        self.rt.as_ref().unwrap().spawn(async move {
+            if let Err(e) = rules.save(&db_pool, editor.dir.as_deref()).await {
+                eprintln!("Could not save scan rules: {:?}", e);
+                return
             }
-            let tx = tx.clone();
-            let dir = dir.to_owned();
-            fut_set.push(async move {
-                let mut dirlist = vec![dir];
-                while let Some(dir) = dirlist.pop() {
-                    if let Ok(entries) = dir.read_dir() {
-                        let entries: Vec<std::fs::DirEntry> = entries.into_iter().flatten().collect();
-                        for entry in entries {
-                            let ft = entry.file_type().unwrap();
-                            if ft.is_file() {
-                                if tx.send(entry.path()).is_err() {
-                                    eprintln!("Error: mpsc closed before receiving {}", entry.path().to_string_lossy());
-                                }
-                            } else if ft.is_dir() {
-                                dirlist.push(entry.path());
-                            } else {
-                                eprintln!("Can't interpret filetype of: {}", entry.path().to_string_lossy());
-                            }
-                        }
+            if let Some(watcher) = watcher {
+                watcher.add_dirs(&db_pool, affected).await;
+            }
+        });
+    }
+
+    fn hash_editor_frame(&mut self, ui: &mut Ui) {
+        if self.hash_settings_recv.is_some() {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.colored_label(Color32::BLACK, "Saving hash settings...");
+            });
+        }
+        let Some((editor, transforms)) = &mut self.hash_editor else { return };
+        let mut close = false;
+        let mut save = false;
+        egui::Frame::none()
+            .fill(Color32::from_rgb(200, 190, 164))
+            .inner_margin(egui::Margin::symmetric(5., 5.))
+            .outer_margin(egui::Margin::symmetric(2., 4.))
+            .show(ui, |ui| {
+                ui.label(RichText::new("Perceptual hash for similar images").strong().color(Color32::BLACK));
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut editor.alg, HashAlgorithm::Mean, RichText::new("Mean").color(Color32::BLACK));
+                    ui.radio_value(&mut editor.alg, HashAlgorithm::Gradient, RichText::new("Gradient").color(Color32::BLACK));
+                    ui.radio_value(&mut editor.alg, HashAlgorithm::DoubleGradient, RichText::new("Double gradient").color(Color32::BLACK));
+                    ui.radio_value(&mut editor.alg, HashAlgorithm::Blockhash, RichText::new("Blockhash").color(Color32::BLACK));
+                });
+                ui.horizontal(|ui| {
+                    ui.colored_label(Color32::BLACK, "Size");
+                    ui.add(egui::DragValue::new(&mut editor.size).clamp_range(4..=32));
+                    ui.colored_label(Color32::BLACK, "squared bits");
+                });
+                ui.add_enabled(editor.alg != HashAlgorithm::Blockhash, egui::Checkbox::new(&mut editor.dct, RichText::new("DCT preprocessing").color(Color32::BLACK)))
+                    .on_hover_text_at_pointer("Holds up better against recompression and worse against crops");
+                ui.checkbox(transforms, RichText::new("Match rotated and mirrored copies").color(Color32::BLACK))
+                    .on_hover_text_at_pointer("Hashes every rotation and mirror image of each image,\nmaking hashing several times slower");
+                ui.horizontal(|ui| {
+                    save = ui.button("Save").on_hover_text_at_pointer("Images never hashed this way are rehashed,\nhashes of other kinds are kept to switch back to").clicked();
+                    close = ui.button("Cancel").clicked();
+                });
+            });
+        if save {
+            let (editor, transforms) = self.hash_editor.take().unwrap();
+            let transforms = (transforms != self.match_transforms).then_some(transforms);
+            let hash_kind = HashKind::new(editor.alg, editor.size, editor.dct);
+            let hash_kind = (hash_kind != self.hash_kind).then_some(hash_kind);
+            if transforms.is_some() || hash_kind.is_some() {
+                let (tx, rx) = mpsc::channel();
+                self.hash_settings_recv = Some(rx);
+                let db_pool = self.db_pool.clone();
+                // both saved before rehashing starts, so it hashes the way they were both set
+                self.rt.as_ref().unwrap().spawn(async move {
+                    let transforms = match transforms {
+                        Some(transforms) => Some((transforms, set_match_transforms(&db_pool, transforms).await)),
+                        None => None,
+                    };
+                    let kind = match hash_kind {
+                        Some(hash_kind) => Some((hash_kind, hash_kind.save(&db_pool).await)),
+                        None => None,
+                    };
+                    let _ = tx.send(HashSettingsSaved { transforms, kind });
+                });
+            }
+        } else if close {
+            self.hash_editor = None;
+        }
+    }
+
+    // the library rehashes once the new settings are saved, whether or not the library manager is still open
+    fn poll_hash_settings(&mut self, ctx: &egui::Context) {
+        let Some(rx) = &self.hash_settings_recv else { return };
+        match rx.try_recv() {
+            Ok(saved) => {
+                self.hash_settings_recv = None;
+                if let Some((transforms, unvaried)) = saved.transforms {
+                    self.match_transforms = transforms;
+                    self.hash_dupes = vec![];
+                    self.which_hash_set = 0;
+                    if unvaried > 0 {
+                        eprintln!("Matching rotated and mirrored copies, {} entries to rehash", unvaried);
+                        self.popover = PopOvers::HashingDbUpdate;
+                        self.filelist_loaded = false;
+                        self.hashing_cancelled = CancellationToken::new();
                     }
                 }
-            })
+                match saved.kind {
+                    Some((hash_kind, Ok(unhashed))) => {
+                        eprintln!("Hashing with {}, {} entries to rehash", hash_kind.name(), unhashed);
+                        self.hash_kind = hash_kind;
+                        *self.phash_index.write().unwrap() = BkTree::new();
+                        self.hash_dupes = vec![];
+                        self.which_hash_set = 0;
+                        self.popover = PopOvers::HashingDbUpdate;
+                        self.filelist_loaded = false;
+                        self.hashing_cancelled = CancellationToken::new();
+                    },
+                    Some((hash_kind, Err(e))) => eprintln!("Could not switch to {}: {:?}", hash_kind.name(), e),
+                    None => (),
+                }
+            },
+            Err(TryRecvError::Empty) => ctx.request_repaint(),
+            Err(TryRecvError::Disconnected) => self.hash_settings_recv = None,
+        }
+    }
+
+    fn rules_editor_frame(&mut self, ui: &mut Ui) {
+        if let Some(rx) = &self.rules_editor_recv {
+            match rx.try_recv() {
+                Ok(editor) => { self.rules_editor = Some(editor); self.rules_editor_recv = None },
+                Err(TryRecvError::Empty) => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.colored_label(Color32::BLACK, "Loading rules...");
+                    });
+                    ui.ctx().request_repaint();
+                    return
+                },
+                Err(TryRecvError::Disconnected) => self.rules_editor_recv = None,
+            }
+        }
+        let Some(editor) = &mut self.rules_editor else { return };
+        let mut close = false;
+        let mut save = false;
+        egui::Frame::none()
+            .fill(Color32::from_rgb(200, 190, 164))
+            .inner_margin(egui::Margin::symmetric(5., 5.))
+            .outer_margin(egui::Margin::symmetric(2., 4.))
+            .show(ui, |ui| {
+                ui.label(RichText::new(match &editor.dir {
+                    Some(dir) => format!("Scan rules for {}", dir.to_string_lossy()),
+                    None => "Scan rules for all directories".to_string(),
+                }).strong().color(Color32::BLACK));
+                ui.colored_label(Color32::BLACK, "Exclude, one glob per line:")
+                    .on_hover_text_at_pointer("e.g. node_modules/, .git/, *.tmp or raw/**/*.xmp, !keep.tmp to let one back in\nA .refstoignore file in a directory excludes globs relative to it");
+                ui.add(egui::TextEdit::multiline(&mut editor.excludes).desired_rows(3).desired_width(f32::INFINITY));
+                ui.colored_label(Color32::BLACK, "Only include, one glob per line:")
+                    .on_hover_text_at_pointer("Leave empty to include every file not excluded");
+                ui.add(egui::TextEdit::multiline(&mut editor.includes).desired_rows(2).desired_width(f32::INFINITY));
+                if editor.dir.is_some() {
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut editor.limit_depth, RichText::new("Descend at most").color(Color32::BLACK));
+                        ui.add_enabled(editor.limit_depth, egui::DragValue::new(&mut editor.max_depth).clamp_range(0..=64));
+                        ui.colored_label(Color32::BLACK, "directories");
+                    });
+                    ui.checkbox(&mut editor.skip_hidden, RichText::new("Skip hidden files and directories").color(Color32::BLACK));
+                    ui.checkbox(&mut editor.follow_symlinks, RichText::new("Follow symlinks").color(Color32::BLACK))
+                        .on_hover_text_at_pointer("Directories reached more than once, e.g. through a symlink loop, are only walked the first time");
+                    ui.checkbox(&mut editor.one_filesystem, RichText::new("Stay on this filesystem").color(Color32::BLACK))
+                        .on_hover_text_at_pointer("Don't descend into other mounts beneath the directory");
+                }
+                ui.horizontal(|ui| {
+                    save = ui.button("Save").on_hover_text_at_pointer("RELOAD afterwards to rescan with the new rules").clicked();
+                    close = ui.button("Cancel").clicked();
+                });
+            });
+        if save {
+            let editor = self.rules_editor.take().unwrap();
+            self.save_rules(editor);
+        } else if close {
+            self.rules_editor = None;
+        }
+    }
+
+    fn is_offline(&self, path: &Path) -> bool {
+        volume::is_offline(path, &self.offline_dirs.read().unwrap())
+    }
+
+    fn receive_thumbnails(&mut self) {
+        while let Ok((loaded_path, thumbnail)) = self.thumbnails_recv.try_recv() {
+            self.thumbnails.insert(loaded_path, thumbnail.map_or(Thumbnail::Failed, Thumbnail::Loaded));
+            self.thumbnails_pending -= 1;
+        };
+    }
+
+    // thumbnails are loaded in the background on first request, off the runtime's threads and a decode slot each,
+    // as decoding can take external programs
+    fn get_thumbnail(&mut self, path: &PathBuf) -> &Thumbnail {
+        if !self.thumbnails.contains_key(path) {
+            self.thumbnails.insert(path.to_owned(), Thumbnail::Loading);
+            self.thumbnails_pending += 1;
+            let tx = self.thumbnails_tx.clone();
+            let path = path.to_owned();
+            self.rt.as_ref().unwrap().spawn(async move {
+                let _decoding = budget::decode_slot().await;
+                let thumbnail = {
+                    let path = path.clone();
+                    tokio::task::spawn_blocking(move || load_thumbnail(&path)).await.unwrap_or(None)
+                };
+                let _ = tx.send((path, thumbnail));
+            });
+        }
+        self.thumbnails.get(path).unwrap()
+    }
+
+    fn spawn_cluster(&mut self) {
+        let hi = HashIndexer::with_phash_index(self.db_pool.clone(), self.phash_index.clone());
+        // hamming_proximity is given as 0 - 100 percentile difference
+        let hamming_distance = ((self.hamming_proximity * self.hash_kind.bits()) / 100) as u32;
+        let (tx, rx) = mpsc::channel();
+        self.hash_dupes = vec![];
+        self.which_hash_set = 0;
+        self.hash_dupes_recv = Some(rx);
+        self.rt.as_ref().unwrap().spawn(async move {
+            hi.cluster(hamming_distance, None, tx).await;
+        });
+    }
+
+    fn spawn_filter(&mut self) {
+        let (field, value) = self.metadata_filter.clone();
+        let db_pool = self.db_pool.clone();
+        let (tx, rx) = mpsc::channel();
+        self.metadata_matches_recv = Some(rx);
+        self.rt.as_ref().unwrap().spawn(async move {
+            let _ = tx.send(matching(&db_pool, field, &value).await);
+        });
+    }
+
+    fn receive_metadata_matches(&mut self) {
+        if let Some(rx) = &self.metadata_matches_recv {
+            match rx.try_recv() {
+                Ok(matches) => { self.metadata_matches = Some(matches); self.metadata_matches_recv = None },
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => self.metadata_matches_recv = None,
+            }
         }
+    }
+
+    fn receive_hash_dupes(&mut self) {
+        if let Some(rx) = &self.hash_dupes_recv {
+            loop { match rx.try_recv() {
+                Ok(HashDupeMessage::NewSet) => self.hash_dupes.push(vec![]),
+                Ok(HashDupeMessage::Entry(path, transform)) => { self.hash_dupes.last_mut().expect("Tried inserting to hash_dupes before creating set").push((path, transform)); },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.hash_dupes_recv = None;
+                    break
+                },
+            } }
+        }
+    }
+
+    // walks the watched directories, updating files as they are found
+    fn spawn_scan(&mut self) {
+        println!("Spawned scan");
+        let watched_dirs: Vec<PathBuf> = self.watched_dirs.read().unwrap().iter().cloned().collect();
+        let throughput = Arc::new(Throughput::default());
+        self.scan_throughput = throughput.clone();
+        self.scan_walking = true;
+        self.hashing_complete = false;
+        self.rehashed_cnt = 0;
+        let (tx, rx) = mpsc::channel();
+        self.rehashed_cnt_recv = Some(rx);
+        let db_pool = self.db_pool.clone();
+        let phash_index = self.phash_index.clone();
         let ct = self.hashing_cancelled.clone();
+        let wic = self.watched_image_count.clone();
+        self.rt.as_ref().unwrap().spawn(async move {
+            let dirs = volume::scanned_dirs(&db_pool, watched_dirs).await;
+            pipeline::scan(db_pool.clone(), phash_index, dirs, throughput, ct, move |entry, result| {
+                match result {
+                    Ok(_) => (),
+                    Err(HashIndexError::Encoding) => eprintln!("Encoding Error on file '{}'", entry.to_string_lossy()),
+                    // Enable for verbose skipping of non-image files
+                    // Err(HashIndexError::Format) => eprintln!("Skipping bad format file '{}'", entry.to_string_lossy()),
+                    Err(HashIndexError::Format) => (),
+                    Err(HashIndexError::MalformedDB) => eprintln!("MalformedDB Error on file '{}'", entry.to_string_lossy()),
+                    Err(HashIndexError::InsertDB) => eprintln!("InsertDB Error on file '{}'", entry.to_string_lossy()),
+                    Err(HashIndexError::FileNotFound) => println!("Skipping missing file '{}'", entry.to_string_lossy()),
+                    Err(HashIndexError::TooLarge) => eprintln!("Skipping too large file '{}'", entry.to_string_lossy()),
+                    Err(HashIndexError::Other) => eprintln!("Other Error on file '{}'", entry.to_string_lossy()),
+                }
+                let _ = tx.send(1);
+            }).await;
+            wic.store(sqlx::query("SELECT COUNT(*) FROM entries WHERE ignored = 0;").fetch_one(db_pool.acquire().await.unwrap().acquire().await.unwrap()).await.unwrap().get::<i64,_>(0), Relaxed);
+        });
+    }
+
+    // verifies and disposes of the duplicates found in the background, reporting each file as it goes
+    fn spawn_disposal(&mut self) {
+        self.deleted_file_cnt = 0;
+        self.disposal_errors = vec![];
+        self.unverified_sets = vec![];
+        let (tx, rx) = mpsc::channel();
+        self.disposal_recv = Some(rx);
+        let journal = Journal::begin(&self.db_pool, "dedup");
+        self.dedup_journal = Some(journal.clone());
+        let db_pool = self.db_pool.clone();
+        let sets = self.bin_dupes.clone();
+        let method = self.disposal_method.clone();
+        self.rt.as_ref().unwrap().spawn(async move {
+            let (verified, unverified) = verify_dupes(&db_pool, &sets).await;
+            let _ = tx.send(DisposalMessage::Unverified(unverified));
+            dispose_dupes(&verified, &method, &journal, |file, res| { let _ = tx.send(DisposalMessage::Disposed(file.to_owned(), res)); }).await;
+        });
+    }
+
+    // looks for entries whose files are gone in the background, to be previewed before they are forgotten
+    fn find_missing(&mut self) {
+        let hi = HashIndexer::new(self.db_pool.clone());
+        let watched_dirs = self.watched_dirs.read().unwrap().clone();
+        let (tx, rx) = mpsc::channel();
+        self.missing_recv = Some(rx);
+        self.missing_files = vec![];
+        self.unreachable_dirs = vec![];
+        self.popover = PopOvers::CleanMissing;
         self.rt.as_ref().unwrap().spawn(async move {
-            while let Some(_) = fut_set.next().await{ if ct.is_cancelled() { break } }
+            let _ = tx.send(hi.find_missing(&watched_dirs).await);
         });
     }
 
     fn clean_missing(&mut self) {
-        let conn = self.db_pool.clone();
-        let cloned_list = self.filelist.clone();
-        self.rt.as_ref().unwrap().block_on(async move {
-            let stored_filelist: HashSet<PathBuf> = sqlx::query("SELECT * FROM entries").fetch_all(conn.acquire().await.unwrap().acquire().await.unwrap()).await.unwrap().iter().map(|x| PathBuf::from(x.get::<String,_>("fullpath"))).collect();
-            let deleted_files: Vec<String> = stored_filelist.symmetric_difference(&cloned_list).map(|x| x.to_string_lossy().to_string()).collect();
-            let mut delete_builder: sqlx::QueryBuilder<sqlx::Sqlite> = sqlx::QueryBuilder::new("DELETE FROM entries WHERE fullpath IN (");
-            let mut sep = delete_builder.separated(", ");
-            // print!("DELETE FROM entries WHERE fullpath IN (");
-            for file in deleted_files {
-                // print!("'{}', ", file);
-                sep.push_bind(file);
+        let hi = HashIndexer::with_phash_index(self.db_pool.clone(), self.phash_index.clone());
+        let missing = std::mem::take(&mut self.missing_files);
+        let journal = self.dedup_journal.take().unwrap_or_else(|| Journal::begin(&self.db_pool, "clean_missing"));
+        let wic = self.watched_image_count.clone();
+        let db_pool = self.db_pool.clone();
+        self.rt.as_ref().unwrap().spawn(async move {
+            hi.forget(&missing, &journal).await;
+            wic.store(sqlx::query("SELECT COUNT(*) FROM entries WHERE ignored = 0;").fetch_one(db_pool.acquire().await.unwrap().acquire().await.unwrap()).await.unwrap().get::<i64,_>(0), Relaxed);
+        });
+        self.popover = PopOvers::None;
+    }
+
+    fn clean_missing_win(&mut self, ctx: &egui::Context) {
+        if let Some(missing_recv) = &self.missing_recv {
+            match missing_recv.try_recv() {
+                Ok((missing, unreachable)) => {
+                    self.missing_files = missing;
+                    self.unreachable_dirs = unreachable;
+                    self.missing_recv = None;
+                },
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => self.missing_recv = None,
             }
-            // println!(");");
-            sep.push_unseparated(");");
-            let delete_query = delete_builder.build();
-            // println!("{}", sqlx::Execute::sql(&delete_query));
-            match delete_query.execute(conn.acquire().await.unwrap().acquire().await.unwrap()).await {
-                Err(x) => eprintln!("{:?}", x),
-                Ok(x) => println!(">>> DELETED {} ENTRIES FROM DATABASE", x.rows_affected()),
+        }
+        popover_frame("Clean Missing", ctx, Some([400.,400.].into()), |ui| {
+            ui.label(RichText::new("Forget missing files").text_style(egui::TextStyle::Heading).color(Color32::BLACK));
+            hcenter_no_expand(ui, |ui| {ui.separator();});
+            if self.missing_recv.is_some() {
+                ui.horizontal(|ui| {
+                    ui.colored_label(Color32::BLACK, "Looking for files that no longer exist...");
+                    ui.spinner();
+                });
+            } else {
+                if !self.unreachable_dirs.is_empty() {
+                    ui.label(RichText::new(format!("Skipped {} unreachable or empty directories, files beneath them are kept:", self.unreachable_dirs.len())).color(Color32::DARK_RED));
+                    egui::ScrollArea::vertical().id_source("unreachable_dirs").max_height(60.).show(ui, |ui| {
+                        for dir in &self.unreachable_dirs {
+                            ui.colored_label(Color32::DARK_RED, dir.to_string_lossy().to_string());
+                        }
+                    });
+                }
+                ui.colored_label(Color32::BLACK, format!("{} entries are of files that no longer exist:", self.missing_files.len()));
+                egui::ScrollArea::vertical().id_source("missing_files").max_height(200.).show(ui, |ui| {
+                    for file in &self.missing_files {
+                        ui.colored_label(Color32::BLACK, file.to_string_lossy().to_string());
+                    }
+                });
             }
+            ui.horizontal(|ui| {
+                if ui.add_enabled(self.missing_recv.is_none() && !self.missing_files.is_empty(), egui::Button::new(format!("Forget {} entries", self.missing_files.len()))).clicked() {
+                    self.clean_missing();
+                }
+                if ui.button("Cancel").clicked() {
+                    self.popover = PopOvers::None;
+                    self.missing_recv = None;
+                    self.dedup_journal = None;
+                }
+            });
         });
-        self.popover = PopOvers::HashingDbUpdate;
-        self.filelist_loaded = false;
-        self.hashing_cancelled = CancellationToken::new();
     }
 
-    // fn spawn_find_dupes(rt_handle: &runtime::Handle, cancel_token: CancellationToken, db_pool: sqlx::SqlitePool, hash_dupes: Arc<tokio::sync::RwLock<Vec<Vec<String>>>>, bin_dupes: Arc<tokio::sync::RwLock<Vec<Vec<String>>>>, hamming_proximity: usize, done_flag: Arc<AtomicBool>) {
-    //     rt_handle.spawn(async move {
-    //         let hi = HashIndexer::new(db_pool);
-    //         hi.cluster(hash_dupes, bin_dupes, hamming_proximity, cancel_token).await;
-    //         done_flag.store(true, Relaxed);
-    //     });
-    // }
+    // undoes in the background, files being moved or copied back
+    fn undo_last(&mut self) {
+        let (tx, rx) = mpsc::channel();
+        self.undo_recv = Some(rx);
+        let db_pool = self.db_pool.clone();
+        self.rt.as_ref().unwrap().spawn(async move {
+            let report = match undo_last(&db_pool).await {
+                None => vec!["Nothing to undo.".to_string()],
+                Some((kind, results)) => {
+                    let mut report = vec![format!("Undid {}, restored {}/{} files.", kind, results.iter().filter(|x| x.1.is_ok()).count(), results.len())];
+                    for (fullpath, res) in results {
+                        if let Err(e) = res {
+                            eprintln!("Could not restore {}: {}", fullpath.to_string_lossy(), e);
+                            report.push(format!("{}: {}", fullpath.to_string_lossy(), e));
+                        }
+                    }
+                    report
+                },
+            };
+            let _ = tx.send(report);
+        });
+    }
 
     fn main_win(&mut self, ui: &mut egui::Ui) {
+        self.receive_hash_dupes();
+        self.receive_metadata_matches();
+        self.receive_thumbnails();
+        if self.hash_dupes_recv.is_some() || self.metadata_matches_recv.is_some() || self.thumbnails_pending > 0 {
+            ui.ctx().request_repaint();
+        }
         ui.vertical(|ui| {
             egui::containers::Frame {
                 inner_margin: egui::style::Margin { left: 10., right: 10., top: 4., bottom: 4.},
@@ -313,7 +885,32 @@ impl IndexingGui {
                     ui.separator();
                     ui.add(egui::widgets::DragValue::new(&mut self.hamming_proximity).clamp_range(0..=100));
                     ui.label(RichText::new("% different by hash").color(Color32::BLACK));
-                    if ui.button("LOAD").clicked() {
+                    if ui.add_enabled(self.hash_dupes_recv.is_none(), egui::Button::new("LOAD")).clicked() {
+                        self.spawn_cluster();
+                    }
+                    if self.hash_dupes_recv.is_some() {
+                        ui.spinner();
+                    }
+                    ui.separator();
+                    ui.label(RichText::new("Only sets with").color(Color32::BLACK));
+                    egui::ComboBox::from_id_source("metadata_field").selected_text(self.metadata_filter.0.describe()).show_ui(ui, |ui| {
+                        for field in MetadataField::ALL {
+                            ui.selectable_value(&mut self.metadata_filter.0, field, field.describe());
+                        }
+                    });
+                    if self.metadata_filter.0 != MetadataField::HasGps {
+                        ui.add(egui::TextEdit::singleline(&mut self.metadata_filter.1).desired_width(100.));
+                    }
+                    if ui.add_enabled(self.metadata_matches_recv.is_none(), egui::Button::new("FILTER"))
+                        .on_hover_text_at_pointer(RichText::new("Capture dates match from their start, e.g. 2021-06").color(egui::Color32::WHITE))
+                        .clicked() {
+                        self.spawn_filter();
+                    }
+                    if self.metadata_matches_recv.is_some() {
+                        ui.spinner();
+                    }
+                    if self.metadata_matches.is_some() && ui.button("CLEAR").clicked() {
+                        self.metadata_matches = None;
                     }
                     // change to RTL
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
@@ -331,7 +928,12 @@ impl IndexingGui {
                             self.hashing_cancelled = CancellationToken::new();
                         }
                         if ui.button("CLEAN MISSING").clicked() {
-                            self.clean_missing();
+                            self.find_missing();
+                        }
+                        if ui.add_enabled(self.undo_recv.is_none(), egui::Button::new("UNDO LAST"))
+                            .on_hover_text_at_pointer(RichText::new("Reverses the last deduplication or clean up,\nwherever its files can still be recovered").color(egui::Color32::WHITE))
+                            .clicked() {
+                            self.undo_last();
                         }
                     });
                 });
@@ -355,21 +957,38 @@ impl IndexingGui {
                         fill: Color32::GRAY,
                         stroke: egui::Stroke::new(2.0, Color32::BLACK),
                     }.show(ui, |ui| {
-                        let snapshot_set_images_len = self.get_set_images().len();
-                        egui::ScrollArea::vertical().max_width(avail_size.x/3.).drag_to_scroll(false).show_rows(ui, 128., snapshot_set_images_len, |ui, row_range| {
-                            for idx in row_range {
-                                ui.allocate_ui_at_rect(Rect {min: ui.cursor().min, max: ui.cursor().min+[128.,128.].into()}, |ui| {
+                        let snapshot_hash_dupes_len = self.hash_dupes.len();
+                        let visible: Vec<usize> = (0..snapshot_hash_dupes_len).filter(|idx| match &self.metadata_matches {
+                            Some(matches) => self.hash_dupes[*idx].iter().any(|x| matches.contains(&x.0)),
+                            None => true,
+                        }).collect();
+                        egui::ScrollArea::vertical().max_width(avail_size.x/3.).drag_to_scroll(false).show_rows(ui, 128., visible.len(), |ui, row_range| {
+                            for idx in row_range.map(|x| visible[x]) {
+                                let first_entry = self.hash_dupes[idx][0].0.clone();
+                                let set_len = self.hash_dupes[idx].len();
+                                let row = ui.allocate_ui_at_rect(Rect {min: ui.cursor().min, max: ui.cursor().min+[128.,128.].into()}, |ui| {
                                     ui.horizontal_centered(|ui| {
-                                        ui.label(format!("Row {}/{}", idx, snapshot_set_images_len));
+                                        ui.label(format!("Set {}/{}\n{} images", idx+1, snapshot_hash_dupes_len, set_len));
                                         let cursor = Rect::from_min_max(ui.cursor().min, ui.cursor().min+[128.,128.].into());
                                         ui.allocate_ui_at_rect(Rect {min: ui.cursor().min, max: ui.cursor().min+[128.,128.].into()}, |ui| {
                                             ui.centered_and_justified(|ui| {
-                                                ui.image(self.get_set_images()[idx].texture_id(ui.ctx()), aspect_fit(self.get_set_images()[idx].size_vec2(), [128., 128.]));
+                                                let offline = self.is_offline(&first_entry);
+                                                match self.get_thumbnail(&first_entry) {
+                                                    Thumbnail::Loaded(thumbnail) => { ui.image(thumbnail.texture_id(ui.ctx()), aspect_fit(thumbnail.size_vec2(), [128., 128.])); },
+                                                    _ if offline => { ui.colored_label(Color32::DARK_GRAY, "OFFLINE"); },
+                                                    Thumbnail::Failed => { ui.colored_label(Color32::DARK_GRAY, "NO PREVIEW"); },
+                                                    Thumbnail::Loading => { ui.spinner(); },
+                                                }
                                             });
                                         });
-                                        ui.painter_at(cursor).add(egui::Shape::rect_stroke(Rect {min: cursor.min, max: cursor.min+[128.,128.].into()}, egui::Rounding::none(), egui::Stroke::new(5., egui::Color32::from_rgb(255, 100, 100))));
+                                        if idx == self.which_hash_set {
+                                            ui.painter_at(cursor).add(egui::Shape::rect_stroke(Rect {min: cursor.min, max: cursor.min+[128.,128.].into()}, egui::Rounding::none(), egui::Stroke::new(5., egui::Color32::from_rgb(255, 100, 100))));
+                                        }
                                     });
                                 });
+                                if row.response.interact(egui::Sense::click()).clicked() {
+                                    self.which_hash_set = idx;
+                                }
                             }
                         });
                         ui.allocate_space(Vec2 {x:0., y:ui.available_height()});
@@ -387,10 +1006,21 @@ impl IndexingGui {
                         stroke: egui::Stroke::new(2.0, Color32::BLACK),
                     }.show(ui, |ui| {
                         egui::ScrollArea::horizontal().max_height(140.).drag_to_scroll(false).show(ui, |ui| {
-                            for img in self.get_set_images().iter() {
+                            let set = self.hash_dupes.get(self.which_hash_set).cloned().unwrap_or_default();
+                            for (entry, transform) in set.iter() {
                                 ui.allocate_ui_at_rect(Rect {min: ui.cursor().min, max: ui.cursor().min+[128.,128.].into()}, |ui| {
-                                    ui.centered_and_justified(|ui| {
-                                        ui.image(img.texture_id(ui.ctx()), aspect_fit(img.size_vec2(), [128.,128.]));
+                                    ui.vertical_centered(|ui| {
+                                        let offline = self.is_offline(entry);
+                                        let thumbnail_size = if *transform == Transform::Identity { 128. } else { 110. };
+                                        match self.get_thumbnail(entry) {
+                                            Thumbnail::Loaded(img) => { ui.image(img.texture_id(ui.ctx()), aspect_fit(img.size_vec2(), [thumbnail_size, thumbnail_size])).on_hover_text(entry.to_string_lossy().to_string()); },
+                                            _ if offline => { ui.colored_label(Color32::DARK_GRAY, "OFFLINE").on_hover_text(entry.to_string_lossy().to_string()); },
+                                            Thumbnail::Failed => { ui.colored_label(Color32::DARK_GRAY, "NO PREVIEW").on_hover_text(entry.to_string_lossy().to_string()); },
+                                            Thumbnail::Loading => { ui.spinner(); },
+                                        }
+                                        if *transform != Transform::Identity {
+                                            ui.colored_label(Color32::BLACK, format!("same image, {}", transform.describe()));
+                                        }
                                     });
                                 });
                             }
@@ -405,6 +1035,7 @@ impl IndexingGui {
     fn watch_dir_manager_win(&mut self, ctx: &egui::Context) {
         let mut dir_to_del: Option<PathBuf> = None;
         let mut dir_to_add = None;
+        let mut rules_to_edit: Option<Option<PathBuf>> = None;
         popover_frame("dirwatchwin", ctx, Some([400.,600.].into()), |ui| {
             ui.horizontal(|ui| {
                 ui.label(RichText::new("Drag and drop directories to add or").color(egui::Color32::BLACK));
@@ -422,9 +1053,18 @@ impl IndexingGui {
                         if ui.add(egui::Button::new(RichText::new("🗙").color(Color32::WHITE).strong().size(20.)).fill(Color32::LIGHT_RED)).clicked() {
                             self.popover = PopOvers::None;
                         }
+                        if ui.button("Global rules").clicked() {
+                            rules_to_edit = Some(None);
+                        }
+                        if ui.add_enabled(self.hash_settings_recv.is_none(), egui::Button::new("Hashing")).clicked() {
+                            self.hash_editor = Some((self.hash_kind, self.match_transforms));
+                        }
                 });
             });
             egui::ScrollArea::vertical().show(ui, |ui| {
+                self.rules_editor_frame(ui);
+                self.hash_editor_frame(ui);
+                self.dir_removal_frame(ui);
                 match self.watched_dirs.try_read() {
                     Ok(watched_dirs) => {
                         for dir in (*watched_dirs).iter() {
@@ -438,7 +1078,11 @@ impl IndexingGui {
                             }.show(ui, |ui| {
                                 ui.horizontal(|ui| {
                                     let entrycolor; 
-                                    if dir.is_dir() {
+                                    if self.offline_dirs.read().unwrap().contains(dir) {
+                                        entrycolor = Color32::DARK_GRAY;
+                                        ui.label(RichText::new("OFFLINE").color(entrycolor))
+                                            .on_hover_text_at_pointer("Its volume isn't mounted, entries beneath it are kept\nand it is picked up again wherever the volume is mounted next");
+                                    } else if dir.is_dir() {
                                         entrycolor = Color32::BLACK;
                                     } else {
                                         entrycolor = Color32::DARK_RED;
@@ -449,6 +1093,9 @@ impl IndexingGui {
                                         if ui.add(egui::Button::new(RichText::new("REMOVE").strong().color(Color32::WHITE)).fill(egui::Color32::RED)).clicked() {
                                             dir_to_del = Some(dir.to_owned());
                                         };
+                                        if ui.button("RULES").clicked() {
+                                            rules_to_edit = Some(Some(dir.to_owned()));
+                                        }
                                     });
                                 });
                             });
@@ -471,8 +1118,11 @@ impl IndexingGui {
                 self.error_no_dialogs = !ui.button("OK").clicked();
             });
         }
+        if let Some(dir) = rules_to_edit {
+            self.edit_rules(dir);
+        }
         if let Some(dir) = dir_to_del {
-            self.del_watched_dir(dir, false);
+            self.confirm_dir_removal(dir);
         }
         if let Some(dir) = dir_to_add {
             self.add_watched_dir(dir);
@@ -492,96 +1142,43 @@ impl IndexingGui {
         }
     }
 
-    // call once a frame if !filelist_loaded
-    // will set self.filelist_loaded = true when complete
-    fn receive_filelist_entries(&mut self) {
-        if let Some(filelist_recv) = &self.filelist_recv { loop {
-            match filelist_recv.try_recv() {
-                Ok(filename) => {self.filelist.insert(filename);},
-                Err(TryRecvError::Empty) => break,
-                Err(TryRecvError::Disconnected) => {
-                    self.filelist_loaded = true;
-                    self.hashing_complete = false;
-                    let mut fut_set = FuturesUnordered::new();
-                    self.rehashed_cnt = 0;
-                    let (tx, rx) = mpsc::channel();
-                    self.rehashed_cnt_recv = Some(rx);
-                    for entry in &self.filelist {
-                        // println!("looking at {}", entry.to_string_lossy());
-                        let db_pool = self.db_pool.clone();
-                        let entry = entry.to_owned();
-                        let tx = tx.clone();
-                        fut_set.push(async move {
-                            match HashIndexer::new(db_pool).update(entry.to_string_lossy().into()).await {
-                                Ok(_) => (),
-                                Err(HashIndexError::Encoding) => eprintln!("Encoding Error on file '{}'", entry.to_string_lossy()),
-                                // Enable for verbose skipping of non-image files
-                                // Err(HashIndexError::Format) => eprintln!("Skipping bad format file '{}'", entry.to_string_lossy()),
-                                Err(HashIndexError::Format) => (),
-                                Err(HashIndexError::MalformedDB) => eprintln!("MalformedDB Error on file '{}'", entry.to_string_lossy()),
-                                Err(HashIndexError::InsertDB) => eprintln!("InsertDB Error on file '{}'", entry.to_string_lossy()),
-                                Err(HashIndexError::FileNotFound) => println!("Skipping missing file '{}'", entry.to_string_lossy()),
-                                Err(HashIndexError::Other) => eprintln!("Other Error on file '{}'", entry.to_string_lossy()),
-                            }
-                            tx.send(1).unwrap();
-                        })
-                    }
-                    let ct = self.hashing_cancelled.clone();
-                    println!("started {} update tasks", fut_set.len());
-
-                    // let conn = self.db_pool.clone();
-                    // let cloned_list = self.filelist.clone();
-                    // self.rt.as_ref().unwrap().spawn(async move {
-                    //     let stored_filelist: HashSet<PathBuf> = sqlx::query("SELECT * FROM entries").fetch_all(conn.acquire().await.unwrap().acquire().await.unwrap()).await.unwrap().iter().map(|x| PathBuf::from(x.get::<String,_>("filepath"))).collect();
-                    //     let deleted_files: Vec<String> = stored_filelist.difference(&cloned_list).map(|x| x.to_string_lossy().to_string()).collect();
-                    //     println!("Files missing from filesystem:\n{:?}", deleted_files);
-                    // });
-
-                    let wic = self.watched_image_count.clone();
-                    let conn = self.db_pool.clone();
-                    self.rt.as_ref().unwrap().spawn(async move {
-                        while let Some(_) = fut_set.next().await { if ct.is_cancelled() { break };}
-                        wic.store(sqlx::query("SELECT COUNT(*) FROM entries WHERE ignored = 0;").fetch_one(conn.acquire().await.unwrap().acquire().await.unwrap()).await.unwrap().get::<i64,_>(0), Relaxed);
-                    });
-                    break
-                }
-            }
-        } }
-    }
-
     fn hashing_progress_win(&mut self, ctx: &egui::Context) {
         popover_frame("Hashing Progress Window", ctx, None, |ui| {
-            if !self.filelist_loaded { match &self.filelist_recv {
-                Some(_) => self.receive_filelist_entries(),
-                None => self.spawn_load_filelist(),
-                }
-            } else {
-                self.filelist_recv = None;
-                if let Some(recv) = &self.rehashed_cnt_recv {
-                    loop { match recv.try_recv() {
-                        Ok(rx) => self.rehashed_cnt += rx,
-                        Err(TryRecvError::Empty) => break,
-                        Err(TryRecvError::Disconnected) => {self.hashing_complete = true; break},
-                    }};
+            if !self.filelist_loaded {
+                if !self.scan_walking {
+                    self.spawn_scan();
+                } else if self.scan_throughput.walked() {
+                    self.filelist_loaded = true;
+                    self.scan_walking = false;
                 }
-            } 
+            }
+            // files are updated as they are found
+            if let Some(recv) = &self.rehashed_cnt_recv {
+                loop { match recv.try_recv() {
+                    Ok(rx) => self.rehashed_cnt += rx,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {self.hashing_complete = true; break},
+                }};
+            }
             ui.horizontal(|ui| {
                 if self.filelist_loaded {
                     ui.image(self.checkmark.texture_id(ctx), [12.,12.]);
                 } else {
                     ui.add(egui::Spinner::new().size(24.));
                 }
-                ui.label(egui::RichText::new(format!("Discovered {} files...", self.filelist.len())).color(egui::Color32::BLACK));
+                ui.label(egui::RichText::new(format!("Discovered {} files...", self.scan_throughput.walk.files())).color(egui::Color32::BLACK));
             });
             // let widest = ui.horizontal(|ui| {
             ui.horizontal(|ui| {
                 match (self.hashing_complete, self.filelist_loaded) {
                     (true,true) => {ui.image(self.checkmark.texture_id(ctx), [12.,12.]); self.rehashed_cnt_recv = None},
-                    (false,true) => {ui.add(egui::Spinner::new().size(12.));},
-                    (_, false) => ui.add_space(12.),
+                    (false,_) => {ui.add(egui::Spinner::new().size(12.));},
+                    (true,false) => ui.add_space(12.),
                 }
-                ui.label(egui::RichText::new(format!("Updated {}/{} files...", self.rehashed_cnt, self.filelist.len())).color(egui::Color32::BLACK));
+                ui.label(egui::RichText::new(format!("Updated {}/{} files...", self.rehashed_cnt, self.scan_throughput.walk.files())).color(egui::Color32::BLACK));
             }).response.rect.width();
+            // which stage holds the others up
+            ui.label(egui::RichText::new(self.scan_throughput.summary()).small().color(egui::Color32::DARK_GRAY));
             if self.hashing_complete && self.filelist_loaded {
                 ui.horizontal(|ui| { ui.label(egui::RichText::new("Hashing complete, database updated!").color(egui::Color32::LIGHT_GREEN));});
             }
@@ -599,43 +1196,90 @@ impl IndexingGui {
         });
     }
 
+    // options shared by the selection and loading steps of the binary deduplicator
+    fn bin_dedup_options(&mut self, ui: &mut Ui) {
+        ui.colored_label(Color32::BLACK, "Which duplicate should be kept:");
+        let width = ui.min_size().x;
+        egui::Frame::none()
+            .fill(Color32::from_rgb(200, 190, 164))
+            .inner_margin(egui::Margin::symmetric(5., 5.))
+            .outer_margin(egui::Margin::symmetric(5., 5.))
+            .show(ui, |ui| {
+                ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::CreatedFirst,   RichText::new("Created first").color(Color32::BLACK));
+                ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::ModifiedFirst,  RichText::new("Modified least recently").color(Color32::BLACK));
+                ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::NameShortest,   RichText::new("Shortest name").color(Color32::BLACK));
+                ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::PathShallowest, RichText::new("Shallowest path").color(Color32::BLACK));
+                ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::PathShortest,   RichText::new("Shortest path").color(Color32::BLACK));
+                ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::MostMetadata,   RichText::new("Most metadata").color(Color32::BLACK));
+                ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::CapturedFirst,  RichText::new("Captured first").color(Color32::BLACK));
+                ui.allocate_space([width-20., 0.].into());
+                hcenter_no_expand(ui, |ui| {
+                    ui.separator();
+                    ui.checkbox(&mut self.bin_dedup_method_reversed, "Reverse order").on_hover_text_at_pointer("Switches order, i.e.: Created First → Created Last");
+                });
+            });
+        egui::Frame::none()
+            .fill(Color32::from_rgb(200, 190, 164))
+            .inner_margin(egui::Margin::symmetric(5., 5.))
+            .outer_margin(egui::Margin::symmetric(5., 5.))
+            .show(ui, |ui| {
+                ui.allocate_ui_with_layout([width-20.,0.].into(), egui::Layout::left_to_right(egui::Align::Center), |ui| {
+                    ui.add(egui::Checkbox::without_text(&mut self.incl_ignored));
+                    if ui.add(egui::Label::new(RichText::new("Dedupe ignored files").color(Color32::BLACK)).sense(egui::Sense::click())).on_hover_cursor(egui::CursorIcon::Help).on_hover_text_at_pointer(RichText::new("Also delete duplicated non-image files hashed in database")).clicked() {
+                        self.incl_ignored = !self.incl_ignored;
+                    }
+                });
+                ui.allocate_space([width-20., 0.].into());
+            });
+        ui.colored_label(Color32::BLACK, "What to do with the other copies:");
+        egui::Frame::none()
+            .fill(Color32::from_rgb(200, 190, 164))
+            .inner_margin(egui::Margin::symmetric(5., 5.))
+            .outer_margin(egui::Margin::symmetric(5., 5.))
+            .show(ui, |ui| {
+                ui.radio_value(&mut self.disposal_method, DisposalMethod::Trash, RichText::new("Move to trash").color(Color32::BLACK));
+                let quarantine_selected = matches!(self.disposal_method, DisposalMethod::Quarantine(_));
+                if ui.radio(quarantine_selected, RichText::new("Move to quarantine folder").color(Color32::BLACK)).on_hover_text_at_pointer("Moved files keep their original paths beneath the quarantine folder,\nrun `refsto restore <folder>` to move them back").clicked() {
+                    match &self.quarantine_dir {
+                        Some(dir) => self.disposal_method = DisposalMethod::Quarantine(dir.to_owned()),
+                        None => self.choose_quarantine_dir(),
+                    }
+                }
+                if quarantine_selected {
+                    ui.horizontal(|ui| {
+                        ui.add_space(18.);
+                        if ui.small_button("Change").clicked() {
+                            self.choose_quarantine_dir();
+                        }
+                        if let Some(dir) = &self.quarantine_dir {
+                            ui.colored_label(Color32::BLACK, dir.to_string_lossy().to_string());
+                        }
+                    });
+                }
+                ui.radio_value(&mut self.disposal_method, DisposalMethod::Link, RichText::new("Replace with links to kept file").color(Color32::BLACK)).on_hover_text_at_pointer("Copies stay at their paths but share the kept file's data,\nusing reflinks where the filesystem supports them and hardlinks otherwise");
+                ui.radio_value(&mut self.disposal_method, DisposalMethod::Delete, RichText::new("Delete permanently").color(Color32::DARK_RED));
+                ui.allocate_space([width-20., 0.].into());
+            });
+    }
+
+    fn choose_quarantine_dir(&mut self) {
+        match native_dialog::FileDialog::new().set_location("~").show_open_single_dir() {
+            Ok(Some(dir)) => {
+                self.quarantine_dir = Some(dir.to_owned());
+                self.disposal_method = DisposalMethod::Quarantine(dir);
+            },
+            Ok(None) => (),
+            Err(_) => self.error_no_dialogs = true,
+        }
+    }
+
     fn binary_dedup_win(&mut self, ctx: &egui::Context) {
         if let PopOvers::BinaryDedup(step) = self.popover { match step { 
             BinDedupStep::SelectMethod => {
-                popover_frame("Binary Deduplicator", ctx, Some([270.,270.].into()), |ui| {
+                popover_frame("Binary Deduplicator", ctx, Some([270.,400.].into()), |ui| {
                     ui.label(RichText::new("Delete exact duplicates of images").text_style(egui::TextStyle::Heading).color(Color32::BLACK));
                     hcenter_no_expand(ui, |ui| {ui.separator();});
-                    ui.colored_label(Color32::BLACK, "Which duplicate should be kept:");
-                    let width = ui.min_size().x;
-                    egui::Frame::none()
-                        .fill(Color32::from_rgb(200, 190, 164))
-                        .inner_margin(egui::Margin::symmetric(5., 5.))
-                        .outer_margin(egui::Margin::symmetric(5., 5.))
-                        .show(ui, |ui| {
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::CreatedFirst,   RichText::new("Created first").color(Color32::BLACK));
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::ModifiedFirst,  RichText::new("Modified least recently").color(Color32::BLACK));
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::NameShortest,   RichText::new("Shortest name").color(Color32::BLACK));
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::PathShallowest, RichText::new("Shallowest path").color(Color32::BLACK));
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::PathShortest,   RichText::new("Shortest path").color(Color32::BLACK));
-                            ui.allocate_space([width-20., 0.].into());
-                            hcenter_no_expand(ui, |ui| {
-                                ui.separator();
-                                ui.checkbox(&mut self.bin_dedup_method_reversed, "Reverse order").on_hover_text_at_pointer("Switches order, i.e.: Created First → Created Last");
-                            });
-                        });
-                    egui::Frame::none()
-                        .fill(Color32::from_rgb(200, 190, 164))
-                        .inner_margin(egui::Margin::symmetric(5., 5.))
-                        .outer_margin(egui::Margin::symmetric(5., 5.))
-                        .show(ui, |ui| {
-                            ui.allocate_ui_with_layout([width-20.,0.].into(), egui::Layout::left_to_right(egui::Align::Center), |ui| {
-                                ui.add(egui::Checkbox::without_text(&mut self.incl_ignored));
-                                if ui.add(egui::Label::new(RichText::new("Dedupe ignored files").color(Color32::BLACK)).sense(egui::Sense::click())).on_hover_cursor(egui::CursorIcon::Help).on_hover_text_at_pointer(RichText::new("Also delete duplicated non-image files hashed in database")).clicked() {
-                                    self.incl_ignored = !self.incl_ignored;
-                                }
-                            });
-                            ui.allocate_space([width-20., 0.].into());
-                        });
+                    self.bin_dedup_options(ui);
                     hcenter_no_expand(ui, |ui| {
                         if ui.button("OK?").clicked() {
                             let hi = HashIndexer::new(self.db_pool.clone());
@@ -643,8 +1287,11 @@ impl IndexingGui {
                             let (tx, rx) = mpsc::channel();
                             let method = self.bin_dedup_method;
                             let reversed = self.bin_dedup_method_reversed;
+                            let disposal_method = self.disposal_method.clone();
+                            let db_pool = self.db_pool.clone();
                             self.bin_dupes_recv = Some(rx);
                             self.rt.as_ref().unwrap().spawn(async move {
+                                disposal_method.save(&db_pool).await;
                                 hi.find_bindupes(incl_ignored, method, reversed, tx).await;
                             });
                             // self.which_set = 0;
@@ -674,41 +1321,11 @@ impl IndexingGui {
                     },
                 }
                 if drop_recv { self.bin_dupes_recv = None };
-                popover_frame("Binary Deduplicator", ctx, Some([270.,270.].into()), |ui| {
+                popover_frame("Binary Deduplicator", ctx, Some([270.,400.].into()), |ui| {
                     ui.add_enabled_ui(false, |ui| {
                         ui.label(RichText::new("Delete exact duplicates of images").text_style(egui::TextStyle::Heading).color(Color32::BLACK));
                         hcenter_no_expand(ui, |ui| {ui.separator();});
-                        ui.colored_label(Color32::BLACK, "Which duplicate should be kept:");
-                        let width = ui.min_size().x;
-                        egui::Frame::none()
-                        .fill(Color32::from_rgb(200, 190, 164))
-                        .inner_margin(egui::Margin::symmetric(5., 5.))
-                        .outer_margin(egui::Margin::symmetric(5., 5.))
-                        .show(ui, |ui| {
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::CreatedFirst,   RichText::new("Created first").color(Color32::BLACK));
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::ModifiedFirst,  RichText::new("Modified least recently").color(Color32::BLACK));
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::NameShortest,   RichText::new("Shortest name").color(Color32::BLACK));
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::PathShallowest, RichText::new("Shallowest path").color(Color32::BLACK));
-                            ui.radio_value(&mut self.bin_dedup_method, KeepWhichFile::PathShortest,   RichText::new("Shortest path").color(Color32::BLACK));
-                            ui.allocate_space([width-20., 0.].into());
-                            hcenter_no_expand(ui, |ui| {
-                                ui.separator();
-                                ui.checkbox(&mut self.bin_dedup_method_reversed, "Reverse order").on_hover_text_at_pointer("Switches order, i.e.: Created First → Created Last");
-                            });
-                        });
-                    egui::Frame::none()
-                        .fill(Color32::from_rgb(200, 190, 164))
-                        .inner_margin(egui::Margin::symmetric(5., 5.))
-                        .outer_margin(egui::Margin::symmetric(5., 5.))
-                        .show(ui, |ui| {
-                            ui.allocate_ui_with_layout([width-20.,0.].into(), egui::Layout::left_to_right(egui::Align::Center), |ui| {
-                                ui.add(egui::Checkbox::without_text(&mut self.incl_ignored));
-                                if ui.add(egui::Label::new(RichText::new("Dedupe ignored files").color(Color32::BLACK)).sense(egui::Sense::click())).on_hover_cursor(egui::CursorIcon::Help).on_hover_text_at_pointer(RichText::new("Also delete duplicated non-image files hashed in database")).clicked() {
-                                    self.incl_ignored = !self.incl_ignored;
-                                }
-                            });
-                        ui.allocate_space([width-20., 0.].into());
-                        });
+                        self.bin_dedup_options(ui);
                     });
                     hcenter_no_expand(ui, |ui| {
                         ui.label(RichText::new({
@@ -740,7 +1357,12 @@ impl IndexingGui {
                             if ui.button("Cancel").clicked() {
                                 self.popover = PopOvers::None;
                             }
-                            if ui.button("Delete 'em!").clicked() {
+                            if ui.add_enabled(!self.bin_dupes.is_empty(), egui::Button::new(match self.disposal_method {
+                                DisposalMethod::Trash => "Trash 'em!",
+                                DisposalMethod::Quarantine(_) => "Quarantine 'em!",
+                                DisposalMethod::Delete => "Delete 'em!",
+                                DisposalMethod::Link => "Link 'em!",
+                            })).clicked() {
                                 self.popover = PopOvers::BinaryDedup(BinDedupStep::WarnConfirm);
                             }
                         });
@@ -749,9 +1371,13 @@ impl IndexingGui {
                         .fill(Color32::LIGHT_GRAY)
                         .show(ui, |ui| {
                             egui::ScrollArea::vertical().max_height(270.).max_width(270.).show(ui, |ui| {
-                            if self.bin_dupes.len() > 0 {
+                            if !self.bin_dupes.is_empty() {
                                 for entry in &self.bin_dupes[self.which_set] {
-                                    ui.colored_label(Color32::BLACK, entry.to_string_lossy().to_string());
+                                    if self.is_offline(entry) {
+                                        ui.colored_label(Color32::DARK_GRAY, format!("{} (offline)", entry.to_string_lossy()));
+                                    } else {
+                                        ui.colored_label(Color32::BLACK, entry.to_string_lossy().to_string());
+                                    }
                                 }
                             } else {
                                 ui.colored_label(Color32::DARK_GREEN, "No binary duplicates found!");
@@ -770,11 +1396,29 @@ impl IndexingGui {
                         ui.allocate_space([width, 0.].into());
                         ui.label(RichText::new("WARNING!").heading().color(Color32::RED));
                         ui.label(RichText::new("Pressing continue will").heading().color(Color32::BLACK));
-                        ui.label(RichText::new("DELETE").heading().color(Color32::RED));
-                        ui.label(RichText::new(format!("{} files from disk", self.bin_dupes.iter().flatten().count())).heading().color(Color32::BLACK));
+                        let disposed_cnt: usize = self.bin_dupes.iter().map(|x| x.len()-1).sum();
+                        match &self.disposal_method {
+                            DisposalMethod::Trash => {
+                                ui.label(RichText::new("MOVE").heading().color(Color32::RED));
+                                ui.label(RichText::new(format!("{} files to the trash", disposed_cnt)).heading().color(Color32::BLACK));
+                            },
+                            DisposalMethod::Quarantine(dir) => {
+                                ui.label(RichText::new("MOVE").heading().color(Color32::RED));
+                                ui.label(RichText::new(format!("{} files to {}", disposed_cnt, dir.to_string_lossy())).heading().color(Color32::BLACK));
+                            },
+                            DisposalMethod::Delete => {
+                                ui.label(RichText::new("PERMANENTLY DELETE").heading().color(Color32::RED));
+                                ui.label(RichText::new(format!("{} files from disk", disposed_cnt)).heading().color(Color32::BLACK));
+                            },
+                            DisposalMethod::Link => {
+                                ui.label(RichText::new("REPLACE").heading().color(Color32::RED));
+                                ui.label(RichText::new(format!("{} files with links to the kept files", disposed_cnt)).heading().color(Color32::BLACK));
+                            },
+                        }
                         ui.add_space(60.);
                         ui.horizontal_centered(|ui| {
                             if ui.button("Bye, files").clicked() {
+                                self.spawn_disposal();
                                 self.popover = PopOvers::BinaryDedup(BinDedupStep::Deletion);
                             }
                             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
@@ -787,38 +1431,67 @@ impl IndexingGui {
                 });
             },
             BinDedupStep::Deletion => {
-                for set in &self.bin_dupes {
-                    println!("{}", set.len());
-                    assert!(set.len() >= 2);
-                    let mut set_iter = set.iter();
-                    println!("KEEP {}", set_iter.next().unwrap().to_string_lossy());
-                    for file in set_iter {
-                        std::fs::remove_file(file).unwrap();
-                        self.deleted_file_cnt += 1;
-                    }
+                if let Some(rx) = &self.disposal_recv {
+                    loop { match rx.try_recv() {
+                        Ok(DisposalMessage::Unverified(reasons)) => self.unverified_sets = reasons,
+                        Ok(DisposalMessage::Disposed(_, Ok(_))) => self.deleted_file_cnt += 1,
+                        Ok(DisposalMessage::Disposed(file, Err(e))) => {
+                            eprintln!("Could not dispose of {}: {}", file.to_string_lossy(), e);
+                            self.disposal_errors.push(format!("{}: {}", file.to_string_lossy(), e));
+                        },
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            self.disposal_recv = None;
+                            self.popover = PopOvers::BinaryDedup(BinDedupStep::ReviewDeleted);
+                            break
+                        },
+                    } }
                 }
-                self.filelist = HashSet::new();
-                self.filelist_loaded = false;
-                self.spawn_load_filelist();
-                self.popover = PopOvers::BinaryDedup(BinDedupStep::ReviewDeleted);
+                let disposed_cnt: usize = self.bin_dupes.iter().map(|x| x.len().saturating_sub(1)).sum();
+                popover_frame("Binary Deduplicator", ctx, Some([270.,270.].into()), |ui| {
+                    ui.label(RichText::new("Delete exact duplicates of images").text_style(egui::TextStyle::Heading).color(Color32::BLACK));
+                    hcenter_no_expand(ui, |ui| {ui.separator();});
+                    ui.horizontal(|ui| {
+                        ui.add(egui::Spinner::new().size(12.));
+                        ui.label(RichText::new(format!("Disposing of {}/{} files...", self.deleted_file_cnt + self.disposal_errors.len(), disposed_cnt)).color(Color32::BLACK));
+                    });
+                });
+                ctx.request_repaint();
             },
             BinDedupStep::ReviewDeleted => {
                 popover_frame("Binary Deduplicator", ctx, Some([270.,270.].into()), |ui| {
                     ui.label(RichText::new("Delete exact duplicates of images").text_style(egui::TextStyle::Heading).color(Color32::BLACK));
                     hcenter_no_expand(ui, |ui| {ui.separator();});
-                    ui.label(RichText::new(format!("Deleted {} files.", self.deleted_file_cnt)).color(Color32::BLACK));
-                    if !self.filelist_loaded {
-                        self.receive_filelist_entries(); 
-                        ui.spinner();
-                    } else {
-                        ui.add_space(12.);
+                    ui.label(RichText::new(format!("{} {} files.", self.disposal_method.past_tense(), self.deleted_file_cnt)).color(Color32::BLACK));
+                    if !self.unverified_sets.is_empty() {
+                        ui.label(RichText::new(format!("Skipped {} sets that failed verification:", self.unverified_sets.len())).color(Color32::DARK_RED));
+                        egui::ScrollArea::vertical().id_source("unverified_sets").max_height(100.).show(ui, |ui| {
+                            for reason in &self.unverified_sets {
+                                ui.colored_label(Color32::DARK_RED, reason);
+                            }
+                        });
                     }
-                    if ui.add_enabled(self.filelist_loaded, egui::Button::new("Accept the consequences\nof your actions")).clicked() {
-                        self.clean_missing();
+                    if !self.disposal_errors.is_empty() {
+                        ui.label(RichText::new(format!("{} files could not be disposed of:", self.disposal_errors.len())).color(Color32::DARK_RED));
+                        egui::ScrollArea::vertical().max_height(100.).show(ui, |ui| {
+                            for error in &self.disposal_errors {
+                                ui.colored_label(Color32::DARK_RED, error);
+                            }
+                        });
+                    }
+                    ui.add_space(12.);
+                    if ui.button("Accept the consequences\nof your actions").clicked() {
+                        self.find_missing();
                     }
                 });
             },
         } }
+        if self.error_no_dialogs {
+            popover_frame("Dialog Error", ctx, Some([200.,200.].into()), |ui| {
+                ui.colored_label(egui::Color32::RED, "ERROR: no system dialog found");
+                self.error_no_dialogs = !ui.button("OK").clicked();
+            });
+        }
         match self.popover {
             PopOvers::BinaryDedup(_) => (),
             _ => {
@@ -853,17 +1526,30 @@ fn popover_frame<R>(id: impl Into<egui::Id>, ctx: &egui::Context, size: Option<V
     })
 }
 
+fn load_thumbnail(path: &PathBuf) -> Option<RetainedImage> {
+    match std::fs::read(path).map(|bytes| crate::exif::load_upright(path, &bytes, false)) {
+        Ok(Ok((image, _))) => {
+            let image = image.thumbnail(128, 128);
+            let color_image = egui::ColorImage::from_rgba_unmultiplied([image.width().try_into().unwrap(), image.height().try_into().unwrap()], image.to_rgba8().as_flat_samples().as_slice());
+            Some(RetainedImage::from_color_image(path.to_string_lossy(), color_image))
+        },
+        _ => {
+            eprintln!("Resize failed on image {}...", path.to_string_lossy());
+            None
+        },
+    }
+}
+
 fn aspect_fit(img_size: impl Into<Vec2>, fit_size: impl Into<Vec2>) -> Vec2 {
     let img_size = img_size.into();
     let fit_size = fit_size.into();
     let img_rat = img_size.x / img_size.y;
     let fit_rat = fit_size.x / fit_size.y;
-    let ratio;
-    if img_rat < fit_rat {
-        ratio = fit_size.y / img_size.y;
+    let ratio = if img_rat < fit_rat {
+        fit_size.y / img_size.y
     } else {
-        ratio = fit_size.x / img_size.x;
-    }
+        fit_size.x / img_size.x
+    };
     Vec2 {x: ratio*img_size.x, y: ratio*img_size.y}
 }
 
@@ -878,8 +1564,41 @@ impl eframe::App for IndexingGui {
             PopOvers::BinaryDedup(_) => self.binary_dedup_win(ctx),
             PopOvers::HashingDbUpdate => self.hashing_progress_win(ctx),
             PopOvers::LibraryManager => self.watch_dir_manager_win(ctx),
+            PopOvers::CleanMissing => self.clean_missing_win(ctx),
             PopOvers::None => ()
         }
+        self.poll_hash_settings(ctx);
+        if let Some(rx) = &self.undo_recv {
+            match rx.try_recv() {
+                Ok(report) => { self.undo_report = Some(report); self.undo_recv = None },
+                Err(TryRecvError::Empty) => {
+                    popover_frame("Undo", ctx, Some([270.,200.].into()), |ui| {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label(RichText::new("Undoing...").color(Color32::BLACK));
+                        });
+                    });
+                    ctx.request_repaint();
+                },
+                Err(TryRecvError::Disconnected) => self.undo_recv = None,
+            }
+        }
+        if let Some(report) = &self.undo_report {
+            let mut dismissed = false;
+            popover_frame("Undo", ctx, Some([270.,200.].into()), |ui| {
+                ui.label(RichText::new(&report[0]).color(Color32::BLACK));
+                if report.len() > 1 {
+                    ui.label(RichText::new(format!("{} files could not be restored:", report.len()-1)).color(Color32::DARK_RED));
+                    egui::ScrollArea::vertical().max_height(100.).show(ui, |ui| {
+                        for error in &report[1..] {
+                            ui.colored_label(Color32::DARK_RED, error);
+                        }
+                    });
+                }
+                dismissed = ui.button("OK").clicked();
+            });
+            if dismissed { self.undo_report = None }
+        }
     }
 
     fn on_exit(&mut self, _ctx: Option<&eframe::glow::Context>) {
@@ -0,0 +1,210 @@
+use sqlx::{Row, Acquire};
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum HashAlgorithm {
+    Mean,
+    Gradient,
+    DoubleGradient,
+    Blockhash,
+}
+
+// perceptual hash a library is compared by, hashes of other kinds are kept alongside to switch back to
+#[derive(Clone, Copy, PartialEq)]
+pub struct HashKind {
+    pub alg: HashAlgorithm,
+    pub size: u32, // hash is size x size bits
+    pub dct: bool, // DCT preprocessing, better at recompression and worse at crops
+}
+
+// what every library was hashed with before the kind was configurable
+impl Default for HashKind {
+    fn default() -> Self {
+        HashKind { alg: HashAlgorithm::Gradient, size: 8, dct: false }
+    }
+}
+
+impl HashKind {
+    pub fn new(alg: HashAlgorithm, size: u32, dct: bool) -> Self {
+        let size = size.clamp(4, 32);
+        match alg {
+            // blockhash works on blocks of 4 and never resizes, so there is nothing to preprocess
+            HashAlgorithm::Blockhash => HashKind { alg, size: size.div_ceil(4) * 4, dct: false },
+            _ => HashKind { alg, size, dct },
+        }
+    }
+
+    // as recorded in metadata and hashes, e.g. gradient:8 or mean:16:dct
+    pub fn name(&self) -> String {
+        let alg = match self.alg {
+            HashAlgorithm::Mean => "mean",
+            HashAlgorithm::Gradient => "gradient",
+            HashAlgorithm::DoubleGradient => "double_gradient",
+            HashAlgorithm::Blockhash => "blockhash",
+        };
+        format!("{}:{}{}", alg, self.size, if self.dct { ":dct" } else { "" })
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        let mut parts = name.split(':');
+        let alg = match parts.next()? {
+            "mean" => HashAlgorithm::Mean,
+            "gradient" => HashAlgorithm::Gradient,
+            "double_gradient" => HashAlgorithm::DoubleGradient,
+            "blockhash" => HashAlgorithm::Blockhash,
+            _ => return None,
+        };
+        let size = parts.next()?.parse().ok()?;
+        Some(HashKind::new(alg, size, parts.next() == Some("dct")))
+    }
+
+    pub fn hasher(&self) -> image_hasher::Hasher {
+        let config = image_hasher::HasherConfig::new().hash_size(self.size, self.size).hash_alg(match self.alg {
+            HashAlgorithm::Mean => image_hasher::HashAlg::Mean,
+            HashAlgorithm::Gradient => image_hasher::HashAlg::Gradient,
+            HashAlgorithm::DoubleGradient => image_hasher::HashAlg::DoubleGradient,
+            HashAlgorithm::Blockhash => image_hasher::HashAlg::Blockhash,
+        });
+        if self.dct { config.preproc_dct().to_hasher() } else { config.to_hasher() }
+    }
+
+    // length of the hashes, which Hamming distances are out of
+    pub fn bits(&self) -> usize {
+        self.hasher().hash_image(&image::DynamicImage::new_rgb8(self.size * 2, self.size * 2)).as_bytes().len() * 8
+    }
+
+    pub async fn load(db_pool: &sqlx::SqlitePool) -> Self {
+        let mut conn = loop {
+            if let Ok(acquisition) = db_pool.acquire().await {
+                break acquisition;
+            }
+        };
+        let name: Option<String> = sqlx::query("SELECT hash_kind FROM metadata").fetch_one(conn.acquire().await.unwrap()).await
+            .expect("SELECT hash_kind from metadata failed!")
+            .get("hash_kind");
+        name.and_then(|x| Self::parse(&x)).unwrap_or_default()
+    }
+
+    // switches the library to this kind, bringing back hashes of it from before where there are any,
+    // returns how many entries are left to be rehashed by the next scan
+    pub async fn save(&self, db_pool: &sqlx::SqlitePool) -> anyhow::Result<i64> {
+        let mut transaction = db_pool.begin().await?;
+        sqlx::query("UPDATE metadata SET hash_kind = ?").bind(self.name()).execute(&mut *transaction).await?;
+        sqlx::query("UPDATE entries SET phash = (SELECT phash FROM hashes WHERE hashes.entry_id = entries.entry_id AND kind = ?) WHERE ignored = 0").bind(self.name()).execute(&mut *transaction).await?;
+        // sets found with the old hashes say nothing about the new ones
+        sqlx::query("DELETE FROM hash_dupe_sets_x_entries; DELETE FROM hash_dupe_sets;").execute(&mut *transaction).await?;
+        let unhashed = sqlx::query("SELECT COUNT(*) FROM entries WHERE ignored = 0 AND phash IS NULL").fetch_one(&mut *transaction).await?.get::<i64,_>(0);
+        transaction.commit().await?;
+        Ok(unhashed)
+    }
+}
+
+// kinds stored for the library and how many entries have each
+pub async fn stored_kinds(db_pool: &sqlx::SqlitePool) -> Vec<(String, i64)> {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    sqlx::query("SELECT kind, COUNT(*) AS cnt FROM hashes GROUP BY kind ORDER BY kind").fetch_all(conn.acquire().await.unwrap()).await
+        .expect("SELECT from hashes failed!")
+        .iter().map(|x| (x.get("kind"), x.get("cnt"))).collect()
+}
+
+// orientation of an image relative to another it matched, the eight ways a photo gets rotated or mirrored
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Transform {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipH,
+    FlipV,
+    Transpose,
+    Transverse,
+}
+
+impl Transform {
+    // variants hashed besides the image as it is
+    pub const VARIANTS: [Transform; 7] = [Transform::Rotate90, Transform::Rotate180, Transform::Rotate270, Transform::FlipH, Transform::FlipV, Transform::Transpose, Transform::Transverse];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transform::Identity => "identity",
+            Transform::Rotate90 => "rotate90",
+            Transform::Rotate180 => "rotate180",
+            Transform::Rotate270 => "rotate270",
+            Transform::FlipH => "flip_h",
+            Transform::FlipV => "flip_v",
+            Transform::Transpose => "transpose",
+            Transform::Transverse => "transverse",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [Transform::Identity].iter().chain(Self::VARIANTS.iter()).find(|x| x.name() == name).copied()
+    }
+
+    // what turns an image stored with this EXIF orientation upright
+    pub fn from_orientation(orientation: u16) -> Self {
+        match orientation {
+            2 => Transform::FlipH,
+            3 => Transform::Rotate180,
+            4 => Transform::FlipV,
+            5 => Transform::Transpose,
+            6 => Transform::Rotate90,
+            7 => Transform::Transverse,
+            8 => Transform::Rotate270,
+            _ => Transform::Identity,
+        }
+    }
+
+    // as shown to users, completing "same image, ..."
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Transform::Identity => "as is",
+            Transform::Rotate90 => "rotated 90°",
+            Transform::Rotate180 => "rotated 180°",
+            Transform::Rotate270 => "rotated 270°",
+            Transform::FlipH => "mirrored",
+            Transform::FlipV => "flipped upside down",
+            Transform::Transpose => "rotated 90° and mirrored",
+            Transform::Transverse => "rotated 270° and mirrored",
+        }
+    }
+
+    // rotations are clockwise
+    pub fn apply(&self, img: &image::DynamicImage) -> image::DynamicImage {
+        match self {
+            Transform::Identity => img.clone(),
+            Transform::Rotate90 => img.rotate90(),
+            Transform::Rotate180 => img.rotate180(),
+            Transform::Rotate270 => img.rotate270(),
+            Transform::FlipH => img.fliph(),
+            Transform::FlipV => img.flipv(),
+            Transform::Transpose => img.rotate90().fliph(),
+            Transform::Transverse => img.rotate270().fliph(),
+        }
+    }
+}
+
+// whether rotated and mirrored copies are matched, which has the indexer hash every variant of each image
+pub async fn match_transforms(db_pool: &sqlx::SqlitePool) -> bool {
+    crate::settings::get_setting(db_pool, "match_transforms").await.as_deref() == Some("true")
+}
+
+// returns how many entries are left without variant hashes of the kind in use for the next scan
+pub async fn set_match_transforms(db_pool: &sqlx::SqlitePool, enabled: bool) -> i64 {
+    crate::settings::set_setting(db_pool, "match_transforms", if enabled { "true" } else { "false" }).await;
+    if !enabled {
+        return 0
+    }
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    // videos are matched by their frames and never get variants
+    sqlx::query("SELECT COUNT(*) FROM entries WHERE ignored = 0 AND entry_id NOT IN (SELECT entry_id FROM transform_hashes WHERE kind = (SELECT hash_kind FROM metadata)) AND entry_id NOT IN (SELECT entry_id FROM frame_hashes)").fetch_one(conn.acquire().await.unwrap()).await
+        .expect("SELECT from transform_hashes failed!")
+        .get::<i64,_>(0)
+}
@@ -1,15 +1,36 @@
-use std::{time::SystemTime, path::PathBuf};
+use std::{time::{Instant, SystemTime}, os::unix::fs::MetadataExt, path::{Path, PathBuf}, sync::{Arc, RwLock}, collections::{HashMap, HashSet}};
 // use futures::stream::FuturesUnordered;
 use sqlx::{Row, sqlite::SqliteQueryResult, Acquire};
-use tokio::fs::metadata;
-use xxhash_rust::xxh3::xxh3_64;
+use tokio::{fs::metadata, io::AsyncReadExt, sync::OnceCell};
+use xxhash_rust::xxh3::{Xxh3, xxh3_64};
 
-use crate::HASH_SIZE_BYTES;
-use crate::gui::{BinDupeMessage, KeepWhichFile};
+use crate::animation;
+use crate::bktree::BkTree;
+use crate::budget;
+use crate::decode;
+use crate::gui::{BinDupeMessage, HashDupeMessage, KeepWhichFile};
+use crate::hashkind::{HashKind, Transform, match_transforms};
+use crate::journal::Journal;
+use crate::metadata::ImageMetadata;
+use crate::pipeline::{self, Throughput};
+use crate::video;
+use crate::watcher::like_beneath;
+
+const FORGET_BATCH_SIZE: usize = 500;
+// of a video only this much is kept in memory, where most containers put their metadata
+const VIDEO_HEAD_SIZE: usize = 1 << 20;
+// the decoded image and a copy of it, as hashers and transforms work on copies
+const DECODED_BYTES_PER_PIXEL: u64 = 8;
+
+// the phashes of an entry rotated and mirrored, by the transform that produced each
+type TransformHashes = Vec<(Transform, Box<[u8]>)>;
 
 pub struct HashIndexer {
     db_pool: sqlx::SqlitePool,
-    hasher_config: image_hasher::HasherConfig<[u8; HASH_SIZE_BYTES]>,
+    phash_index: Arc<RwLock<BkTree>>,
+    throughput: Arc<Throughput>,
+    // hash kind and whether to match transforms, read once for every file updated, so an indexer misses them changing
+    settings: OnceCell<(HashKind, bool)>,
 }
 
 pub enum HashIndexError {
@@ -18,19 +39,42 @@ pub enum HashIndexError {
     MalformedDB,
     InsertDB,
     FileNotFound,
+    TooLarge, // more pixels than allowed, or more than the whole memory budget to read and decode
     Other,
 }
 
+// what decoding and hashing a file on the hash pool comes back with
+struct Hashed {
+    phash: image_hasher::ImageHash,
+    orientation: u16,
+    variants: Vec<(Transform, image_hasher::ImageHash)>,
+    frame_hashes: Vec<image_hasher::ImageHash>,
+    frame_cnt: Option<i64>,
+    duration: Option<f64>,
+}
+
 impl HashIndexer {
     pub fn new(db_pool: sqlx::SqlitePool) -> Self {
-        // let hasher = img_hash::HasherConfig::new().to_hasher();
-        let hasher_config = image_hasher::HasherConfig::with_bytes_type::<[u8; HASH_SIZE_BYTES]>();
-        HashIndexer{db_pool, hasher_config}
+        Self::with_phash_index(db_pool, Arc::new(RwLock::new(BkTree::new())))
+    }
+
+    // share one phash index between indexers so updates are visible to later clustering
+    pub fn with_phash_index(db_pool: sqlx::SqlitePool, phash_index: Arc<RwLock<BkTree>>) -> Self {
+        HashIndexer{db_pool, phash_index, throughput: Arc::new(Throughput::default()), settings: OnceCell::new()}
+    }
+
+    // reads and hashes counted towards a scan's throughput
+    pub fn with_throughput(self, throughput: Arc<Throughput>) -> Self {
+        HashIndexer{throughput, ..self}
+    }
+
+    async fn settings(&self) -> (HashKind, bool) {
+        *self.settings.get_or_init(|| async { (HashKind::load(&self.db_pool).await, match_transforms(&self.db_pool).await) }).await
     }
 
     pub async fn update(&self, fullpath: String) -> Result<SqliteQueryResult, HashIndexError> {
         let fullpath = fullpath.as_str();
-        let mut file_bytes = None;
+        let mut contents: Option<(Vec<u8>, i64)> = None;
         let meta = { match metadata(fullpath).await {
             Ok(x) => x,
             Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Err(HashIndexError::FileNotFound),
@@ -40,6 +84,8 @@ impl HashIndexer {
             },
         }};
         let filesize = meta.len() as i64;
+        // paths sharing an inode are the same file, never duplicates of each other
+        let (dev, ino) = (meta.dev() as i64, meta.ino() as i64);
         let mtime = match meta.modified() {
             Ok(time) => time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64,
             Err(_) => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64,
@@ -58,14 +104,49 @@ impl HashIndexer {
                 break acquisition;
             }
         };
-        if let Ok(rows) = sqlx::query("SELECT phash, xxhash, filesize, mtime, ignored FROM entries WHERE fullpath = ?").bind(fullpath).fetch_all(conn.acquire().await.unwrap()).await {
-            if rows.len() > 0 { // fullpath already in db, conditionally compute hash and update
+        let (hash_kind, transforms) = self.settings().await;
+        let mut replaced_entry_id: Option<i64> = None;
+        // unchanged file without a hash of the kind in use, e.g. after switching kinds, rehashed in place to keep its other kinds
+        let mut unhashed_entry_id: Option<i64> = None;
+        // unchanged file hashed before EXIF orientation was honored, rehashed in place if it turns out to be stored rotated
+        let mut unoriented_entry_id: Option<i64> = None;
+        // unchanged file that might be animated, hashed before animations were told apart, rehashed in place if it is
+        let mut unchecked_entry_id: Option<i64> = None;
+        // unchanged image indexed before metadata was, whose metadata is read without decoding it
+        let mut undescribed_entry_id: Option<i64> = None;
+        if let Ok(rows) = sqlx::query("SELECT entry_id, phash, xxhash, filesize, mtime, ignored, decoders, dev, ino, orientation, frame_cnt, EXISTS (SELECT 1 FROM image_metadata m WHERE m.entry_id = entries.entry_id) AS described, EXISTS (SELECT 1 FROM transform_hashes t WHERE t.entry_id = entries.entry_id AND t.kind = ?1) AS variants, EXISTS (SELECT 1 FROM frame_hashes f WHERE f.entry_id = entries.entry_id AND f.kind = ?1) AS frames FROM entries WHERE fullpath = ?2").bind(hash_kind.name()).bind(fullpath).fetch_all(conn.acquire().await.unwrap()).await {
+            if !rows.is_empty() { // fullpath already in db, conditionally compute hash and update
                 if rows.len() > 1 {
                     return Err(HashIndexError::MalformedDB);
                 } else {
-                    let (db_filesize, db_mtime): (i64, i64) = (rows[0].get("filesize"), rows[0].get("mtime"));
-                    if db_filesize == filesize && db_mtime == mtime {
+                    // an entry restored by undoing a clean up from before its times were journaled has none, and is rehashed
+                    let unchanged = rows[0].get::<Option<i64>,_>("filesize") == Some(filesize) && rows[0].get::<Option<i64>,_>("mtime") == Some(mtime);
+                    // no decoder could read it or it was too large when it was ignored, but there are other decoders or another budget now
+                    let skipped_with = rows[0].get::<Option<String>,_>("decoders");
+                    let undecoded = rows[0].get::<bool,_>("ignored") && skipped_with.as_deref() != Some(decode::signature()) && skipped_with != Some(budget::budget().signature());
+                    // videos and animations have no variants, being matched by their frames instead
+                    let sequenced = video::is_video(&pb) || rows[0].get::<Option<i64>,_>("frame_cnt").is_some_and(|x| x > 1);
+                    let unhashed = undecoded || !rows[0].get::<bool,_>("ignored") && (rows[0].get::<Option<Vec<u8>>,_>("phash").is_none()
+                        || (transforms && !sequenced && !rows[0].get::<bool,_>("variants"))
+                        || (sequenced && !rows[0].get::<bool,_>("frames")));
+                    let unoriented = !rows[0].get::<bool,_>("ignored") && rows[0].get::<Option<i64>,_>("orientation").is_none();
+                    let unchecked = !rows[0].get::<bool,_>("ignored") && rows[0].get::<Option<i64>,_>("frame_cnt").is_none() && animation::may_be_animated(&pb);
+                    if unchanged && unhashed {
+                        unhashed_entry_id = Some(rows[0].get("entry_id"));
+                    } else if unchanged && unoriented {
+                        unoriented_entry_id = Some(rows[0].get("entry_id"));
+                    } else if unchanged && unchecked {
+                        unchecked_entry_id = Some(rows[0].get("entry_id"));
+                    } else if unchanged && !rows[0].get::<bool,_>("ignored") && !rows[0].get::<bool,_>("described") {
+                        undescribed_entry_id = Some(rows[0].get("entry_id"));
+                    } else if unchanged {
+                        // hardlinking or migrating from before inodes were stored leaves contents unchanged
+                        if rows[0].get::<Option<i64>,_>("dev") != Some(dev) || rows[0].get::<Option<i64>,_>("ino") != Some(ino) {
+                            return sqlx::query("UPDATE entries SET dev = ?, ino = ? WHERE fullpath = ?").bind(dev).bind(ino).bind(fullpath).execute(conn.acquire().await.unwrap()).await.map_err(|_| HashIndexError::InsertDB);
+                        }
                         return Ok(SqliteQueryResult::default());
+                    } else {
+                        replaced_entry_id = Some(rows[0].get("entry_id"));
                     }
                     // let (db_filesize, db_mtime, db_xxhash, ignored): (i64, i64, i64, bool) = (rows[0].get("filesize"), rows[0].get("mtime"), rows[0].get("xxhash"), rows[0].get("ignored"));
                     // let db_xxhash = u64::from_be_bytes(db_xxhash.to_be_bytes());
@@ -86,28 +167,242 @@ impl HashIndexer {
                 }
             }
         }
+        // whatever is done below reads the file, taking what that and decoding it will take from the memory budget up front
+        let budget = budget::budget();
+        let pixels = decode::pixels(&pb, budget.downscale);
+        let read_bytes = if video::is_video(&pb) { VIDEO_HEAD_SIZE as u64 } else { filesize as u64 };
+        let reserved = match pixels.is_some_and(|(pixels, _)| pixels > budget.max_megapixels as u64 * 1_000_000) {
+            true => None,
+            false => budget::reserve(read_bytes + pixels.map_or(0, |(_, decoded)| decoded * DECODED_BYTES_PER_PIXEL)).await,
+        };
+        // too large files are only read through for their xxhash, new and changed ones being kept as ignored entries below
+        let too_large = reserved.is_none();
+        if too_large && (unoriented_entry_id.is_some() || unchecked_entry_id.is_some() || undescribed_entry_id.is_some()) {
+            return Err(HashIndexError::TooLarge)
+        }
+        if let Some(entry_id) = replaced_entry_id {
+            self.phash_index.write().unwrap().remove(entry_id);
+            // replacing the row doesn't fire hashes_delete, and hashes and metadata of the old contents are of no use
+            let _ = sqlx::query("DELETE FROM hashes WHERE entry_id = ?1; DELETE FROM transform_hashes WHERE entry_id = ?1; DELETE FROM image_metadata WHERE entry_id = ?1; DELETE FROM frame_hashes WHERE entry_id = ?1").bind(entry_id).execute(conn.acquire().await.unwrap()).await;
+        } else if let Some(entry_id) = unoriented_entry_id {
+            let (file_bytes, _) = contents.insert(self.read_contents(fullpath).await?);
+            let orientation = crate::exif::orientation(file_bytes).unwrap_or(1);
+            if orientation == 1 {
+                let _ = ImageMetadata::extract(file_bytes).store(conn.acquire().await.unwrap(), entry_id).await;
+                return sqlx::query("UPDATE entries SET orientation = 1 WHERE entry_id = ?").bind(entry_id).execute(conn.acquire().await.unwrap()).await.map_err(|_| HashIndexError::InsertDB);
+            }
+            // hashes of every kind were taken of it lying sideways
+            self.phash_index.write().unwrap().remove(entry_id);
+            let _ = sqlx::query("DELETE FROM hashes WHERE entry_id = ?; DELETE FROM transform_hashes WHERE entry_id = ?").bind(entry_id).bind(entry_id).execute(conn.acquire().await.unwrap()).await;
+            unhashed_entry_id = Some(entry_id);
+        } else if let Some(entry_id) = unchecked_entry_id {
+            let (file_bytes, _) = contents.insert(self.read_contents(fullpath).await?);
+            if !animation::is_animated(file_bytes) {
+                return sqlx::query("UPDATE entries SET frame_cnt = 1 WHERE entry_id = ?").bind(entry_id).execute(conn.acquire().await.unwrap()).await.map_err(|_| HashIndexError::InsertDB);
+            }
+            // hashes of every kind were taken of its first frame alone
+            self.phash_index.write().unwrap().remove(entry_id);
+            let _ = sqlx::query("DELETE FROM hashes WHERE entry_id = ?1; DELETE FROM transform_hashes WHERE entry_id = ?1").bind(entry_id).execute(conn.acquire().await.unwrap()).await;
+            unhashed_entry_id = Some(entry_id);
+        } else if let Some(entry_id) = undescribed_entry_id {
+            let (file_bytes, _) = self.read_contents(fullpath).await?;
+            return ImageMetadata::extract(&file_bytes).store(conn.acquire().await.unwrap(), entry_id).await.map_err(|_| HashIndexError::InsertDB);
+        } else if unhashed_entry_id.is_none() {
+            // a new path with the contents of a vanished one is a move, carried over without decoding it again
+            let (_, xxhash) = contents.insert(match too_large {
+                true => self.read_head(fullpath).await?,
+                false => self.read_contents(fullpath).await?,
+            });
+            let candidates: Vec<(i64, String)> = sqlx::query("SELECT entry_id, fullpath FROM entries WHERE xxhash = ? AND filesize = ?").bind(*xxhash).bind(filesize).fetch_all(conn.acquire().await.unwrap()).await
+                .unwrap_or_default()
+                .iter().map(|x| (x.get("entry_id"), x.get("fullpath"))).collect();
+            // entries on volumes not mounted right now are out of reach, not gone
+            let offline = match candidates.is_empty() {
+                true => HashSet::new(),
+                false => crate::volume::offline_dirs(&self.db_pool).await,
+            };
+            for (entry_id, old_fullpath) in candidates {
+                if Path::new(&old_fullpath).symlink_metadata().is_ok() || crate::volume::is_offline(Path::new(&old_fullpath), &offline) {
+                    continue
+                }
+                // fullpath in WHERE so a concurrent update claiming the same vanished entry loses
+                let res = sqlx::query("UPDATE entries SET fullpath = ?, filename = ?, dircnt = ?, mtime = ?, dev = ?, ino = ? WHERE entry_id = ? AND fullpath = ?").bind(fullpath).bind(&filename).bind(dircnt).bind(mtime).bind(dev).bind(ino).bind(entry_id).bind(&old_fullpath).execute(conn.acquire().await.unwrap()).await;
+                if let Ok(moved) = res {
+                    if moved.rows_affected() > 0 {
+                        return Ok(moved)
+                    }
+                }
+            }
+        }
         let res = async move {
-            if file_bytes.is_none() { file_bytes = Some(tokio::fs::read(fullpath).await.expect(&format!("ERROR READING FILE {}", fullpath))) };
-            let xxhash = i64::from_be_bytes(xxh3_64(&file_bytes.as_ref().unwrap()).to_be_bytes());
-            let img_bytes = image::load_from_memory(&file_bytes.as_ref().unwrap());
-            match img_bytes {
-                Ok(img_bytes) => {
-                    let phash = self.hasher_config.to_hasher().hash_image(&img_bytes).to_base64();
-                    let res = sqlx::query("INSERT OR REPLACE INTO entries (fullpath, phash, xxhash, filesize, mtime, ctime, filename, dircnt) VALUES (?, ?, ?, ?, ?, ?, ?, ?)").bind(fullpath).bind(phash).bind(xxhash).bind(filesize).bind(mtime).bind(ctime).bind(filename).bind(dircnt).execute(conn.acquire().await.unwrap()).await.or_else(|_| Err(HashIndexError::InsertDB));
+            let (file_bytes, xxhash) = match contents {
+                Some(contents) => contents,
+                None if too_large => self.read_head(fullpath).await?,
+                None => self.read_contents(fullpath).await?,
+            };
+            let (hashed, file_bytes) = match reserved {
+                Some(_reserved) => {
+                    let decoding = budget::decode_slot().await;
+                    let throughput = self.throughput.clone();
+                    let path = pb.clone();
+                    let hashed = pipeline::on_hash_pool(move || {
+                        let started = Instant::now();
+                        let hashed = hash_file(&path, &file_bytes, hash_kind, transforms, budget.downscale);
+                        throughput.record_hash(started.elapsed());
+                        (hashed, file_bytes)
+                    }).await;
+                    drop(decoding);
+                    hashed
+                },
+                None => (Err(image::ImageError::Limits(image::error::LimitError::from_kind(image::error::LimitErrorKind::InsufficientMemory))), file_bytes),
+            };
+            match hashed {
+                Ok(Hashed { phash, orientation, variants, frame_hashes, frame_cnt, duration }) => {
+                    let res = match unhashed_entry_id {
+                        Some(entry_id) => sqlx::query("UPDATE entries SET phash = ?, orientation = ?, frame_cnt = ?, duration = ?, ignored = 0, decoders = NULL WHERE entry_id = ?").bind(phash.as_bytes()).bind(orientation).bind(frame_cnt).bind(duration).bind(entry_id).execute(conn.acquire().await.unwrap()).await,
+                        None => sqlx::query("INSERT OR REPLACE INTO entries (fullpath, phash, xxhash, filesize, mtime, ctime, filename, dircnt, dev, ino, orientation, frame_cnt, duration) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)").bind(fullpath).bind(phash.as_bytes()).bind(xxhash).bind(filesize).bind(mtime).bind(ctime).bind(filename).bind(dircnt).bind(dev).bind(ino).bind(orientation).bind(frame_cnt).bind(duration).execute(conn.acquire().await.unwrap()).await,
+                    }.map_err(|_| HashIndexError::InsertDB);
+                    if let Ok(updated) = &res {
+                        let entry_id = unhashed_entry_id.unwrap_or(updated.last_insert_rowid());
+                        self.phash_index.write().unwrap().insert(entry_id, phash.as_bytes().into());
+                        for (transform, variant) in variants.iter() {
+                            let _ = sqlx::query("INSERT OR REPLACE INTO transform_hashes (entry_id, kind, transform, phash) VALUES (?, ?, ?, ?)").bind(entry_id).bind(hash_kind.name()).bind(transform.name()).bind(variant.as_bytes()).execute(conn.acquire().await.unwrap()).await;
+                        }
+                        for (frame_idx, frame_hash) in frame_hashes.iter().enumerate() {
+                            let _ = sqlx::query("INSERT OR REPLACE INTO frame_hashes (entry_id, kind, frame_idx, phash) VALUES (?, ?, ?, ?)").bind(entry_id).bind(hash_kind.name()).bind(frame_idx as i64).bind(frame_hash.as_bytes()).execute(conn.acquire().await.unwrap()).await;
+                        }
+                        if let Err(e) = ImageMetadata::extract(&file_bytes).store(conn.acquire().await.unwrap(), entry_id).await {
+                            eprintln!("Could not store metadata of {}: {:?}", fullpath, e);
+                        }
+                    }
                     res
                 },
                 Err(image::ImageError::Unsupported(_)) => {
-                    let _ = sqlx::query("INSERT OR REPLACE INTO entries (fullpath, xxhash, filesize, mtime, ctime, filename, dircnt, ignored) VALUES (?, ?, ?, ?, ?, ?, ?, 1)").bind(fullpath).bind(xxhash).bind(filesize).bind(mtime).bind(ctime).bind(filename).bind(dircnt).execute(conn.acquire().await.unwrap()).await.or_else(|_| Err(HashIndexError::InsertDB));
-                    return Err(HashIndexError::Format)
+                    sqlx::query("INSERT OR REPLACE INTO entries (fullpath, xxhash, filesize, mtime, ctime, filename, dircnt, dev, ino, ignored, decoders) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 1, ?)").bind(fullpath).bind(xxhash).bind(filesize).bind(mtime).bind(ctime).bind(filename).bind(dircnt).bind(dev).bind(ino).bind(decode::signature()).execute(conn.acquire().await.unwrap()).await.map_err(|_| HashIndexError::InsertDB)?;
+                    Err(HashIndexError::Format)
+                },
+                Err(image::ImageError::Limits(_)) => {
+                    // passed over by rescans until the budget changes
+                    sqlx::query("INSERT OR REPLACE INTO entries (fullpath, xxhash, filesize, mtime, ctime, filename, dircnt, dev, ino, ignored, decoders) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 1, ?)").bind(fullpath).bind(xxhash).bind(filesize).bind(mtime).bind(ctime).bind(filename).bind(dircnt).bind(dev).bind(ino).bind(budget.signature()).execute(conn.acquire().await.unwrap()).await.map_err(|_| HashIndexError::InsertDB)?;
+                    Err(HashIndexError::TooLarge)
                 },
-                Err(image::ImageError::IoError(_)) => return Err(HashIndexError::Encoding),
-                Err(image::ImageError::Decoding(_)) => return Err(HashIndexError::Encoding),
-                Err(_) => panic!(),
+                Err(image::ImageError::IoError(_)) => Err(HashIndexError::Encoding),
+                Err(image::ImageError::Decoding(_)) => Err(HashIndexError::Encoding),
+                Err(_) => Err(HashIndexError::Other),
             }
         }.await;
         res
     }
 
+    async fn read_contents(&self, fullpath: &str) -> Result<(Vec<u8>, i64), HashIndexError> {
+        self.read(fullpath, read_contents(fullpath)).await
+    }
+
+    // the xxhash of a file too large to be read whole, along with its start
+    async fn read_head(&self, fullpath: &str) -> Result<(Vec<u8>, i64), HashIndexError> {
+        self.read(fullpath, read_head(fullpath)).await
+    }
+
+    // reads a few files at a time, however many updates are running
+    // files go away between being found and read, editors and sync tools renaming and deleting their temporary files
+    async fn read(&self, fullpath: &str, reading: impl std::future::Future<Output = std::io::Result<(Vec<u8>, i64, u64)>>) -> Result<(Vec<u8>, i64), HashIndexError> {
+        let _reading = pipeline::read_slot().await;
+        let started = Instant::now();
+        let (file_bytes, xxhash, read) = match reading.await {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Err(HashIndexError::FileNotFound),
+            Err(e) => {
+                eprintln!("{}: {:?}", fullpath, e);
+                return Err(HashIndexError::Other)
+            },
+        };
+        self.throughput.record_read(read, started.elapsed());
+        Ok((file_bytes, xxhash))
+    }
+
+    // entries whose files are gone, along with the watched directories skipped for being unreachable
+    // an unmounted or unreadable root says nothing about the files beneath it, so those are left alone
+    pub async fn find_missing(&self, watched_dirs: &HashSet<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let mut conn = loop {
+            if let Ok(acquisition) = self.db_pool.acquire().await {
+                break acquisition;
+            }
+        };
+        let conn = conn.acquire().await.unwrap();
+        let stored_filelist: Vec<PathBuf> = sqlx::query("SELECT fullpath FROM entries").fetch_all(&mut *conn).await
+            .expect("SELECT from entries failed!")
+            .iter().map(|x| PathBuf::from(x.get::<String,_>("fullpath"))).collect();
+        let watched_dirs = watched_dirs.clone();
+        let offline = crate::volume::offline_dirs(&self.db_pool).await;
+        tokio::task::spawn_blocking(move || {
+            // files on volumes not mounted right now aren't missing, just out of reach
+            let mut reachable: HashMap<PathBuf, bool> = offline.into_iter().map(|x| (x, false)).collect();
+            let mut missing = vec![];
+            for file in stored_filelist {
+                match file.symlink_metadata() {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                    _ => continue,
+                }
+                // files outside every watched directory are judged by the closest directory still there
+                let root = match watched_dirs.iter().filter(|x| file.starts_with(x)).max_by_key(|x| x.as_os_str().len()) {
+                    Some(root) => root.to_owned(),
+                    None => file.ancestors().skip(1).find(|x| x.is_dir()).unwrap_or(Path::new("/")).to_owned(),
+                };
+                if *reachable.entry(root).or_insert_with_key(|root| is_reachable(root)) {
+                    missing.push(file);
+                }
+            }
+            let mut unreachable: Vec<PathBuf> = reachable.into_iter().filter(|x| !x.1).map(|x| x.0).collect();
+            unreachable.sort();
+            (missing, unreachable)
+        }).await.expect("Checking for missing files failed!")
+    }
+
+    // entries beneath dir that none of the other watched directories cover
+    pub async fn purgeable(&self, dir: &Path, other_dirs: &HashSet<PathBuf>) -> Vec<PathBuf> {
+        let mut conn = loop {
+            if let Ok(acquisition) = self.db_pool.acquire().await {
+                break acquisition;
+            }
+        };
+        let conn = conn.acquire().await.unwrap();
+        sqlx::query("SELECT fullpath FROM entries WHERE fullpath LIKE ? ESCAPE '\\'").bind(like_beneath(dir)).fetch_all(&mut *conn).await
+            .expect("SELECT from entries failed!")
+            .iter().map(|x| PathBuf::from(x.get::<String,_>("fullpath")))
+            .filter(|x| !other_dirs.iter().any(|other| x.starts_with(other)))
+            .collect()
+    }
+
+    // removes the entries of files along with their dedup set memberships, returning how many were removed
+    // journaled and committed in batches, so a large clean up neither holds the database nor is lost halfway
+    pub async fn forget(&self, files: &[PathBuf], journal: &Journal) -> u64 {
+        let mut forgotten = 0;
+        for batch in files.chunks(FORGET_BATCH_SIZE) {
+            for file in batch {
+                journal.record("forget", file, None).await;
+            }
+            let mut transaction = self.db_pool.begin().await.expect("BEGIN forget failed!");
+            for file in batch {
+                let entry_id = sqlx::query("DELETE FROM entries WHERE fullpath = ? RETURNING entry_id").bind(file.to_string_lossy()).fetch_optional(&mut *transaction).await
+                    .expect("DELETE from entries failed!")
+                    .map(|x| x.get::<i64,_>("entry_id"));
+                if let Some(entry_id) = entry_id {
+                    sqlx::query("DELETE FROM hash_dupe_sets_x_entries WHERE entry_id = ?").bind(entry_id).execute(&mut *transaction).await
+                        .expect("DELETE from hash_dupe_sets_x_entries failed!");
+                    self.phash_index.write().unwrap().remove(entry_id);
+                    forgotten += 1;
+                }
+            }
+            // sets left with a single member are no longer duplicates of anything
+            sqlx::query("DELETE FROM hash_dupe_sets_x_entries WHERE hdset_id IN (SELECT hdset_id FROM hash_dupe_sets_x_entries GROUP BY hdset_id HAVING COUNT(*) < 2)").execute(&mut *transaction).await
+                .expect("DELETE from hash_dupe_sets_x_entries failed!");
+            sqlx::query("DELETE FROM hash_dupe_sets WHERE hdset_id NOT IN (SELECT hdset_id FROM hash_dupe_sets_x_entries)").execute(&mut *transaction).await
+                .expect("DELETE from hash_dupe_sets failed!");
+            transaction.commit().await.expect("COMMIT forget failed!");
+        }
+        forgotten
+    }
+
     pub async fn find_bindupes(&self, incl_ignored: bool, method: KeepWhichFile, reversed: bool, tx: std::sync::mpsc::Sender<BinDupeMessage>) {
         let mut conn = loop {
             if let Ok(acquisition) = self.db_pool.acquire().await {
@@ -129,19 +424,31 @@ impl HashIndexer {
                 .iter().map(|x| x.get::<i64,_>("xxhash")).collect();
 
         for i64_xxhash in collision_rows {
-            println!("Set of xxhash: {}", i64_xxhash);
-            tx.send(BinDupeMessage::NewSet).unwrap();
-            sqlx::query(
+            let rows = sqlx::query(
                 format!(
-                        "SELECT fullpath FROM entries WHERE xxhash = ? ORDER BY {}{};",
+                        "SELECT fullpath, dev, ino FROM entries LEFT JOIN image_metadata USING (entry_id) WHERE xxhash = ? ORDER BY {}{};",
                         method.get_query(),
                         {if reversed {" DESC"} else {""}}
                     )
                     .as_str()
                 )
                 .bind(i64_xxhash)
-                .fetch_all(&mut *conn).await.unwrap().iter()
-                .for_each(|x| { println!("{}\t{}", i64_xxhash, x.get::<String,_>("fullpath")); tx.send(BinDupeMessage::Entry(PathBuf::from(x.get::<String,_>("fullpath")))).unwrap();});
+                .fetch_all(&mut *conn).await.unwrap();
+            // hardlinks to a file already in the set share its data, so disposing of them frees nothing
+            let mut inodes = HashSet::new();
+            let paths: Vec<String> = rows.iter()
+                .filter(|x| match (x.get::<Option<i64>,_>("dev"), x.get::<Option<i64>,_>("ino")) {
+                    (Some(dev), Some(ino)) => inodes.insert((dev, ino)),
+                    _ => true,
+                })
+                .map(|x| x.get::<String,_>("fullpath")).collect();
+            if paths.len() < 2 {
+                continue
+            }
+            eprintln!("Set of xxhash: {}", i64_xxhash);
+            tx.send(BinDupeMessage::NewSet).unwrap();
+            paths.into_iter()
+                .for_each(|x| { eprintln!("{}\t{}", i64_xxhash, x); tx.send(BinDupeMessage::Entry(PathBuf::from(x))).unwrap();});
         }
         // tokio::time::sleep(tokio::time::Duration::from_secs(15)).await;
     }
@@ -197,46 +504,335 @@ impl HashIndexer {
     //     }
     // }
 
-    // pub async fn cluster(&self, hash_dupes: Arc<RwLock<Vec<Vec<String>>>>, bin_dupes: Arc<RwLock<Vec<Vec<String>>>>, hamming_range: usize, cancel_token: CancellationToken) {
-    //     // Hamming range provided as 0 - 100 percentile difference.
-    //     // self.find_dupes(hash_dupes.clone(), bin_dupes, cancel_token).await;
-    //     let hamming_distance = (hamming_range * HASH_SIZE_BYTES * 8) / 100;
-    //     let hamming_distance: u32 = hamming_distance.try_into().unwrap();
-    //     println!("Checking for distance {}", hamming_distance);
+    // indexed images within hamming_distance of file, which needn't be indexed itself, closest first,
+    // along with how each is transformed from file when rotated and mirrored copies are matched
+    // for a video or animation, the indexed videos and animations within hamming_distance of it by video::sequence_distance
+    pub async fn similar_to(&self, file: &Path, hamming_distance: u32) -> Result<Vec<(PathBuf, u32, Transform)>, HashIndexError> {
+        let meta = metadata(file).await.or(Err(HashIndexError::FileNotFound))?;
+        if video::is_video(file) {
+            let (frames, _) = video::sample_frames(file).ok_or(HashIndexError::Format)?;
+            return self.similar_sequences(file, &meta, &frames, None, hamming_distance).await
+        }
+        let file_bytes = tokio::fs::read(file).await.or(Err(HashIndexError::FileNotFound))?;
+        if let Some(animation) = animation::decode(&file_bytes) {
+            return self.similar_sequences(file, &meta, &animation.frames, Some((animation.frame_cnt, animation.duration)), hamming_distance).await
+        }
+        let (img, _) = crate::exif::load_upright(file, &file_bytes, budget::budget().downscale).or(Err(HashIndexError::Format))?;
+        let hasher = HashKind::load(&self.db_pool).await.hasher();
+        let transforms: Vec<Transform> = match match_transforms(&self.db_pool).await {
+            true => [Transform::Identity].iter().chain(Transform::VARIANTS.iter()).copied().collect(),
+            false => vec![Transform::Identity],
+        };
 
-    //     let mut conn = loop {
-    //         if let Ok(acquisition) = self.db_pool.acquire().await {
-    //             break acquisition;
-    //         }
-    //     };
-    //     let mut hash_dupes = hash_dupes.write().await;
-    //     let mut hash_dupes_phashes: Vec<image_hasher::ImageHash::<[u8; HASH_SIZE_BYTES]>> = vec![];
-    //     for dupe_set in (*hash_dupes).iter() {
-    //         let set_phash = sqlx::query("SELECT phash FROM entries WHERE filepath = ?").bind(&dupe_set[0]).fetch_one(conn.acquire().await.unwrap()).await.unwrap();
-    //         hash_dupes_phashes.push(image_hasher::ImageHash::<[u8; HASH_SIZE_BYTES]>::from_base64(set_phash.get("phash")).unwrap());
-    //     }
-    //     assert_eq!(hash_dupes.len(), hash_dupes_phashes.len());
-
-    //     let mut all_entries = sqlx::query("SELECT * FROM entries WHERE NOT ignored;").fetch(conn.acquire().await.unwrap());
-    //     while let Some(Ok(row)) = all_entries.next().await {
-    //         let mut matched = false;
-    //         let phash = image_hasher::ImageHash::<[u8; HASH_SIZE_BYTES]>::from_base64(row.get("phash")).unwrap();
-    //         for (idx,reference) in hash_dupes_phashes.iter().enumerate() {
-    //             if phash.dist(&reference) <= hamming_distance {
-    //                 hash_dupes[idx].push(row.get::<String, &str>("filepath"));
-    //                 matched = true;
-    //                 break;
-    //             }
-    //         }
-    //         if !matched {
-    //             hash_dupes.push(vec!(row.get::<String, &str>("filepath")));
-    //             hash_dupes_phashes.push(phash);
-    //             assert_eq!(hash_dupes.len(), hash_dupes_phashes.len());
-    //         }
-    //     }
-    //     hash_dupes.retain(|x| x.len()>1);
-    //     for set in hash_dupes.iter() {
-    //         println!("Dupe set: {:?}", set);
-    //     }
-    // }
-}
\ No newline at end of file
+        let mut conn = loop {
+            if let Ok(acquisition) = self.db_pool.acquire().await {
+                break acquisition;
+            }
+        };
+        let mut similar: HashMap<PathBuf, (u32, Transform)> = HashMap::new();
+        for transform in transforms {
+            let phash = hasher.hash_image(&transform.apply(&img));
+            // the file itself and its hardlinks aren't similar to it, they are it
+            let rows = sqlx::query("SELECT fullpath, hamming(phash, ?) AS distance FROM entries WHERE ignored = 0 AND distance <= ? AND fullpath != ? AND (dev IS NOT ? OR ino IS NOT ?)")
+                .bind(phash.as_bytes()).bind(hamming_distance).bind(file.to_string_lossy()).bind(meta.dev() as i64).bind(meta.ino() as i64)
+                .fetch_all(conn.acquire().await.unwrap()).await
+                .or(Err(HashIndexError::MalformedDB))?;
+            for row in rows {
+                let distance = row.get::<i64,_>("distance") as u32;
+                // the image as it is wins ties, being tried first
+                let closest = similar.entry(PathBuf::from(row.get::<String,_>("fullpath"))).or_insert((distance, transform));
+                if distance < closest.0 {
+                    *closest = (distance, transform);
+                }
+            }
+        }
+        let mut similar: Vec<(PathBuf, u32, Transform)> = similar.into_iter().map(|(file, (distance, transform))| (file, distance, transform)).collect();
+        similar.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        Ok(similar)
+    }
+
+    // length being the frame count and duration of an animation
+    async fn similar_sequences(&self, file: &Path, meta: &std::fs::Metadata, frames: &[image::DynamicImage], length: Option<(i64, f64)>, hamming_distance: u32) -> Result<Vec<(PathBuf, u32, Transform)>, HashIndexError> {
+        let hasher = HashKind::load(&self.db_pool).await.hasher();
+        let frames: Vec<Box<[u8]>> = frames.iter().map(|x| hasher.hash_image(x).as_bytes().into()).collect();
+
+        let mut conn = loop {
+            if let Ok(acquisition) = self.db_pool.acquire().await {
+                break acquisition;
+            }
+        };
+        let rows = sqlx::query("SELECT e.fullpath, f.phash, e.frame_cnt, e.duration FROM frame_hashes f JOIN entries e USING (entry_id) WHERE f.kind = (SELECT hash_kind FROM metadata) AND e.fullpath != ? AND (e.dev IS NOT ? OR e.ino IS NOT ?) ORDER BY entry_id, frame_idx")
+            .bind(file.to_string_lossy()).bind(meta.dev() as i64).bind(meta.ino() as i64)
+            .fetch_all(conn.acquire().await.unwrap()).await
+            .or(Err(HashIndexError::MalformedDB))?;
+        let mut sequences: HashMap<PathBuf, Vec<Box<[u8]>>> = HashMap::new();
+        for row in rows {
+            if let (Some(length), Some(frame_cnt), Some(duration)) = (length, row.get::<Option<i64>,_>("frame_cnt"), row.get::<Option<f64>,_>("duration")) {
+                if !animation::lengths_agree(length, (frame_cnt, duration)) {
+                    continue
+                }
+            }
+            sequences.entry(PathBuf::from(row.get::<String,_>("fullpath"))).or_default().push(row.get::<Vec<u8>,_>("phash").into());
+        }
+        let mut similar: Vec<(PathBuf, u32, Transform)> = sequences.into_iter()
+            .map(|(other, sequence)| (other, video::sequence_distance(&frames, &sequence), Transform::Identity))
+            .filter(|x| x.1 <= hamming_distance)
+            .collect();
+        similar.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        Ok(similar)
+    }
+
+    // sets start with the entry kept by keep when given, and are in the order entries were indexed otherwise
+    pub async fn cluster(&self, hamming_distance: u32, keep: Option<(KeepWhichFile, bool)>, tx: std::sync::mpsc::Sender<HashDupeMessage>) {
+        eprintln!("Checking for distance {}", hamming_distance);
+
+        let mut conn = loop {
+            if let Ok(acquisition) = self.db_pool.acquire().await {
+                break acquisition;
+            }
+        };
+        let conn = conn.acquire().await.unwrap();
+
+        let order = match keep {
+            Some((method, reversed)) => format!("{}{}, entry_id", method.get_query(), if reversed { " DESC" } else { "" }),
+            None => "entry_id".to_string(),
+        };
+        let rows = sqlx::query(&format!("SELECT entry_id, fullpath, phash, dev, ino FROM entries LEFT JOIN image_metadata USING (entry_id) WHERE ignored = 0 AND phash IS NOT NULL ORDER BY {};", order))
+            .fetch_all(&mut *conn).await
+            .expect("SELECT phashes from database failed!");
+        // hardlinked paths are one file, only the first of them is clustered
+        let mut inodes = HashSet::new();
+        let entries: Vec<(i64, PathBuf, image_hasher::ImageHash)> = rows
+            .iter().filter(|row| match (row.get::<Option<i64>,_>("dev"), row.get::<Option<i64>,_>("ino")) {
+                (Some(dev), Some(ino)) => inodes.insert((dev, ino)),
+                _ => true,
+            }).filter_map(|row| {
+                match image_hasher::ImageHash::<Box<[u8]>>::from_bytes(row.get("phash")) {
+                    Ok(phash) => Some((row.get("entry_id"), PathBuf::from(row.get::<String,_>("fullpath")), phash)),
+                    Err(_) => { eprintln!("Malformed phash for {}", row.get::<String,_>("fullpath")); None },
+                }
+            }).collect();
+
+        // hashes of the rotated and mirrored variants of each entry, searched with besides the entry itself
+        let mut variants: HashMap<i64, TransformHashes> = HashMap::new();
+        if match_transforms(&self.db_pool).await {
+            let rows = sqlx::query("SELECT entry_id, transform, phash FROM transform_hashes WHERE kind = (SELECT hash_kind FROM metadata)")
+                .fetch_all(&mut *conn).await
+                .expect("SELECT from transform_hashes failed!");
+            for row in rows {
+                if let Some(transform) = Transform::parse(row.get("transform")) {
+                    variants.entry(row.get("entry_id")).or_default().push((transform, row.get::<Vec<u8>,_>("phash").into()));
+                }
+            }
+        }
+
+        // frame hashes of videos and animations, which are matched with each other by those instead of with stills,
+        // along with the frame count and duration of animations
+        let mut sequences: HashMap<i64, Vec<Box<[u8]>>> = HashMap::new();
+        let mut lengths: HashMap<i64, (i64, f64)> = HashMap::new();
+        let rows = sqlx::query("SELECT entry_id, f.phash, e.frame_cnt, e.duration FROM frame_hashes f JOIN entries e USING (entry_id) WHERE f.kind = (SELECT hash_kind FROM metadata) ORDER BY entry_id, frame_idx")
+            .fetch_all(&mut *conn).await
+            .expect("SELECT from frame_hashes failed!");
+        for row in rows {
+            sequences.entry(row.get("entry_id")).or_default().push(row.get::<Vec<u8>,_>("phash").into());
+            if let (Some(frame_cnt), Some(duration)) = (row.get::<Option<i64>,_>("frame_cnt"), row.get::<Option<f64>,_>("duration")) {
+                lengths.insert(row.get("entry_id"), (frame_cnt, duration));
+            }
+        }
+        // every frame of every video, keyed by its position in frame_owners, narrowing down which videos to compare whole
+        let mut frame_index = BkTree::new();
+        let mut frame_owners: Vec<usize> = vec![];
+        for (idx, (entry_id, _, _)) in entries.iter().enumerate() {
+            for frame in sequences.get(entry_id).into_iter().flatten() {
+                frame_index.insert(frame_owners.len() as i64, frame.clone());
+                frame_owners.push(idx);
+            }
+        }
+
+        // each set is represented by its first unclaimed entry and takes every unclaimed entry within range of it,
+        // noting how the entry is transformed from the first
+        let mut hash_dupes: Vec<Vec<(usize, Transform)>> = vec![];
+        {
+            let mut phash_index = self.phash_index.write().unwrap();
+            for (entry_id, _, phash) in entries.iter() {
+                phash_index.insert(*entry_id, phash.as_bytes().into());
+            }
+            let entry_idxs: HashMap<i64, usize> = entries.iter().enumerate().map(|(idx, x)| (x.0, idx)).collect();
+            let mut claimed = vec![false; entries.len()];
+            for (idx, (entry_id, _, phash)) in entries.iter().enumerate() {
+                if claimed[idx] { continue }
+                claimed[idx] = true;
+                let mut set = vec![(idx, Transform::Identity)];
+                if let Some(frames) = sequences.get(entry_id) {
+                    // videos within range share at least one frame within range
+                    let candidates: HashSet<usize> = frames.iter().flat_map(|x| frame_index.find_within(x, hamming_distance)).map(|(frame, _)| frame_owners[frame as usize]).collect();
+                    for other_idx in candidates {
+                        let other_entry_id = entries[other_idx].0;
+                        let lengths_agree = match (lengths.get(entry_id), lengths.get(&other_entry_id)) {
+                            (Some(length), Some(other_length)) => animation::lengths_agree(*length, *other_length),
+                            _ => true,
+                        };
+                        if !claimed[other_idx] && lengths_agree && video::sequence_distance(frames, &sequences[&other_entry_id]) <= hamming_distance {
+                            claimed[other_idx] = true;
+                            set.push((other_idx, Transform::Identity));
+                        }
+                    }
+                } else {
+                    let queries = std::iter::once((Transform::Identity, phash.as_bytes()))
+                        .chain(variants.get(entry_id).into_iter().flatten().map(|(transform, variant)| (*transform, &variant[..])));
+                    for (transform, query) in queries {
+                        for (other_entry_id, _) in phash_index.find_within(query, hamming_distance) {
+                            if let Some(&other_idx) = entry_idxs.get(&other_entry_id) {
+                                if !claimed[other_idx] && !sequences.contains_key(&other_entry_id) {
+                                    claimed[other_idx] = true;
+                                    set.push((other_idx, transform));
+                                }
+                            }
+                        }
+                    }
+                }
+                if set.len() > 1 {
+                    set[1..].sort_by_key(|x| x.0);
+                    hash_dupes.push(set);
+                }
+            }
+        }
+
+        let mut transaction = self.db_pool.begin().await.expect("Failed to begin hash_dupe_sets transaction");
+        sqlx::query("DELETE FROM hash_dupe_sets_x_entries; DELETE FROM hash_dupe_sets;").execute(&mut *transaction).await.expect("Clearing hash_dupe_sets failed!");
+        for set in &hash_dupes {
+            let hdset_id = sqlx::query("INSERT INTO hash_dupe_sets (hamming_distance) VALUES (?)").bind(hamming_distance).execute(&mut *transaction).await.expect("INSERT into hash_dupe_sets failed!").last_insert_rowid();
+            for (idx, transform) in set {
+                sqlx::query("INSERT INTO hash_dupe_sets_x_entries (hdset_id, entry_id, transform) VALUES (?, ?, ?)").bind(hdset_id).bind(entries[*idx].0).bind(transform.name()).execute(&mut *transaction).await.expect("INSERT into hash_dupe_sets_x_entries failed!");
+            }
+        }
+        transaction.commit().await.expect("Committing hash_dupe_sets failed!");
+
+        for set in hash_dupes {
+            if tx.send(HashDupeMessage::NewSet).is_err() { return }
+            for (idx, transform) in set {
+                let _ = tx.send(HashDupeMessage::Entry(entries[idx].1.clone(), transform));
+            }
+        }
+    }
+}
+
+// the contents of a file along with their xxhash and how much was read, of videos only the start, as they can be far larger than
+// the memory budget and ffmpeg reads them itself
+async fn read_contents(fullpath: &str) -> std::io::Result<(Vec<u8>, i64, u64)> {
+    if !video::is_video(Path::new(fullpath)) {
+        let file_bytes = tokio::fs::read(fullpath).await?;
+        let xxhash = i64::from_be_bytes(xxh3_64(&file_bytes).to_be_bytes());
+        let read = file_bytes.len() as u64;
+        return Ok((file_bytes, xxhash, read))
+    }
+    read_head(fullpath).await
+}
+
+// the xxhash of a file read through in pieces, of which only the start is kept
+async fn read_head(fullpath: &str) -> std::io::Result<(Vec<u8>, i64, u64)> {
+    let mut file = tokio::fs::File::open(fullpath).await?;
+    let mut hasher = Xxh3::new();
+    let mut head = Vec::with_capacity(VIDEO_HEAD_SIZE);
+    let mut buf = vec![0; VIDEO_HEAD_SIZE];
+    let mut read = 0;
+    loop {
+        let len = file.read(&mut buf).await?;
+        if len == 0 {
+            break
+        }
+        hasher.update(&buf[..len]);
+        read += len as u64;
+        let kept = len.min(VIDEO_HEAD_SIZE - head.len());
+        head.extend_from_slice(&buf[..kept]);
+    }
+    Ok((head, i64::from_be_bytes(hasher.digest().to_be_bytes()), read))
+}
+
+// videos and animations are hashed frame by frame, their middle frame standing in for them as the entry's phash
+fn hash_file(path: &Path, file_bytes: &[u8], hash_kind: HashKind, transforms: bool, downscale: bool) -> image::ImageResult<Hashed> {
+    let (img, orientation, frames, frame_cnt, duration) = if video::is_video(path) {
+        let (frames, duration) = video::sample_frames(path).ok_or_else(decode::unsupported)?;
+        (frames[frames.len() / 2].clone(), 1, frames, None, Some(duration))
+    } else if let Some(animation) = animation::decode(file_bytes) {
+        (animation.frames[animation.frames.len() / 2].clone(), 1, animation.frames, Some(animation.frame_cnt), Some(animation.duration))
+    } else {
+        let (img, orientation) = crate::exif::load_upright(path, file_bytes, downscale)?;
+        (img, orientation, vec![], Some(1), None)
+    };
+    let hasher = hash_kind.hasher();
+    let variants = match transforms && frames.is_empty() {
+        true => Transform::VARIANTS.iter().map(|x| (*x, hasher.hash_image(&x.apply(&img)))).collect(),
+        false => vec![],
+    };
+    Ok(Hashed { phash: hasher.hash_image(&img), orientation, variants, frame_hashes: frames.iter().map(|x| hasher.hash_image(x)).collect(), frame_cnt, duration })
+}
+
+// an unmounted mount point is left as an empty directory, which would make everything indexed beneath it look deleted
+fn is_reachable(dir: &Path) -> bool {
+    match dir.read_dir() {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn copies_dont_take_over_entries_of_offline_volumes() {
+        let dir = std::env::temp_dir().join(format!("refsto-index-test-{}", std::process::id()));
+        let (usb, local) = (dir.join("usb"), dir.join("local"));
+        std::fs::create_dir_all(&usb).unwrap();
+        std::fs::create_dir_all(&local).unwrap();
+        let on_usb = usb.join("gradient.png");
+        image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 64])).save(&on_usb).unwrap();
+        let db_pool = crate::test_database(&dir).await;
+        sqlx::query("INSERT INTO watched_dirs (fullpath) VALUES (?)").bind(usb.to_string_lossy()).execute(&db_pool).await.unwrap();
+        let hi = HashIndexer::new(db_pool.clone());
+        assert!(hi.update(on_usb.to_string_lossy().into()).await.is_ok());
+
+        // the volume goes away with the file still on it, while a copy is scanned
+        let copy = local.join("gradient.png");
+        std::fs::copy(&on_usb, &copy).unwrap();
+        std::fs::rename(&usb, dir.join("unmounted")).unwrap();
+        assert!(hi.update(copy.to_string_lossy().into()).await.is_ok());
+        let paths: Vec<String> = sqlx::query("SELECT fullpath FROM entries ORDER BY fullpath").fetch_all(&db_pool).await.unwrap()
+            .iter().map(|x| x.get("fullpath")).collect();
+        assert_eq!(paths, vec![copy.to_string_lossy().to_string(), on_usb.to_string_lossy().to_string()]);
+
+        // a file gone from a volume still there was moved
+        let moved = local.join("moved.png");
+        std::fs::rename(&copy, &moved).unwrap();
+        assert!(hi.update(moved.to_string_lossy().into()).await.is_ok());
+        let paths: Vec<String> = sqlx::query("SELECT fullpath FROM entries ORDER BY fullpath").fetch_all(&db_pool).await.unwrap()
+            .iter().map(|x| x.get("fullpath")).collect();
+        assert_eq!(paths, vec![moved.to_string_lossy().to_string(), on_usb.to_string_lossy().to_string()]);
+        db_pool.close().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn too_large_files_are_ignored_until_the_budget_changes() {
+        let dir = std::env::temp_dir().join(format!("refsto-budget-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // a header saying there are far more pixels than allowed, without them
+        let file = dir.join("huge.ppm");
+        std::fs::write(&file, b"P6\n30000 30000\n255\n").unwrap();
+        let db_pool = crate::test_database(&dir).await;
+        let hi = HashIndexer::new(db_pool.clone());
+        assert!(matches!(hi.update(file.to_string_lossy().into()).await, Err(HashIndexError::TooLarge)));
+        let row = sqlx::query("SELECT xxhash, ignored, decoders FROM entries WHERE fullpath = ?").bind(file.to_string_lossy()).fetch_one(&db_pool).await.unwrap();
+        assert_eq!(row.get::<i64,_>("xxhash"), i64::from_be_bytes(xxh3_64(&std::fs::read(&file).unwrap()).to_be_bytes()));
+        assert!(row.get::<bool,_>("ignored"));
+        assert_eq!(row.get::<String,_>("decoders"), budget::budget().signature());
+
+        // passed over by a rescan, and tried again once the budget it was too large for is gone
+        assert!(hi.update(file.to_string_lossy().into()).await.is_ok());
+        sqlx::query("UPDATE entries SET decoders = 'budget 1 MiB 1 MP'").execute(&db_pool).await.unwrap();
+        assert!(matches!(hi.update(file.to_string_lossy().into()).await, Err(HashIndexError::TooLarge)));
+        db_pool.close().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
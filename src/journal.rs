@@ -0,0 +1,206 @@
+use std::{path::{Path, PathBuf}, fs, io, sync::Arc, time::SystemTime};
+use sqlx::{Row, Acquire};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::dispose::move_file;
+
+// append-only record of destructive operations, each made up of one record per file touched
+// the operation itself is only written along with its first record
+#[derive(Clone)]
+pub struct Journal {
+    db_pool: sqlx::SqlitePool,
+    kind: &'static str,
+    op_id: Arc<tokio::sync::OnceCell<i64>>,
+}
+
+pub struct JournalRecord {
+    pub action: String,
+    pub fullpath: PathBuf,
+    pub dest: Option<PathBuf>,
+    pub undone: bool,
+}
+
+pub struct Operation {
+    pub op_id: i64,
+    pub kind: String,
+    pub time: i64,
+    pub undone: bool,
+    pub records: Vec<JournalRecord>,
+}
+
+impl Journal {
+    pub fn begin(db_pool: &sqlx::SqlitePool, kind: &'static str) -> Self {
+        Journal { db_pool: db_pool.clone(), kind, op_id: Arc::new(tokio::sync::OnceCell::new()) }
+    }
+
+    async fn op_id(&self, conn: &mut sqlx::SqliteConnection) -> i64 {
+        *self.op_id.get_or_init(|| async {
+            let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+            sqlx::query("INSERT INTO operations (kind, time) VALUES (?, ?)").bind(self.kind).bind(time).execute(conn).await
+                .expect("INSERT into operations failed!")
+                .last_insert_rowid()
+        }).await
+    }
+
+    // records what was done to fullpath along with its hashes as last indexed,
+    // dest being wherever its data can still be found: the trash or quarantine copy, or the kept duplicate
+    pub async fn record(&self, action: &str, fullpath: &Path, dest: Option<&Path>) {
+        let mut conn = loop {
+            if let Ok(acquisition) = self.db_pool.acquire().await {
+                break acquisition;
+            }
+        };
+        let conn = conn.acquire().await.unwrap();
+        let op_id = self.op_id(&mut *conn).await;
+        let fullpath = fullpath.to_string_lossy();
+        if let Err(e) = sqlx::query("INSERT INTO journal (op_id, action, fullpath, dest, xxhash, phash, filesize, filename, dircnt, mtime, ctime, dev, ino) SELECT ?, ?, ?, ?, xxhash, phash, filesize, filename, dircnt, mtime, ctime, dev, ino FROM (SELECT 1) LEFT JOIN entries ON entries.fullpath = ?")
+            .bind(op_id).bind(action).bind(&fullpath).bind(dest.map(|x| x.to_string_lossy())).bind(&fullpath)
+            .execute(&mut *conn).await {
+            eprintln!("Could not journal {} of {}: {:?}", action, fullpath, e);
+        }
+    }
+}
+
+// latest operations first, with their records
+pub async fn list_operations(db_pool: &sqlx::SqlitePool, limit: i64) -> Vec<Operation> {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    let conn = conn.acquire().await.unwrap();
+    let mut operations: Vec<Operation> = sqlx::query("SELECT op_id, kind, time, undone FROM operations ORDER BY op_id DESC LIMIT ?").bind(limit).fetch_all(&mut *conn).await
+        .expect("SELECT from operations failed!")
+        .iter().map(|x| Operation { op_id: x.get("op_id"), kind: x.get("kind"), time: x.get("time"), undone: x.get("undone"), records: vec![] }).collect();
+    for op in operations.iter_mut() {
+        op.records = sqlx::query("SELECT action, fullpath, dest, undone FROM journal WHERE op_id = ? ORDER BY journal_id").bind(op.op_id).fetch_all(&mut *conn).await
+            .expect("SELECT from journal failed!")
+            .iter().map(|x| JournalRecord {
+                action: x.get("action"),
+                fullpath: PathBuf::from(x.get::<String,_>("fullpath")),
+                dest: x.get::<Option<String>,_>("dest").map(PathBuf::from),
+                undone: x.get("undone"),
+            }).collect();
+    }
+    operations
+}
+
+// reverses the latest operation not yet undone, newest record first
+// records that fail are left in place so undo can be retried, the operation counts as undone once none are left
+pub async fn undo_last(db_pool: &sqlx::SqlitePool) -> Option<(String, Vec<(PathBuf, io::Result<()>)>)> {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    let conn = conn.acquire().await.unwrap();
+    let op = sqlx::query("SELECT op_id, kind FROM operations WHERE undone = 0 ORDER BY op_id DESC LIMIT 1").fetch_optional(&mut *conn).await
+        .expect("SELECT from operations failed!")?;
+    let (op_id, kind): (i64, String) = (op.get("op_id"), op.get("kind"));
+    let records = sqlx::query("SELECT journal_id, action, fullpath, dest, xxhash, phash, filesize, filename, dircnt, mtime, ctime, dev, ino FROM journal WHERE op_id = ? AND undone = 0 ORDER BY journal_id DESC").bind(op_id).fetch_all(&mut *conn).await
+        .expect("SELECT from journal failed!");
+    let mut results = vec![];
+    for row in records {
+        let fullpath = PathBuf::from(row.get::<String,_>("fullpath"));
+        let dest = row.get::<Option<String>,_>("dest").map(PathBuf::from);
+        let res = match (row.get::<String,_>("action").as_str(), dest) {
+            ("trash", Some(dest)) => undo_move(&dest, &fullpath).map(|_| {
+                let mut info_name = dest.file_name().unwrap().to_os_string();
+                info_name.push(".trashinfo");
+                let _ = fs::remove_file(dest.parent().unwrap().with_file_name("info").join(info_name));
+            }),
+            ("quarantine", Some(dest)) => undo_move(&dest, &fullpath),
+            ("delete", Some(kept)) => undo_delete(&kept, &fullpath, row.get("xxhash"), row.get("filesize")),
+            ("link", Some(_)) => undo_link(&fullpath),
+            // variants, frames and metadata went with the entry, the next update of it restoring them as for entries from before they were kept
+            // records from before times were journaled come back without them, rehashed on the next update
+            ("forget", _) => sqlx::query("INSERT OR IGNORE INTO entries (fullpath, phash, xxhash, filesize, filename, dircnt, mtime, ctime, dev, ino, ignored) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+                .bind(fullpath.to_string_lossy()).bind(row.get::<Option<Vec<u8>>,_>("phash")).bind(row.get::<Option<i64>,_>("xxhash")).bind(row.get::<Option<i64>,_>("filesize"))
+                .bind(row.get::<Option<String>,_>("filename")).bind(row.get::<Option<i64>,_>("dircnt"))
+                .bind(row.get::<Option<i64>,_>("mtime")).bind(row.get::<Option<i64>,_>("ctime")).bind(row.get::<Option<i64>,_>("dev")).bind(row.get::<Option<i64>,_>("ino"))
+                .bind(row.get::<Option<Vec<u8>>,_>("phash").is_none())
+                .execute(&mut *conn).await
+                .map(|_| ())
+                .map_err(io::Error::other),
+            (action, _) => Err(io::Error::new(io::ErrorKind::Unsupported, format!("cannot undo {}", action))),
+        };
+        if res.is_ok() {
+            sqlx::query("UPDATE journal SET undone = 1 WHERE journal_id = ?").bind(row.get::<i64,_>("journal_id")).execute(&mut *conn).await
+                .expect("UPDATE journal failed!");
+        }
+        results.push((fullpath, res));
+    }
+    if results.iter().all(|(_, res)| res.is_ok()) {
+        sqlx::query("UPDATE operations SET undone = 1 WHERE op_id = ?").bind(op_id).execute(&mut *conn).await
+            .expect("UPDATE operations failed!");
+    }
+    Some((kind, results))
+}
+
+fn undo_move(dest: &Path, fullpath: &Path) -> io::Result<()> {
+    if fullpath.symlink_metadata().is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a file exists at the original path"))
+    }
+    fs::create_dir_all(fullpath.parent().unwrap())?;
+    move_file(dest, fullpath)
+}
+
+// deleted exact duplicates come back as copies of the kept file, as long as it is unchanged
+fn undo_delete(kept: &Path, fullpath: &Path, xxhash: Option<i64>, filesize: Option<i64>) -> io::Result<()> {
+    if fullpath.symlink_metadata().is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a file exists at the original path"))
+    }
+    let kept_bytes = fs::read(kept)?;
+    if Some(kept_bytes.len() as i64) != filesize || Some(i64::from_be_bytes(xxh3_64(&kept_bytes).to_be_bytes())) != xxhash {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} changed since, cannot recreate from it", kept.to_string_lossy())))
+    }
+    fs::create_dir_all(fullpath.parent().unwrap())?;
+    fs::write(fullpath, kept_bytes)
+}
+
+// gives a linked file its own copy of the data again
+fn undo_link(fullpath: &Path) -> io::Result<()> {
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(fullpath.file_name().unwrap());
+    tmp_name.push(".refsto-unlink");
+    let tmp = fullpath.with_file_name(tmp_name);
+    if let Err(e) = fs::copy(fullpath, &tmp).and_then(|_| fs::rename(&tmp, fullpath)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e)
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::index::HashIndexer;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn undone_forget_survives_rescan() {
+        let dir = std::env::temp_dir().join(format!("refsto-journal-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("gradient.png");
+        image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])).save(&file).unwrap();
+        let db_pool = crate::test_database(&dir).await;
+        let hi = HashIndexer::new(db_pool.clone());
+        assert!(hi.update(file.to_string_lossy().into()).await.is_ok());
+
+        assert_eq!(hi.forget(std::slice::from_ref(&file), &Journal::begin(&db_pool, "clean_missing")).await, 1);
+        let (_, results) = undo_last(&db_pool).await.unwrap();
+        assert!(results.iter().all(|(_, res)| res.is_ok()));
+        let restored = sqlx::query("SELECT mtime, ctime, dev, ino FROM entries WHERE fullpath = ?").bind(file.to_string_lossy()).fetch_one(&db_pool).await.unwrap();
+        let meta = file.metadata().unwrap();
+        assert_eq!(restored.get::<Option<i64>,_>("mtime"), Some(meta.mtime()));
+        assert_eq!(restored.get::<Option<i64>,_>("ino"), Some(meta.ino() as i64));
+
+        // the rescan finds it unchanged, only bringing back what went with the entry
+        assert!(hi.update(file.to_string_lossy().into()).await.is_ok());
+        let described: i64 = sqlx::query("SELECT COUNT(*) FROM image_metadata JOIN entries USING (entry_id) WHERE fullpath = ?").bind(file.to_string_lossy()).fetch_one(&db_pool).await.unwrap().get(0);
+        assert_eq!(described, 1);
+        db_pool.close().await;
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
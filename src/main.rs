@@ -1,6 +1,26 @@
+mod animation;
+mod bktree;
+mod budget;
+mod cli;
+mod decode;
+mod dispose;
+mod exif;
 mod gui;
+mod hashkind;
 mod index;
+mod journal;
+mod metadata;
+mod migrations;
+mod pipeline;
+mod rules;
+mod settings;
+mod sqlfns;
+mod video;
+mod volume;
+mod walk;
+mod watcher;
 use std::sync::Arc;
+use clap::Parser;
 use dirs::config_local_dir;
 use sqlx::{sqlite::SqlitePoolOptions, Row};
 use tokio::runtime;
@@ -8,36 +28,44 @@ use tokio::runtime;
 use gui::IndexingGui;
 
 const SQLITE_CON_CNT: u32 = 2048;
-const HASH_SIZE_BYTES: usize = 8;
-const TABLE_VERSION: i64 = 2;
+const TABLE_VERSION: i64 = 16;
 
-async fn setup_database(pool: sqlx::SqlitePool) {
+async fn setup_database(pool: sqlx::SqlitePool) -> anyhow::Result<()> {
     if let Ok(table_version) = sqlx::query("SELECT table_version FROM metadata").fetch_one(&pool).await {
-        if table_version.get::<i64,_>("table_version") == TABLE_VERSION {
-            println!("TABLE VERSION {}", table_version.get::<i64,_>("table_version"));
-            return
-        } else {
-            eprintln!("Outdated but data-bearing database found!
-            To continue with this version of refsto, run `rm {}/refsto.dat`
-            THIS WILL DELETE THE DATABASE AND ITS HASHES, BUT WILL LEAVE YOUR IMAGES INTACT.
-            ALL DATA CAN BE REGENERATED BY RE-ADDING YOUR DIRECTORIES AND RUNNING \"LOAD\"
-            
-            Database version: {}\nRefsto version & database version: {} | {}", config_local_dir().unwrap().to_string_lossy(), table_version.get::<i64,_>("table_version"), option_env!("CARGO_PKG_VERSION").unwrap(), TABLE_VERSION);
-            todo!("Present update options for incompatible database version");
-        }
-    } 
-    let _ = sqlx::query("CREATE TABLE IF NOT EXISTS metadata (refsto_version STRING, table_version INTEGER);
-    CREATE TABLE IF NOT EXISTS watched_dirs ( rowid INTEGER PRIMARY KEY ASC, fullpath TEXT UNIQUE );
-    CREATE TABLE IF NOT EXISTS entries ( entry_id INTEGER PRIMARY KEY ASC, fullpath TEXT UNIQUE, phash BLOB, xxhash BLOB, filesize INTEGER, mtime INTEGER, ctime INTEGER, filename TEXT, dircnt INTEGER, ignored BOOLEAN DEFAULT 0 );
-    CREATE TABLE IF NOT EXISTS hash_dupe_sets ( hdset_id INTEGER PRIMARY KEY ASC, hamming_distance INTEGER);
-    CREATE TABLE IF NOT EXISTS hash_dupe_sets_x_entries ( hdset_id INTEGER, entry_id INTEGER );
-    INSERT INTO metadata (refsto_version, table_version) VALUES (?, ?)").bind(option_env!("CARGO_PKG_VERSION").unwrap()).bind(TABLE_VERSION).execute(&pool).await;
+        let table_version = table_version.get::<i64,_>("table_version");
+        eprintln!("TABLE VERSION {}", table_version);
+        return migrations::migrate(&pool, table_version, true).await
+    }
+    sqlx::query(migrations::BASE_SCHEMA).execute(&pool).await?;
+    sqlx::query("INSERT INTO metadata (refsto_version, table_version) VALUES (?, ?)").bind(option_env!("CARGO_PKG_VERSION").unwrap()).bind(migrations::BASE_VERSION).execute(&pool).await?;
+    migrations::migrate(&pool, migrations::BASE_VERSION, false).await
+}
+
+// a database of its own in dir, set up as it would be for the GUI or CLI
+#[cfg(test)]
+async fn test_database(dir: &std::path::Path) -> sqlx::SqlitePool {
+    let pool = SqlitePoolOptions::new().after_connect(|conn, _| Box::pin(sqlfns::register(conn)))
+        .connect(&format!("sqlite:{}/refsto.dat?mode=rwc", dir.to_string_lossy())).await.unwrap();
+    setup_database(pool.clone()).await.unwrap();
+    pool
 }
 
 fn main() {
+    let cli = cli::Cli::parse();
     // std::env::set_var("WINIT_UNIX_BACKEND", "x11"); // currently necessary since winit does not support DnD in Wayland
     let rt = Arc::new(runtime::Builder::new_multi_thread().enable_time().build().unwrap());
-    let db_pool = rt.block_on(SqlitePoolOptions::new().max_connections(SQLITE_CON_CNT).connect(format!("sqlite:{}/refsto.dat?mode=rwc", config_local_dir().unwrap().to_string_lossy()).as_str())).unwrap();
-    rt.block_on(setup_database(db_pool.clone()));
+    let db_pool = rt.block_on(SqlitePoolOptions::new().max_connections(SQLITE_CON_CNT).after_connect(|conn, _| Box::pin(sqlfns::register(conn))).connect(format!("sqlite:{}/refsto.dat?mode=rwc", config_local_dir().unwrap().to_string_lossy()).as_str())).unwrap();
+    if let Err(e) = rt.block_on(setup_database(db_pool.clone())) {
+        eprintln!("{:#}", e);
+        if cli.command.is_none() {
+            let _ = native_dialog::MessageDialog::new().set_type(native_dialog::MessageType::Error).set_title("refsto: database error").set_text(&format!("{:#}", e)).show_alert();
+        }
+        std::process::exit(1);
+    }
+    budget::configure(rt.block_on(budget::Budget::load(&db_pool)));
+    if let Some(command) = cli.command {
+        let exit_code = rt.block_on(cli::run(command, cli.json, db_pool));
+        std::process::exit(exit_code);
+    }
     let _ = eframe::run_native("Computing directory hashes...", eframe::NativeOptions::default(), Box::new(|cc| Box::new(IndexingGui::new(cc, rt, db_pool))));
 }
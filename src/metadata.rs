@@ -0,0 +1,161 @@
+use std::{collections::HashSet, io::Cursor, path::PathBuf};
+use sqlx::{Row, Acquire};
+
+use crate::exif::Exif;
+
+// what EXIF and XMP say about an image, kept in image_metadata for keep rules and filtering
+#[derive(Default)]
+pub struct ImageMetadata {
+    pub captured: Option<String>, // YYYY-MM-DD HH:MM:SS
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    pub gps: Option<(f64, f64)>,
+    pub width: Option<u32>, // as seen, after turning it upright
+    pub height: Option<u32>,
+    pub rating: Option<i64>,
+    pub keywords: Vec<String>,
+}
+
+impl ImageMetadata {
+    // reads only headers, the image is never decoded
+    pub fn extract(file_bytes: &[u8]) -> Self {
+        let mut metadata = ImageMetadata::default();
+        let exif = Exif::parse(file_bytes);
+        if let Some(exif) = &exif {
+            metadata.captured = exif.captured();
+            metadata.make = exif.make();
+            metadata.model = exif.model();
+            metadata.lens = exif.lens();
+            metadata.gps = exif.gps();
+            metadata.rating = exif.rating().map(i64::from);
+        }
+        // XMP is what editors write ratings and keywords to, and wins over EXIF where both have a field
+        if let Some(xmp) = find_xmp(file_bytes) {
+            metadata.rating = xmp_value(xmp, "xmp:Rating").and_then(|x| x.parse::<f64>().ok()).map(|x| x as i64).or(metadata.rating);
+            metadata.captured = metadata.captured.or_else(|| {
+                ["exif:DateTimeOriginal", "photoshop:DateCreated", "xmp:CreateDate"].iter().find_map(|name| xmp_value(xmp, name)).and_then(|x| normalize_xmp_date(&x))
+            });
+            metadata.keywords = xmp_list(xmp, "dc:subject");
+        }
+        if let Ok((width, height)) = image::io::Reader::new(Cursor::new(file_bytes)).with_guessed_format().map_err(image::ImageError::from).and_then(|x| x.into_dimensions()) {
+            let sideways = matches!(exif.and_then(|x| x.orientation()), Some(5..=8));
+            (metadata.width, metadata.height) = if sideways { (Some(height), Some(width)) } else { (Some(width), Some(height)) };
+        }
+        metadata
+    }
+
+    // descriptive fields present, what the most-metadata keep rule compares by
+    pub fn field_cnt(&self) -> i64 {
+        [self.captured.is_some(), self.make.is_some(), self.model.is_some(), self.lens.is_some(), self.gps.is_some(), self.rating.is_some(), !self.keywords.is_empty()]
+            .iter().filter(|x| **x).count() as i64
+    }
+
+    pub async fn store(&self, conn: &mut sqlx::SqliteConnection, entry_id: i64) -> Result<sqlx::sqlite::SqliteQueryResult, sqlx::Error> {
+        sqlx::query("INSERT OR REPLACE INTO image_metadata (entry_id, captured, make, model, lens, gps_lat, gps_lon, width, height, rating, keywords, field_cnt) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(entry_id).bind(&self.captured).bind(&self.make).bind(&self.model).bind(&self.lens)
+            .bind(self.gps.map(|x| x.0)).bind(self.gps.map(|x| x.1)).bind(self.width).bind(self.height).bind(self.rating)
+            .bind(serde_json::to_string(&self.keywords).unwrap()).bind(self.field_cnt())
+            .execute(conn).await
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MetadataField {
+    Camera,
+    Lens,
+    Keyword,
+    Captured,
+    MinRating,
+    HasGps,
+}
+
+impl MetadataField {
+    pub const ALL: [MetadataField; 6] = [MetadataField::Camera, MetadataField::Lens, MetadataField::Keyword, MetadataField::Captured, MetadataField::MinRating, MetadataField::HasGps];
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            MetadataField::Camera => "Camera",
+            MetadataField::Lens => "Lens",
+            MetadataField::Keyword => "Keyword",
+            MetadataField::Captured => "Captured in",
+            MetadataField::MinRating => "Rated at least",
+            MetadataField::HasGps => "Has location",
+        }
+    }
+}
+
+// paths of images whose metadata matches, text fields matching case-insensitively anywhere and the capture date from its start, e.g. 2021-06
+pub async fn matching(db_pool: &sqlx::SqlitePool, field: MetadataField, value: &str) -> HashSet<PathBuf> {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    let condition = match field {
+        MetadataField::Camera => "IFNULL(make, '') || ' ' || IFNULL(model, '') LIKE '%' || ? || '%'",
+        MetadataField::Lens => "lens LIKE '%' || ? || '%'",
+        MetadataField::Keyword => "EXISTS (SELECT 1 FROM json_each(keywords) WHERE value LIKE ?)",
+        MetadataField::Captured => "captured LIKE ? || '%'",
+        MetadataField::MinRating => "rating >= CAST(? AS INTEGER)",
+        MetadataField::HasGps => "gps_lat IS NOT NULL",
+    };
+    let query = format!("SELECT fullpath FROM entries JOIN image_metadata USING (entry_id) WHERE {}", condition);
+    let query = match field {
+        MetadataField::HasGps => sqlx::query(&query),
+        _ => sqlx::query(&query).bind(value.trim()),
+    };
+    query.fetch_all(conn.acquire().await.unwrap()).await
+        .expect("SELECT from image_metadata failed!")
+        .iter().map(|x| PathBuf::from(x.get::<String,_>("fullpath"))).collect()
+}
+
+// the XMP packet, wherever the container keeps it, as long as it isn't compressed
+fn find_xmp(file_bytes: &[u8]) -> Option<&str> {
+    let start = find_bytes(file_bytes, b"<x:xmpmeta")?;
+    let len = find_bytes(&file_bytes[start..], b"</x:xmpmeta>")? + b"</x:xmpmeta>".len();
+    std::str::from_utf8(&file_bytes[start..start + len]).ok()
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|x| x == needle)
+}
+
+// a simple property, written either as an attribute or as an element
+fn xmp_value(xmp: &str, name: &str) -> Option<String> {
+    let attribute = format!("{}=\"", name);
+    if let Some(start) = xmp.find(&attribute).map(|x| x + attribute.len()) {
+        let len = xmp[start..].find('"')?;
+        return Some(unescape_xml(&xmp[start..start + len]))
+    }
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let start = xmp.find(&open)? + open.len();
+    let len = xmp[start..].find(&close)?;
+    Some(unescape_xml(xmp[start..start + len].trim()))
+}
+
+// the items of a bag or sequence property, like dc:subject
+fn xmp_list(xmp: &str, name: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let Some(start) = xmp.find(&open).map(|x| x + open.len()) else { return vec![] };
+    let Some(len) = xmp[start..].find(&close) else { return vec![] };
+    xmp[start..start + len].split("<rdf:li").skip(1)
+        .filter_map(|item| item.get(item.find('>')? + 1..item.find("</rdf:li>")?).map(|x| unescape_xml(x.trim())))
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+// XMP dates are ISO 8601 and may leave out the time or carry a timezone
+fn normalize_xmp_date(date: &str) -> Option<String> {
+    let date = date.replace('T', " ");
+    match date.len() {
+        10 => Some(format!("{} 00:00:00", date)),
+        16 => Some(format!("{}:00", date)),
+        19.. if date.is_ascii() => Some(date[..19].to_string()),
+        _ => None,
+    }
+}
@@ -0,0 +1,114 @@
+use std::time::SystemTime;
+use anyhow::{bail, Context};
+use dirs::config_local_dir;
+
+use crate::TABLE_VERSION;
+
+// Oldest database layout that can be upgraded, created as-is for new databases.
+pub const BASE_VERSION: i64 = 2;
+pub const BASE_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS metadata (refsto_version STRING, table_version INTEGER);
+    CREATE TABLE IF NOT EXISTS watched_dirs ( rowid INTEGER PRIMARY KEY ASC, fullpath TEXT UNIQUE );
+    CREATE TABLE IF NOT EXISTS entries ( entry_id INTEGER PRIMARY KEY ASC, fullpath TEXT UNIQUE, phash BLOB, xxhash BLOB, filesize INTEGER, mtime INTEGER, ctime INTEGER, filename TEXT, dircnt INTEGER, ignored BOOLEAN DEFAULT 0 );
+    CREATE TABLE IF NOT EXISTS hash_dupe_sets ( hdset_id INTEGER PRIMARY KEY ASC, hamming_distance INTEGER);
+    CREATE TABLE IF NOT EXISTS hash_dupe_sets_x_entries ( hdset_id INTEGER, entry_id INTEGER );";
+
+// MIGRATIONS[i] upgrades a database from version BASE_VERSION+i to BASE_VERSION+i+1.
+// Append only, never edit a step that has been released.
+const MIGRATIONS: &[&str] = &[
+    // 2 -> 3: persisted user settings
+    "CREATE TABLE settings ( key TEXT PRIMARY KEY, value TEXT );",
+    // 3 -> 4: journal of destructive operations
+    "CREATE TABLE operations ( op_id INTEGER PRIMARY KEY ASC, kind TEXT, time INTEGER, undone BOOLEAN DEFAULT 0 );
+    CREATE TABLE journal ( journal_id INTEGER PRIMARY KEY ASC, op_id INTEGER, action TEXT, fullpath TEXT, dest TEXT, xxhash BLOB, phash BLOB, filesize INTEGER, filename TEXT, dircnt INTEGER, undone BOOLEAN DEFAULT 0 );",
+    // 4 -> 5: scan rules, global where watched_dir is NULL
+    "CREATE TABLE scan_rules ( rule_id INTEGER PRIMARY KEY ASC, watched_dir TEXT, kind TEXT, pattern TEXT );
+    ALTER TABLE watched_dirs ADD COLUMN max_depth INTEGER;
+    ALTER TABLE watched_dirs ADD COLUMN skip_hidden BOOLEAN DEFAULT 0;",
+    // 5 -> 6: symlink and mount handling, inode of each entry
+    "ALTER TABLE watched_dirs ADD COLUMN follow_symlinks BOOLEAN DEFAULT 0;
+    ALTER TABLE watched_dirs ADD COLUMN one_filesystem BOOLEAN DEFAULT 0;
+    ALTER TABLE entries ADD COLUMN dev INTEGER;
+    ALTER TABLE entries ADD COLUMN ino INTEGER;",
+    // 6 -> 7: volume each watched directory is on, and where on it
+    "ALTER TABLE watched_dirs ADD COLUMN volume_id TEXT;
+    ALTER TABLE watched_dirs ADD COLUMN volume_path TEXT;",
+    // 7 -> 8: configurable perceptual hash, entries.phash being of the kind in use and hashes holding every kind computed
+    "ALTER TABLE metadata ADD COLUMN hash_kind TEXT DEFAULT 'gradient:8';
+    CREATE TABLE hashes ( entry_id INTEGER, kind TEXT, phash BLOB, PRIMARY KEY (entry_id, kind) );
+    INSERT INTO hashes (entry_id, kind, phash) SELECT entry_id, 'gradient:8', phash FROM entries WHERE phash IS NOT NULL;
+    CREATE TRIGGER hashes_insert AFTER INSERT ON entries WHEN NEW.phash IS NOT NULL BEGIN
+        INSERT OR REPLACE INTO hashes (entry_id, kind, phash) SELECT NEW.entry_id, hash_kind, NEW.phash FROM metadata;
+    END;
+    CREATE TRIGGER hashes_update AFTER UPDATE OF phash ON entries WHEN NEW.phash IS NOT NULL BEGIN
+        INSERT OR REPLACE INTO hashes (entry_id, kind, phash) SELECT NEW.entry_id, hash_kind, NEW.phash FROM metadata;
+    END;
+    CREATE TRIGGER hashes_delete AFTER DELETE ON entries BEGIN
+        DELETE FROM hashes WHERE entry_id = OLD.entry_id;
+    END;",
+    // 8 -> 9: phashes as raw bytes instead of base64, compared in SQL with hamming()
+    "UPDATE entries SET phash = phash_from_base64(phash) WHERE typeof(phash) = 'text';
+    UPDATE hashes SET phash = phash_from_base64(phash) WHERE typeof(phash) = 'text';
+    DELETE FROM hashes WHERE phash IS NULL;
+    UPDATE journal SET phash = phash_from_base64(phash) WHERE typeof(phash) = 'text';",
+    // 9 -> 10: hashes of rotated and mirrored variants, and which of them matched within a set
+    "CREATE TABLE transform_hashes ( entry_id INTEGER, kind TEXT, transform TEXT, phash BLOB, PRIMARY KEY (entry_id, kind, transform) );
+    CREATE TRIGGER transform_hashes_delete AFTER DELETE ON entries BEGIN
+        DELETE FROM transform_hashes WHERE entry_id = OLD.entry_id;
+    END;
+    ALTER TABLE hash_dupe_sets_x_entries ADD COLUMN transform TEXT DEFAULT 'identity';",
+    // 10 -> 11: EXIF orientation images were turned upright by before hashing, NULL where not checked yet
+    "ALTER TABLE entries ADD COLUMN orientation INTEGER;",
+    // 11 -> 12: EXIF and XMP metadata of images, keywords being a JSON array
+    "CREATE TABLE image_metadata ( entry_id INTEGER PRIMARY KEY, captured TEXT, make TEXT, model TEXT, lens TEXT, gps_lat REAL, gps_lon REAL, width INTEGER, height INTEGER, rating INTEGER, keywords TEXT, field_cnt INTEGER );
+    CREATE TRIGGER image_metadata_delete AFTER DELETE ON entries BEGIN
+        DELETE FROM image_metadata WHERE entry_id = OLD.entry_id;
+    END;",
+    // 12 -> 13: decoders there were when an entry was ignored as unreadable, NULL for entries ignored before, retrying them
+    "ALTER TABLE entries ADD COLUMN decoders TEXT;",
+    // 13 -> 14: hashes of frames sampled from videos, in the order they were sampled
+    "CREATE TABLE frame_hashes ( entry_id INTEGER, kind TEXT, frame_idx INTEGER, phash BLOB, PRIMARY KEY (entry_id, kind, frame_idx) );
+    CREATE TRIGGER frame_hashes_delete AFTER DELETE ON entries BEGIN
+        DELETE FROM frame_hashes WHERE entry_id = OLD.entry_id;
+    END;",
+    // 14 -> 15: frames of animations and seconds they play, frame_cnt being 1 for stills and NULL for videos and where not checked yet
+    "ALTER TABLE entries ADD COLUMN frame_cnt INTEGER;
+    ALTER TABLE entries ADD COLUMN duration REAL;",
+    // 15 -> 16: times and inode of forgotten entries, restored along with them on undo, NULL for records from before
+    "ALTER TABLE journal ADD COLUMN mtime INTEGER;
+    ALTER TABLE journal ADD COLUMN ctime INTEGER;
+    ALTER TABLE journal ADD COLUMN dev INTEGER;
+    ALTER TABLE journal ADD COLUMN ino INTEGER;",
+];
+
+const _: () = assert!(BASE_VERSION + MIGRATIONS.len() as i64 == TABLE_VERSION, "TABLE_VERSION must match the number of migration steps");
+
+// brings a database at db_version up to TABLE_VERSION, backing it up first if asked to
+pub async fn migrate(pool: &sqlx::SqlitePool, db_version: i64, backup: bool) -> anyhow::Result<()> {
+    if db_version == TABLE_VERSION {
+        return Ok(())
+    }
+    if db_version > TABLE_VERSION {
+        bail!("Database version {} is newer than this version of refsto supports ({}).\nPlease update refsto (currently {}) to open this database.", db_version, TABLE_VERSION, option_env!("CARGO_PKG_VERSION").unwrap());
+    }
+    if db_version < BASE_VERSION {
+        bail!("Database version {} is too old to be migrated, oldest supported version is {}.\nTo continue with this version of refsto, move {}/refsto.dat out of the way and re-add your directories.", db_version, BASE_VERSION, config_local_dir().unwrap().to_string_lossy());
+    }
+
+    eprintln!("Migrating database from version {} to {}", db_version, TABLE_VERSION);
+    if backup {
+        let backup_path = config_local_dir().unwrap().join(format!("refsto.dat.v{}-{}.bak", db_version, SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()));
+        eprintln!("Backing up database to {}", backup_path.to_string_lossy());
+        sqlx::query("VACUUM INTO ?").bind(backup_path.to_string_lossy()).execute(pool).await
+            .with_context(|| format!("Failed to back up database to {}, not migrating", backup_path.to_string_lossy()))?;
+    }
+
+    let mut transaction = pool.begin().await?;
+    for (idx, step) in MIGRATIONS.iter().enumerate().skip((db_version - BASE_VERSION) as usize) {
+        sqlx::query(step).execute(&mut *transaction).await
+            .with_context(|| format!("Migration to database version {} failed, database left at version {}", BASE_VERSION + idx as i64 + 1, db_version))?;
+    }
+    sqlx::query("UPDATE metadata SET refsto_version = ?, table_version = ?").bind(option_env!("CARGO_PKG_VERSION").unwrap()).bind(TABLE_VERSION).execute(&mut *transaction).await?;
+    transaction.commit().await?;
+    eprintln!("Database migrated to version {}", TABLE_VERSION);
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::{Arc, Mutex, OnceLock, RwLock, atomic::{AtomicBool, AtomicU64, Ordering::Relaxed}}, time::{Duration, Instant}};
+use futures::StreamExt;
+use serde_json::json;
+use sqlx::sqlite::SqliteQueryResult;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio_rayon::{AsyncThreadPool, rayon::{ThreadPool, ThreadPoolBuilder}};
+use tokio_util::sync::CancellationToken;
+
+use crate::bktree::BkTree;
+use crate::index::{HashIndexer, HashIndexError};
+use crate::rules::ScanRules;
+use crate::walk::walk_dir;
+
+// paths walked ahead of the files being updated, beyond which walking waits
+const WALK_QUEUE: usize = 4096;
+// files read at once, more only seek a disk back and forth between them
+const READ_SLOTS: usize = 4;
+// hash threads run this much nicer than the rest, so the GUI stays responsive with every core hashing
+const HASH_NICENESS: i32 = 10;
+
+// what one stage of a scan got through
+#[derive(Default)]
+pub struct Stage {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    busy_us: AtomicU64, // summed over the files it was handling at once
+    last_us: AtomicU64, // since the scan started, when it was last through with a file
+}
+
+impl Stage {
+    fn record(&self, started: Instant, bytes: u64, busy: Duration) {
+        self.files.fetch_add(1, Relaxed);
+        self.bytes.fetch_add(bytes, Relaxed);
+        self.busy_us.fetch_add(busy.as_micros() as u64, Relaxed);
+        self.last_us.fetch_max(started.elapsed().as_micros() as u64, Relaxed);
+    }
+
+    pub fn files(&self) -> u64 {
+        self.files.load(Relaxed)
+    }
+
+    // files and MiB a second up to its last file, and how many files it was handling at once on average
+    fn rates(&self) -> (f64, f64, f64) {
+        let secs = self.last_us.load(Relaxed).max(1) as f64 / 1_000_000.0;
+        (self.files() as f64 / secs, self.bytes.load(Relaxed) as f64 / (1024.0 * 1024.0) / secs, self.busy_us.load(Relaxed) as f64 / 1_000_000.0 / secs)
+    }
+}
+
+// how fast each stage of a scan goes, the slowest holding up the others
+pub struct Throughput {
+    started: Instant,
+    walked: AtomicBool,
+    pub walk: Stage,
+    pub read: Stage,
+    pub hash: Stage, // decoding and hashing
+}
+
+impl Default for Throughput {
+    fn default() -> Self {
+        Throughput { started: Instant::now(), walked: AtomicBool::new(false), walk: Stage::default(), read: Stage::default(), hash: Stage::default() }
+    }
+}
+
+impl Throughput {
+    pub fn record_walk(&self) {
+        self.walk.record(self.started, 0, Duration::ZERO);
+    }
+
+    pub fn record_read(&self, bytes: u64, busy: Duration) {
+        self.read.record(self.started, bytes, busy);
+    }
+
+    pub fn record_hash(&self, busy: Duration) {
+        self.hash.record(self.started, 0, busy);
+    }
+
+    // every watched directory walked and the files found taken up, though they may still be updating
+    pub fn walked(&self) -> bool {
+        self.walked.load(Relaxed)
+    }
+
+    pub fn summary(&self) -> String {
+        let ((walk_rate, _, _), (_, read_mb, read_busy), (hash_rate, _, hash_busy)) = (self.walk.rates(), self.read.rates(), self.hash.rates());
+        format!("Walked {} files at {:.0}/s, read {} at {:.1} MiB/s ({:.1} at once), hashed {} at {:.1}/s ({:.1} at once)",
+            self.walk.files(), walk_rate, self.read.files(), read_mb, read_busy, self.hash.files(), hash_rate, hash_busy)
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        let ((walk_rate, _, _), (read_rate, read_mb, read_busy), (hash_rate, _, hash_busy)) = (self.walk.rates(), self.read.rates(), self.hash.rates());
+        json!({
+            "walk": {"files": self.walk.files(), "files_per_sec": walk_rate},
+            "read": {"files": self.read.files(), "files_per_sec": read_rate, "mib_per_sec": read_mb, "at_once": read_busy},
+            "hash": {"files": self.hash.files(), "files_per_sec": hash_rate, "at_once": hash_busy},
+        })
+    }
+}
+
+fn hash_threads() -> usize {
+    std::thread::available_parallelism().map_or(4, |x| x.get())
+}
+
+// a thread per core for decoding and hashing, leaving the runtime's threads free for reading files and the database
+fn hash_pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .num_threads(hash_threads())
+            .thread_name(|idx| format!("refsto-hash-{}", idx))
+            // on Linux the priority of the process set from one of its threads is that thread's alone
+            .start_handler(|_| unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, HASH_NICENESS); })
+            .build()
+            .expect("Building hash thread pool failed!")
+    })
+}
+
+// runs work on the hash pool, waiting for it without holding up the runtime
+pub async fn on_hash_pool<R: Send + 'static>(work: impl FnOnce() -> R + Send + 'static) -> R {
+    hash_pool().spawn_async(work).await
+}
+
+pub async fn read_slot() -> SemaphorePermit<'static> {
+    static READS: Semaphore = Semaphore::const_new(READ_SLOTS);
+    READS.acquire().await.expect("Read slots closed!")
+}
+
+// walks dirs and updates every file beneath them, each stage only getting so far ahead of the next: walking waits once
+// WALK_QUEUE files are queued, at most READ_SLOTS files are read at once, and files are decoded and hashed a core each,
+// enough updates running to keep the hash pool busy while others read
+pub async fn scan(db_pool: sqlx::SqlitePool, phash_index: Arc<RwLock<BkTree>>, dirs: Vec<PathBuf>, throughput: Arc<Throughput>, cancel: CancellationToken, mut on_result: impl FnMut(&Path, Result<SqliteQueryResult, HashIndexError>)) {
+    let (tx, rx) = tokio::sync::mpsc::channel::<PathBuf>(WALK_QUEUE);
+    // watched directories can overlap, finding a file twice
+    let seen: Arc<Mutex<HashSet<PathBuf>>> = Arc::new(Mutex::new(HashSet::new()));
+    for dir in dirs {
+        let rules = ScanRules::load(&db_pool, Some(&dir)).await;
+        let (tx, seen, throughput) = (tx.clone(), seen.clone(), throughput.clone());
+        tokio::task::spawn_blocking(move || walk_dir(dir, &rules, |file| {
+            if !seen.lock().unwrap().insert(file.clone()) {
+                return true
+            }
+            throughput.record_walk();
+            tx.blocking_send(file).is_ok()
+        }));
+    }
+    drop(tx);
+    // the queue running dry is every walk being over, the files found all taken
+    let walked = futures::stream::unfold((rx, throughput.clone()), |(mut rx, throughput)| async move {
+        match rx.recv().await {
+            Some(file) => Some((file, (rx, throughput))),
+            None => { throughput.walked.store(true, Relaxed); None },
+        }
+    });
+    let indexer = Arc::new(HashIndexer::with_phash_index(db_pool, phash_index).with_throughput(throughput.clone()));
+    let mut updates = std::pin::pin!(walked
+        .take_until(cancel.cancelled())
+        .map(|file| {
+            let indexer = indexer.clone();
+            async move {
+                let res = indexer.update(file.to_string_lossy().into()).await;
+                (file, res)
+            }
+        })
+        .buffer_unordered(READ_SLOTS + 2 * hash_threads()));
+    while let Some((file, res)) = updates.next().await {
+        on_result(&file, res);
+    }
+    // walking stops short once cancelled
+    throughput.walked.store(true, Relaxed);
+}
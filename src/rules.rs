@@ -0,0 +1,307 @@
+use std::{path::{Path, PathBuf}, fs};
+use sqlx::{Row, Acquire};
+
+pub const IGNORE_FILE: &str = ".refstoignore";
+
+// gitignore-like glob: without a slash it matches file or directory names at any depth,
+// with one it matches the path relative to where it was given, ** spanning directories
+#[derive(Clone)]
+pub struct Glob {
+    pattern: String,
+    anchored: bool,
+    dir_only: bool,
+    negated: bool, // given with a leading !, letting back in what earlier globs left out
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        let pattern = pattern.trim();
+        let negated = pattern.starts_with('!');
+        let pattern = pattern.trim_start_matches('!');
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        Glob { pattern: pattern.trim_start_matches('/').to_string(), anchored, dir_only, negated }
+    }
+
+    pub fn matches(&self, rel_path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false
+        }
+        if self.anchored {
+            let components: Vec<String> = rel_path.iter().map(|x| x.to_string_lossy().to_string()).collect();
+            match_components(&self.pattern.split('/').collect::<Vec<_>>(), &components.iter().map(|x| x.as_str()).collect::<Vec<_>>())
+        } else {
+            rel_path.file_name().is_some_and(|x| match_segment(&self.pattern.chars().collect::<Vec<_>>(), &x.to_string_lossy().chars().collect::<Vec<_>>()))
+        }
+    }
+}
+
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(&"**") => (0..=path.len()).any(|skip| match_components(&pattern[1..], &path[skip..])),
+        Some(segment) => !path.is_empty()
+            && match_segment(&segment.chars().collect::<Vec<_>>(), &path[0].chars().collect::<Vec<_>>())
+            && match_components(&pattern[1..], &path[1..]),
+    }
+}
+
+// *, ? and [...] classes within one path component
+fn match_segment(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| match_segment(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && match_segment(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let Some(end) = pattern.iter().skip(2).position(|&c| c == ']').map(|x| x + 2) else {
+                return name.first() == Some(&'[') && match_segment(&pattern[1..], &name[1..])
+            };
+            let Some(&c) = name.first() else { return false };
+            let (negated, class) = match pattern[1] {
+                '!' | '^' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            let mut in_class = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i+1] == '-' {
+                    in_class |= class[i] <= c && c <= class[i+2];
+                    i += 3;
+                } else {
+                    in_class |= class[i] == c;
+                    i += 1;
+                }
+            }
+            in_class != negated && match_segment(&pattern[end+1..], &name[1..])
+        },
+        Some(&p) => name.first() == Some(&p) && match_segment(&pattern[1..], &name[1..]),
+    }
+}
+
+// which files beneath a watched directory get indexed
+#[derive(Clone, Default)]
+pub struct ScanRules {
+    pub includes: Vec<String>, // files must match one of these, if any are given
+    pub excludes: Vec<String>,
+    pub max_depth: Option<usize>, // directory levels descended below the watched directory
+    pub skip_hidden: bool,
+    pub follow_symlinks: bool,
+    pub one_filesystem: bool, // don't descend into other mounts
+}
+
+impl ScanRules {
+    // rules of dir merged with the global ones, dir being None for just the global rules
+    pub async fn load(db_pool: &sqlx::SqlitePool, dir: Option<&Path>) -> Self {
+        Self::load_with(db_pool, dir, true).await
+    }
+
+    // rules of dir without the global ones, for editing
+    pub async fn load_own(db_pool: &sqlx::SqlitePool, dir: Option<&Path>) -> Self {
+        Self::load_with(db_pool, dir, false).await
+    }
+
+    async fn load_with(db_pool: &sqlx::SqlitePool, dir: Option<&Path>, with_global: bool) -> Self {
+        let mut conn = loop {
+            if let Ok(acquisition) = db_pool.acquire().await {
+                break acquisition;
+            }
+        };
+        let conn = conn.acquire().await.unwrap();
+        let dir = dir.map(|x| x.to_string_lossy().to_string());
+        let mut rules = ScanRules::default();
+        for row in sqlx::query("SELECT kind, pattern FROM scan_rules WHERE (watched_dir IS NULL AND ?) OR watched_dir IS ? ORDER BY rule_id").bind(with_global).bind(&dir).fetch_all(&mut *conn).await.expect("SELECT from scan_rules failed!") {
+            match row.get::<&str,_>("kind") {
+                "include" => rules.includes.push(row.get("pattern")),
+                _ => rules.excludes.push(row.get("pattern")),
+            }
+        }
+        if let Some(row) = sqlx::query("SELECT max_depth, skip_hidden, follow_symlinks, one_filesystem FROM watched_dirs WHERE fullpath = ?").bind(&dir).fetch_optional(&mut *conn).await.expect("SELECT from watched_dirs failed!") {
+            rules.max_depth = row.get::<Option<i64>,_>("max_depth").map(|x| x as usize);
+            rules.skip_hidden = row.get("skip_hidden");
+            rules.follow_symlinks = row.get("follow_symlinks");
+            rules.one_filesystem = row.get("one_filesystem");
+        }
+        rules
+    }
+
+    // replaces the rules of dir, or the global ones for None
+    pub async fn save(&self, db_pool: &sqlx::SqlitePool, dir: Option<&Path>) -> anyhow::Result<()> {
+        let dir = dir.map(|x| x.to_string_lossy().to_string());
+        let mut transaction = db_pool.begin().await?;
+        sqlx::query("DELETE FROM scan_rules WHERE watched_dir IS ?").bind(&dir).execute(&mut *transaction).await?;
+        for (kind, patterns) in [("include", &self.includes), ("exclude", &self.excludes)] {
+            for pattern in patterns.iter().filter(|x| !x.trim().is_empty()) {
+                sqlx::query("INSERT INTO scan_rules (watched_dir, kind, pattern) VALUES (?, ?, ?)").bind(&dir).bind(kind).bind(pattern.trim()).execute(&mut *transaction).await?;
+            }
+        }
+        if dir.is_some() {
+            sqlx::query("UPDATE watched_dirs SET max_depth = ?, skip_hidden = ?, follow_symlinks = ?, one_filesystem = ? WHERE fullpath = ?").bind(self.max_depth.map(|x| x as i64)).bind(self.skip_hidden).bind(self.follow_symlinks).bind(self.one_filesystem).bind(&dir).execute(&mut *transaction).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    // whether path beneath root is let through by these rules and every .refstoignore on the way
+    pub fn allows(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let Ok(rel_path) = path.strip_prefix(root) else { return false };
+        let mut ignores = vec![];
+        let mut dir = root.to_owned();
+        let components: Vec<_> = rel_path.iter().collect();
+        for (depth, component) in components.iter().enumerate() {
+            ignores.extend(read_ignore_file(&dir));
+            dir.push(component);
+            let last = depth + 1 == components.len();
+            if !self.allows_entry(root, &dir, depth, last && !is_dir, &ignores) {
+                return false
+            }
+        }
+        true
+    }
+
+    // checks a single entry found depth directory levels below root, ignores being the .refstoignore globs in effect
+    pub fn allows_entry(&self, root: &Path, path: &Path, depth: usize, is_file: bool, ignores: &[(PathBuf, Glob)]) -> bool {
+        let rel_path = path.strip_prefix(root).unwrap_or(path);
+        if self.skip_hidden && path.file_name().is_some_and(|x| x.to_string_lossy().starts_with('.')) {
+            return false
+        }
+        if !is_file && self.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+            return false
+        }
+        // the last glob to match decides, those of .refstoignore files coming after the rules' and deeper ones after those above
+        let ignored = ignores.iter().rev().find_map(|(base, glob)| path.strip_prefix(base).is_ok_and(|x| glob.matches(x, !is_file)).then_some(!glob.negated));
+        let excluded = ignored.or_else(|| self.excludes.iter().rev().map(|x| Glob::new(x)).find(|x| x.matches(rel_path, !is_file)).map(|x| !x.negated));
+        if excluded == Some(true) {
+            return false
+        }
+        !is_file || self.includes.is_empty() || self.includes.iter().rev().map(|x| Glob::new(x)).find(|x| x.matches(rel_path, false)).is_some_and(|x| !x.negated)
+    }
+}
+
+// globs of the .refstoignore in dir, one per line, anchored to dir
+pub fn read_ignore_file(dir: &Path) -> Vec<(PathBuf, Glob)> {
+    match fs::read_to_string(dir.join(IGNORE_FILE)) {
+        Ok(contents) => contents.lines()
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .map(|x| (dir.to_owned(), Glob::new(x)))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, rel_path: &str, is_dir: bool) -> bool {
+        Glob::new(pattern).matches(Path::new(rel_path), is_dir)
+    }
+
+    fn rules(excludes: &[&str], includes: &[&str], max_depth: Option<usize>) -> ScanRules {
+        ScanRules {
+            excludes: excludes.iter().map(|x| x.to_string()).collect(),
+            includes: includes.iter().map(|x| x.to_string()).collect(),
+            max_depth,
+            ..ScanRules::default()
+        }
+    }
+
+    fn allows_file(rules: &ScanRules, rel_path: &str) -> bool {
+        let root = Path::new("/lib");
+        let path = root.join(rel_path);
+        rules.allows_entry(root, &path, path.strip_prefix(root).unwrap().iter().count() - 1, true, &[])
+    }
+
+    #[test]
+    fn names_match_at_any_depth() {
+        assert!(matches("*.tmp", "a.tmp", false));
+        assert!(matches("*.tmp", "x/y/a.tmp", false));
+        assert!(!matches("*.tmp", "a.tmp/b", false));
+        assert!(matches("img?.png", "img1.png", false));
+        assert!(!matches("img?.png", "img10.png", false));
+    }
+
+    #[test]
+    fn double_stars_span_directories() {
+        assert!(matches("**/cache", "cache", true));
+        assert!(matches("**/cache", "a/b/cache", true));
+        assert!(!matches("**/cache", "a/cache2", true));
+        assert!(matches("raw/**", "raw/a", false));
+        assert!(matches("raw/**", "raw/a/b/c.xmp", false));
+        assert!(!matches("raw/**", "x/raw/a", false));
+        assert!(matches("raw/**/*.xmp", "raw/a.xmp", false));
+        assert!(matches("raw/**/*.xmp", "raw/a/b/c.xmp", false));
+        assert!(!matches("raw/**/*.xmp", "raw/a/b/c.jpg", false));
+    }
+
+    #[test]
+    fn slashes_anchor_to_where_the_glob_was_given() {
+        assert!(matches("/thumbs", "thumbs", true));
+        assert!(!matches("/thumbs", "a/thumbs", true));
+        assert!(matches("a/thumbs", "a/thumbs", true));
+        assert!(!matches("a/thumbs", "b/a/thumbs", true));
+        assert!(matches("thumbs", "b/a/thumbs", true));
+    }
+
+    #[test]
+    fn classes_match_one_character() {
+        assert!(matches("img[0-9].png", "img3.png", false));
+        assert!(!matches("img[0-9].png", "imga.png", false));
+        assert!(matches("[a-cx]*", "xylophone", false));
+        assert!(!matches("[a-cx]*", "dog", false));
+        assert!(matches("[!a-c]*", "dog", false));
+        assert!(!matches("[!a-c]*", "cat", false));
+        // unclosed, the bracket is literal
+        assert!(matches("[abc", "[abc", false));
+    }
+
+    #[test]
+    fn trailing_slashes_match_directories_only() {
+        assert!(matches("cache/", "a/cache", true));
+        assert!(!matches("cache/", "a/cache", false));
+        assert!(matches("cache", "a/cache", false));
+    }
+
+    #[test]
+    fn last_matching_glob_decides() {
+        let let_back_in = rules(&["*.tmp", "!keep.tmp"], &[], None);
+        assert!(!allows_file(&let_back_in, "a/other.tmp"));
+        assert!(allows_file(&let_back_in, "a/keep.tmp"));
+        let left_out_again = rules(&["!keep.tmp", "*.tmp"], &[], None);
+        assert!(!allows_file(&left_out_again, "a/keep.tmp"));
+        let only_jpegs = rules(&[], &["*.jpg", "!private*"], None);
+        assert!(allows_file(&only_jpegs, "a/b.jpg"));
+        assert!(!allows_file(&only_jpegs, "a/b.png"));
+        assert!(!allows_file(&only_jpegs, "a/private.jpg"));
+    }
+
+    #[test]
+    fn directories_are_not_descended_past_max_depth() {
+        let root = Path::new("/lib");
+        let shallow = rules(&[], &[], Some(1));
+        assert!(shallow.allows_entry(root, &root.join("a"), 0, false, &[]));
+        assert!(shallow.allows_entry(root, &root.join("a/f.jpg"), 1, true, &[]));
+        assert!(!shallow.allows_entry(root, &root.join("a/b"), 1, false, &[]));
+        let flat = rules(&[], &[], Some(0));
+        assert!(flat.allows_entry(root, &root.join("f.jpg"), 0, true, &[]));
+        assert!(!flat.allows_entry(root, &root.join("a"), 0, false, &[]));
+    }
+
+    #[test]
+    fn ignore_files_apply_beneath_their_directory() {
+        let root = std::env::temp_dir().join(format!("refsto-rules-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a").join(IGNORE_FILE), "# scratch\n*.tmp\ncache/\n").unwrap();
+        fs::write(root.join("a/b").join(IGNORE_FILE), "!keep.tmp\n").unwrap();
+        let rules = rules(&["*.xmp"], &[], None);
+        assert!(rules.allows(&root, &root.join("x.tmp"), false));
+        assert!(!rules.allows(&root, &root.join("a/x.tmp"), false));
+        assert!(!rules.allows(&root, &root.join("a/cache/x.jpg"), false));
+        assert!(!rules.allows(&root, &root.join("a/b/x.tmp"), false));
+        assert!(rules.allows(&root, &root.join("a/b/keep.tmp"), false));
+        assert!(!rules.allows(&root, &root.join("a/b/x.xmp"), false));
+        assert!(!rules.allows(Path::new("/elsewhere"), &root.join("a/x.jpg"), false));
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,23 @@
+use sqlx::{Row, Acquire};
+
+pub async fn get_setting(db_pool: &sqlx::SqlitePool, key: &str) -> Option<String> {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    sqlx::query("SELECT value FROM settings WHERE key = ?").bind(key).fetch_optional(conn.acquire().await.unwrap()).await
+        .expect("SELECT from settings failed!")
+        .map(|x| x.get::<String,_>("value"))
+}
+
+pub async fn set_setting(db_pool: &sqlx::SqlitePool, key: &str, value: &str) {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    if let Err(e) = sqlx::query("INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value").bind(key).bind(value).execute(conn.acquire().await.unwrap()).await {
+        eprintln!("Could not save setting {}: {:?}", key, e);
+    }
+}
@@ -0,0 +1,62 @@
+use std::{ffi::CString, os::raw::c_int};
+use libsqlite3_sys as ffi;
+
+type ScalarFn = unsafe extern "C" fn(*mut ffi::sqlite3_context, c_int, *mut *mut ffi::sqlite3_value);
+
+// scalar functions queries rely on, registered on every connection of the pool
+pub async fn register(conn: &mut sqlx::SqliteConnection) -> Result<(), sqlx::Error> {
+    let functions: [(&str, c_int, ScalarFn); 2] = [
+        ("hamming", 2, hamming),
+        ("phash_from_base64", 1, phash_from_base64),
+    ];
+    let mut handle = conn.lock_handle().await?;
+    let db = handle.as_raw_handle().as_ptr();
+    for (name, n_args, func) in functions {
+        let c_name = CString::new(name).unwrap();
+        let res = unsafe {
+            ffi::sqlite3_create_function_v2(db, c_name.as_ptr(), n_args, ffi::SQLITE_UTF8 | ffi::SQLITE_DETERMINISTIC, std::ptr::null_mut(), Some(func), None, None, None)
+        };
+        if res != ffi::SQLITE_OK {
+            return Err(sqlx::Error::Protocol(format!("Registering SQL function {} failed with code {}", name, res)))
+        }
+    }
+    Ok(())
+}
+
+unsafe fn blob_arg<'a>(value: *mut ffi::sqlite3_value) -> Option<&'a [u8]> {
+    if ffi::sqlite3_value_type(value) != ffi::SQLITE_BLOB {
+        return None
+    }
+    let len = ffi::sqlite3_value_bytes(value) as usize;
+    if len == 0 {
+        return Some(&[])
+    }
+    Some(std::slice::from_raw_parts(ffi::sqlite3_value_blob(value) as *const u8, len))
+}
+
+// hamming(a, b): bits differing between two phashes, NULL unless both are blobs of the same length
+unsafe extern "C" fn hamming(ctx: *mut ffi::sqlite3_context, _argc: c_int, argv: *mut *mut ffi::sqlite3_value) {
+    match (blob_arg(*argv), blob_arg(*argv.add(1))) {
+        (Some(a), Some(b)) if a.len() == b.len() => {
+            ffi::sqlite3_result_int64(ctx, a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones() as i64).sum());
+        },
+        _ => ffi::sqlite3_result_null(ctx),
+    }
+}
+
+// phash_from_base64(text): phashes as stored before they were blobs, NULL if malformed so they get rehashed
+unsafe extern "C" fn phash_from_base64(ctx: *mut ffi::sqlite3_context, _argc: c_int, argv: *mut *mut ffi::sqlite3_value) {
+    let value = *argv;
+    if ffi::sqlite3_value_type(value) != ffi::SQLITE_TEXT {
+        return ffi::sqlite3_result_value(ctx, value)
+    }
+    let text = ffi::sqlite3_value_text(value);
+    if text.is_null() {
+        return ffi::sqlite3_result_null(ctx)
+    }
+    let text = std::slice::from_raw_parts(text, ffi::sqlite3_value_bytes(value) as usize);
+    match std::str::from_utf8(text).ok().and_then(|x| image_hasher::ImageHash::<Box<[u8]>>::from_base64(x).ok()) {
+        Some(phash) => ffi::sqlite3_result_blob(ctx, phash.as_bytes().as_ptr() as *const _, phash.as_bytes().len() as c_int, ffi::SQLITE_TRANSIENT()),
+        None => ffi::sqlite3_result_null(ctx),
+    }
+}
@@ -0,0 +1,55 @@
+use std::{path::Path, process::Command, time::Duration};
+use image::{DynamicImage, RgbImage};
+
+use crate::bktree::hamming;
+use crate::decode::run_within;
+
+// frames sampled from each video, evenly over its length
+pub const FRAME_CNT: usize = 16;
+// frames are scaled to a square this size, more than any hash size needs
+pub const FRAME_SIDE: u32 = 128;
+// how long ffprobe or ffmpeg may take over one call before it is killed, each video taking a call per frame
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(30);
+
+const VIDEO_EXTENSIONS: &[&str] = &["3gp", "avi", "flv", "m2ts", "m4v", "mkv", "mov", "mp4", "mpeg", "mpg", "mts", "ogv", "webm", "wmv"];
+
+pub fn is_video(path: &Path) -> bool {
+    path.extension().is_some_and(|x| VIDEO_EXTENSIONS.contains(&x.to_string_lossy().to_lowercase().as_str()))
+}
+
+// length in seconds, as ffprobe reads it from the container
+fn duration(path: &Path) -> Option<f64> {
+    let (_, output) = run_within(Command::new("ffprobe").args(["-v", "error", "-show_entries", "format=duration", "-of", "default=noprint_wrappers=1:nokey=1"]).arg(path), FFMPEG_TIMEOUT)?;
+    String::from_utf8_lossy(&output).trim().parse::<f64>().ok().filter(|x| x.is_finite() && *x > 0.0)
+}
+
+// the frame shown at secs, seeking to the keyframe before it
+fn frame_at(path: &Path, secs: f64) -> Option<DynamicImage> {
+    let (_, output) = run_within(Command::new("ffmpeg").args(["-nostdin", "-v", "error", "-ss", &format!("{:.3}", secs), "-i"]).arg(path)
+        .args(["-an", "-sn", "-frames:v", "1", "-vf", &format!("scale={}:{}", FRAME_SIDE, FRAME_SIDE), "-pix_fmt", "rgb24", "-f", "rawvideo", "pipe:1"]), FFMPEG_TIMEOUT)?;
+    RgbImage::from_raw(FRAME_SIDE, FRAME_SIDE, output).map(DynamicImage::ImageRgb8)
+}
+
+// the middle frame, what a video is shown as
+pub fn poster(path: &Path) -> Option<DynamicImage> {
+    frame_at(path, duration(path)? / 2.0)
+}
+
+// FRAME_CNT frames from the middle of equal parts of the video, fewer where seeking fails near the end, and its length
+pub fn sample_frames(path: &Path) -> Option<(Vec<DynamicImage>, f64)> {
+    let duration = duration(path)?;
+    let frames: Vec<DynamicImage> = (0..FRAME_CNT).filter_map(|idx| frame_at(path, duration * (idx as f64 + 0.5) / FRAME_CNT as f64)).collect();
+    (!frames.is_empty()).then_some((frames, duration))
+}
+
+// how far apart two videos are: the distance within which at least half the frames of the one with fewer frames
+// have a counterpart anywhere in the other, so trimmed copies, whose frames are sampled at other times, still match
+pub fn sequence_distance(a: &[Box<[u8]>], b: &[Box<[u8]>]) -> u32 {
+    let (fewer, more) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    let mut closest: Vec<u32> = fewer.iter().map(|x| more.iter().filter_map(|y| hamming(x, y)).min().unwrap_or(u32::MAX)).collect();
+    if closest.is_empty() {
+        return u32::MAX
+    }
+    closest.sort();
+    closest[(closest.len() - 1) / 2]
+}
@@ -0,0 +1,249 @@
+use std::{fs, io::{Read, Seek}, path::{Path, PathBuf}, collections::HashSet, os::{fd::AsRawFd, unix::fs::MetadataExt}};
+use sqlx::{Row, Acquire};
+use tokio_util::sync::CancellationToken;
+
+use crate::rules::ScanRules;
+use crate::walk::walk_dir;
+use crate::watcher::{DirWatcher, WatchEvent, like_beneath};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const NETWORK_FSTYPES: &[&str] = &["nfs", "nfs4", "cifs", "smb3", "smbfs", "fuse.sshfs", "9p"];
+
+// a filesystem as mounted in /proc/self/mountinfo
+struct Mount {
+    dev: u64, // st_dev of everything on it
+    root: PathBuf, // directory of the filesystem mounted, / unless bind mounted
+    mount_point: PathBuf,
+    fstype: String,
+    source: String,
+}
+
+fn mounts() -> Vec<Mount> {
+    let Ok(mountinfo) = fs::read_to_string(MOUNTINFO) else { return vec![] };
+    mountinfo.lines().filter_map(|line| {
+        // id parent major:minor root mount_point options [optional fields] - fstype source superoptions
+        let (mount, fs) = line.split_once(" - ")?;
+        let mount: Vec<&str> = mount.split(' ').collect();
+        let fs: Vec<&str> = fs.split(' ').collect();
+        let (major, minor) = mount.get(2)?.split_once(':')?;
+        Some(Mount {
+            dev: libc::makedev(major.parse().ok()?, minor.parse().ok()?),
+            root: PathBuf::from(unescape(mount.get(3)?)),
+            mount_point: PathBuf::from(unescape(mount.get(4)?)),
+            fstype: fs.first()?.to_string(),
+            source: unescape(fs.get(1)?),
+        })
+    }).collect()
+}
+
+// mountinfo escapes spaces, tabs, newlines and backslashes in paths as octal
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let octal: String = chars.clone().take(3).collect();
+            if let Ok(byte) = u8::from_str_radix(&octal, 8) {
+                unescaped.push(byte as char);
+                chars.nth(2);
+                continue
+            }
+        }
+        unescaped.push(c);
+    }
+    unescaped
+}
+
+// filesystem UUID or label where there is one, the share for network filesystems, else whatever is mounted
+fn volume_id(mount: &Mount) -> String {
+    if NETWORK_FSTYPES.contains(&mount.fstype.as_str()) {
+        return format!("net:{}", mount.source)
+    }
+    if let Ok(device) = fs::canonicalize(&mount.source) {
+        for (kind, dir) in [("uuid", "/dev/disk/by-uuid"), ("label", "/dev/disk/by-label")] {
+            for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+                if fs::canonicalize(entry.path()).is_ok_and(|x| x == device) {
+                    return format!("{}:{}", kind, entry.file_name().to_string_lossy())
+                }
+            }
+        }
+    }
+    format!("{}:{}", mount.fstype, mount.source)
+}
+
+// the volume dir is on and where dir is within it, so it can be found again wherever the volume is mounted next
+pub fn locate(dir: &Path) -> Option<(String, PathBuf)> {
+    let dev = dir.metadata().ok()?.dev();
+    let mounts = mounts();
+    let mount = mounts.iter()
+        .filter(|x| x.dev == dev && dir.starts_with(&x.mount_point))
+        .max_by_key(|x| x.mount_point.as_os_str().len())?;
+    Some((volume_id(mount), mount.root.join(dir.strip_prefix(&mount.mount_point).ok()?)))
+}
+
+// where the directory at volume_path on volume_id is mounted right now, if anywhere
+pub fn find(volume_id: &str, volume_path: &Path) -> Option<PathBuf> {
+    mounts().iter()
+        .filter(|x| volume_id_matches(x, volume_id))
+        .filter_map(|x| volume_path.strip_prefix(&x.root).ok().map(|rel| x.mount_point.join(rel)))
+        .find(|x| x.is_dir())
+}
+
+fn volume_id_matches(mount: &Mount, volume_id: &str) -> bool {
+    // skips resolving /dev/disk for mounts that can't be it
+    match volume_id.split_once(':') {
+        Some(("net", source)) => NETWORK_FSTYPES.contains(&mount.fstype.as_str()) && mount.source == source,
+        Some(("uuid" | "label", _)) => mount.source.starts_with("/dev/") && self::volume_id(mount) == volume_id,
+        _ => self::volume_id(mount) == volume_id,
+    }
+}
+
+// whether file is beneath one of the offline watched directories
+pub fn is_offline(file: &Path, offline: &HashSet<PathBuf>) -> bool {
+    offline.iter().any(|x| file.starts_with(x))
+}
+
+fn is_online(dir: &Path, volume_id: Option<&str>) -> bool {
+    match volume_id {
+        Some(volume_id) => locate(dir).is_some_and(|x| x.0 == volume_id),
+        None => dir.is_dir(),
+    }
+}
+
+// watched directories whose volume isn't mounted where they were last seen
+pub async fn offline_dirs(db_pool: &sqlx::SqlitePool) -> HashSet<PathBuf> {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    let conn = conn.acquire().await.unwrap();
+    let dirs: Vec<(PathBuf, Option<String>)> = sqlx::query("SELECT fullpath, volume_id FROM watched_dirs").fetch_all(&mut *conn).await
+        .expect("SELECT from watched_dirs failed!")
+        .iter().map(|x| (PathBuf::from(x.get::<String,_>("fullpath")), x.get("volume_id"))).collect();
+    tokio::task::spawn_blocking(move || dirs.into_iter().filter(|(dir, volume_id)| !is_online(dir, volume_id.as_deref())).map(|x| x.0).collect()).await
+        .expect("Checking volumes failed!")
+}
+
+// the watched directories that can be walked, saying why the others are skipped
+pub async fn scanned_dirs(db_pool: &sqlx::SqlitePool, watched_dirs: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+    let offline = offline_dirs(db_pool).await;
+    let mut dirs = vec![];
+    for dir in watched_dirs {
+        if offline.contains(&dir) {
+            eprintln!("Skipping {}, its volume is offline", dir.to_string_lossy());
+            continue
+        }
+        if !dir.is_dir() {
+            eprintln!("{} is not a directory!!", dir.to_string_lossy());
+            continue
+        }
+        dirs.push(dir);
+    }
+    dirs
+}
+
+// follows watched directories to wherever their volume is mounted now, moving their entries and rules along,
+// and tags those not yet tagged with the volume they are on, returning the (old, new) path of every directory moved
+pub async fn reconcile(db_pool: &sqlx::SqlitePool) -> Vec<(PathBuf, PathBuf)> {
+    let mut conn = loop {
+        if let Ok(acquisition) = db_pool.acquire().await {
+            break acquisition;
+        }
+    };
+    let conn = conn.acquire().await.unwrap();
+    let dirs: Vec<(PathBuf, Option<String>, Option<String>)> = sqlx::query("SELECT fullpath, volume_id, volume_path FROM watched_dirs").fetch_all(&mut *conn).await
+        .expect("SELECT from watched_dirs failed!")
+        .iter().map(|x| (PathBuf::from(x.get::<String,_>("fullpath")), x.get("volume_id"), x.get("volume_path"))).collect();
+    let watched: HashSet<PathBuf> = dirs.iter().map(|x| x.0.to_owned()).collect();
+    let mut moved = vec![];
+    for (dir, volume_id, volume_path) in dirs {
+        let (volume_id, volume_path) = match (volume_id, volume_path) {
+            (Some(volume_id), Some(volume_path)) => (volume_id, PathBuf::from(volume_path)),
+            _ => {
+                let check_dir = dir.clone();
+                if let Some((volume_id, volume_path)) = tokio::task::spawn_blocking(move || locate(&check_dir)).await.unwrap() {
+                    sqlx::query("UPDATE watched_dirs SET volume_id = ?, volume_path = ? WHERE fullpath = ?").bind(volume_id).bind(volume_path.to_string_lossy()).bind(dir.to_string_lossy()).execute(&mut *conn).await
+                        .expect("UPDATE watched_dirs failed!");
+                }
+                continue
+            },
+        };
+        let check_volume_id = volume_id.clone();
+        let check_dir = dir.clone();
+        let Some(new_dir) = tokio::task::spawn_blocking(move || {
+            if is_online(&check_dir, Some(&check_volume_id)) { None } else { find(&check_volume_id, &volume_path) }
+        }).await.unwrap() else { continue };
+        if watched.contains(&new_dir) {
+            eprintln!("{} is mounted at {}, which is already watched", volume_id, new_dir.to_string_lossy());
+            continue
+        }
+        if let Err(e) = move_watched_dir(&mut *conn, &dir, &new_dir).await {
+            eprintln!("Could not follow {} to {}: {:?}", dir.to_string_lossy(), new_dir.to_string_lossy(), e);
+            continue
+        }
+        moved.push((dir, new_dir));
+    }
+    moved
+}
+
+async fn move_watched_dir(conn: &mut sqlx::SqliteConnection, from: &Path, to: &Path) -> anyhow::Result<()> {
+    let mut transaction = conn.begin().await?;
+    sqlx::query("UPDATE watched_dirs SET fullpath = ? WHERE fullpath = ?").bind(to.to_string_lossy()).bind(from.to_string_lossy()).execute(&mut *transaction).await?;
+    sqlx::query("UPDATE scan_rules SET watched_dir = ? WHERE watched_dir = ?").bind(to.to_string_lossy()).bind(from.to_string_lossy()).execute(&mut *transaction).await?;
+    sqlx::query("UPDATE entries SET fullpath = ? || substr(fullpath, ?), dircnt = dircnt + ? WHERE fullpath LIKE ? ESCAPE '\\'")
+        .bind(to.to_string_lossy()).bind(from.to_string_lossy().chars().count() as i64 + 1).bind(to.ancestors().count() as i64 - from.ancestors().count() as i64).bind(like_beneath(from))
+        .execute(&mut *transaction).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+// blocks until cancelled, calling on_change whenever anything is mounted or unmounted
+fn watch_mounts(cancel: CancellationToken, mut on_change: impl FnMut()) {
+    let Ok(mut mountinfo) = fs::File::open(MOUNTINFO) else { return };
+    let mut contents = vec![];
+    // reading it through is what rearms the poll
+    let _ = mountinfo.read_to_end(&mut contents);
+    while !cancel.is_cancelled() {
+        let mut pollfd = libc::pollfd { fd: mountinfo.as_raw_fd(), events: libc::POLLPRI, revents: 0 };
+        if unsafe { libc::poll(&mut pollfd, 1, 500) } > 0 && pollfd.revents & (libc::POLLPRI | libc::POLLERR) != 0 {
+            contents.clear();
+            let _ = mountinfo.rewind().and_then(|_| mountinfo.read_to_end(&mut contents));
+            on_change();
+        }
+    }
+}
+
+// keeps watched directories in step with volumes coming and going until cancelled:
+// a volume that reappears, wherever it is mounted, is watched again and its files sent to be reindexed
+// on_change gets the directories moved to a new mount point and those still offline
+pub async fn follow_volumes(db_pool: sqlx::SqlitePool, watcher: Option<DirWatcher>, tx: tokio::sync::mpsc::UnboundedSender<WatchEvent>, cancel: CancellationToken, mut on_change: impl FnMut(&[(PathBuf, PathBuf)], &HashSet<PathBuf>)) {
+    let (mount_tx, mut mount_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mounts_cancel = cancel.clone();
+    std::thread::spawn(move || watch_mounts(mounts_cancel, move || { let _ = mount_tx.send(()); }));
+    let mut offline = offline_dirs(&db_pool).await;
+    while mount_rx.recv().await.is_some() {
+        // a single mount can show up as several changes
+        while mount_rx.try_recv().is_ok() {}
+        let moved = reconcile(&db_pool).await;
+        let now_offline = offline_dirs(&db_pool).await;
+        let back_online: Vec<PathBuf> = offline.iter()
+            .map(|dir| moved.iter().find(|x| &x.0 == dir).map_or(dir.to_owned(), |x| x.1.to_owned()))
+            .filter(|dir| !now_offline.contains(dir))
+            .collect();
+        offline = now_offline;
+        on_change(&moved, &offline);
+        if let Some(watcher) = &watcher {
+            for (from, _) in &moved {
+                watcher.remove_dir(from);
+            }
+            watcher.add_dirs(&db_pool, back_online.clone()).await;
+        }
+        for dir in back_online {
+            eprintln!("{} is back online, rescanning", dir.to_string_lossy());
+            let rules = ScanRules::load(&db_pool, Some(&dir)).await;
+            let tx = tx.clone();
+            tokio::task::spawn_blocking(move || walk_dir(dir, &rules, |file| tx.send(WatchEvent::Changed(file)).is_ok()));
+        }
+    }
+}
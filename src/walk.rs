@@ -0,0 +1,55 @@
+use std::{path::PathBuf, collections::HashSet, os::unix::fs::MetadataExt, sync::Arc};
+
+use crate::rules::{Glob, ScanRules, read_ignore_file};
+
+// a directory yet to be walked, its depth below root and the .refstoignore globs of its ancestors
+type PendingDir = (PathBuf, usize, Arc<Vec<(PathBuf, Glob)>>);
+
+// walks dir depth-first, passing every regular file found beneath it that rules let through to found,
+// stopping once found returns false, when whatever it feeds is gone
+pub fn walk_dir(dir: PathBuf, rules: &ScanRules, mut found: impl FnMut(PathBuf) -> bool) {
+    let root = dir.clone();
+    let Ok(root_meta) = root.metadata() else { return };
+    // (st_dev, st_ino) of every directory entered, so symlink and bind mount loops are only walked once
+    let mut visited: HashSet<(u64, u64)> = HashSet::from([(root_meta.dev(), root_meta.ino())]);
+    let mut dirlist: Vec<PendingDir> = vec![(dir, 0, Arc::new(vec![]))];
+    while let Some((dir, depth, ignores)) = dirlist.pop() {
+        let dir_ignores = read_ignore_file(&dir);
+        let ignores = if dir_ignores.is_empty() { ignores } else { Arc::new(ignores.iter().cloned().chain(dir_ignores).collect()) };
+        if let Ok(entries) = dir.read_dir() {
+            let entries: Vec<std::fs::DirEntry> = entries.into_iter().flatten().collect();
+            for entry in entries {
+                let Ok(mut ft) = entry.file_type() else { continue };
+                if ft.is_symlink() {
+                    if !rules.follow_symlinks {
+                        continue
+                    }
+                    match entry.path().metadata() {
+                        Ok(meta) => ft = meta.file_type(),
+                        Err(_) => { eprintln!("Skipping broken symlink {}", entry.path().to_string_lossy()); continue },
+                    }
+                }
+                if !rules.allows_entry(&root, &entry.path(), depth, ft.is_file(), &ignores) {
+                    continue
+                }
+                if ft.is_file() {
+                    if !found(entry.path()) {
+                        return
+                    }
+                } else if ft.is_dir() {
+                    let Ok(meta) = entry.path().metadata() else { continue };
+                    if rules.one_filesystem && meta.dev() != root_meta.dev() {
+                        continue
+                    }
+                    if !visited.insert((meta.dev(), meta.ino())) {
+                        eprintln!("Skipping {}, already walked", entry.path().to_string_lossy());
+                        continue
+                    }
+                    dirlist.push((entry.path(), depth + 1, ignores.clone()));
+                } else {
+                    eprintln!("Can't interpret filetype of: {}", entry.path().to_string_lossy());
+                }
+            }
+        }
+    }
+}
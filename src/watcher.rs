@@ -0,0 +1,263 @@
+use std::{path::{Path, PathBuf}, collections::HashMap, ffi::{CString, OsStr}, io, os::{fd::{AsRawFd, FromRawFd, OwnedFd}, unix::ffi::OsStrExt}, sync::{Arc, Mutex, RwLock}};
+use sqlx::{Row, Acquire};
+use tokio_util::sync::CancellationToken;
+
+use crate::bktree::BkTree;
+use crate::index::HashIndexer;
+use crate::journal::Journal;
+use crate::rules::ScanRules;
+
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DONT_FOLLOW | libc::IN_ONLYDIR;
+const EVENT_HEADER_LEN: usize = std::mem::size_of::<libc::inotify_event>();
+
+pub enum WatchEvent {
+    Changed(PathBuf), // file created or written to
+    Removed(PathBuf), // file or directory, along with everything beneath it
+    Moved(PathBuf, PathBuf), // file or directory moved from one watched path to another
+}
+
+// inotify watches on every directory beneath the watched directories
+// cloned handles share the same watches, so directories can be added while another thread runs the watcher
+#[derive(Clone)]
+pub struct DirWatcher {
+    fd: Arc<OwnedFd>,
+    wds: Arc<Mutex<HashMap<i32, PathBuf>>>,
+    roots: Arc<Mutex<HashMap<PathBuf, ScanRules>>>,
+}
+
+impl DirWatcher {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(DirWatcher { fd: Arc::new(unsafe { OwnedFd::from_raw_fd(fd) }), wds: Arc::new(Mutex::new(HashMap::new())), roots: Arc::new(Mutex::new(HashMap::new())) })
+    }
+
+    // watches a watched directory, or updates the rules it is watched with
+    pub fn add_dir(&self, dir: &Path, rules: ScanRules) {
+        self.roots.lock().unwrap().insert(dir.to_owned(), rules);
+        self.add_tree(dir);
+    }
+
+    // loads the scan rules of each watched directory and watches it with them
+    pub async fn add_dirs(&self, db_pool: &sqlx::SqlitePool, dirs: Vec<PathBuf>) {
+        for dir in dirs {
+            let rules = ScanRules::load(db_pool, Some(&dir)).await;
+            let watcher = self.clone();
+            let _ = tokio::task::spawn_blocking(move || watcher.add_dir(&dir, rules)).await;
+        }
+    }
+
+    pub fn remove_dir(&self, dir: &Path) {
+        self.roots.lock().unwrap().remove(dir);
+        self.drop_watches(dir);
+    }
+
+    // whether the scan rules of the watched directory path is beneath let it through
+    fn allows(&self, path: &Path, is_dir: bool) -> bool {
+        let roots = self.roots.lock().unwrap();
+        match roots.iter().filter(|(root, _)| path.starts_with(root)).max_by_key(|(root, _)| root.as_os_str().len()) {
+            Some((root, rules)) => rules.allows(root, path, is_dir),
+            None => false,
+        }
+    }
+
+    // stops watching dir and every directory beneath it
+    fn drop_watches(&self, dir: &Path) {
+        let mut wds = self.wds.lock().unwrap();
+        wds.retain(|wd, path| {
+            if path.starts_with(dir) {
+                unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), *wd) };
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    // watches every directory beneath dir, returning the files found on the way
+    fn add_tree(&self, dir: &Path) -> Vec<PathBuf> {
+        let mut files = vec![];
+        let mut dirlist = vec![dir.to_owned()];
+        while let Some(dir) = dirlist.pop() {
+            let Ok(c_dir) = CString::new(dir.as_os_str().as_bytes()) else { continue };
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), c_dir.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                eprintln!("Could not watch {}: {}", dir.to_string_lossy(), io::Error::last_os_error());
+                continue
+            }
+            self.wds.lock().unwrap().insert(wd, dir.to_owned());
+            if let Ok(entries) = dir.read_dir() {
+                for entry in entries.flatten() {
+                    match entry.file_type() {
+                        Ok(ft) if ft.is_dir() && self.allows(&entry.path(), true) => dirlist.push(entry.path()),
+                        Ok(ft) if ft.is_file() && self.allows(&entry.path(), false) => files.push(entry.path()),
+                        _ => (),
+                    }
+                }
+            }
+        }
+        files
+    }
+
+    // blocks until cancelled, sending every change beneath the watched directories
+    pub fn run(&self, tx: tokio::sync::mpsc::UnboundedSender<WatchEvent>, cancel: CancellationToken) {
+        let mut buf = vec![0u8; 64 * (EVENT_HEADER_LEN + 256)];
+        // moved-from half of a rename, waiting on its moved-to half
+        let mut pending_move: Option<(u32, PathBuf)> = None;
+        while !cancel.is_cancelled() {
+            let mut pollfd = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            // both halves of a rename are queued together, so a short wait is enough to tell a move out of the watched directories
+            let timeout = if pending_move.is_some() { 10 } else { 500 };
+            if unsafe { libc::poll(&mut pollfd, 1, timeout) } <= 0 {
+                if let Some((_, from)) = pending_move.take() {
+                    self.drop_watches(&from);
+                    let _ = tx.send(WatchEvent::Removed(from));
+                }
+                continue
+            }
+            let len = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len <= 0 {
+                continue
+            }
+            let mut offset = 0;
+            while offset + EVENT_HEADER_LEN <= len as usize {
+                let event = unsafe { std::ptr::read_unaligned(buf.as_ptr().add(offset) as *const libc::inotify_event) };
+                let name = &buf[offset + EVENT_HEADER_LEN..offset + EVENT_HEADER_LEN + event.len as usize];
+                let name = OsStr::from_bytes(name.split(|&b| b == 0).next().unwrap_or_default());
+                offset += EVENT_HEADER_LEN + event.len as usize;
+
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    eprintln!("Filesystem events were lost, RELOAD to rescan watched directories");
+                    continue
+                }
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.wds.lock().unwrap().remove(&event.wd);
+                    continue
+                }
+                let Some(path) = self.wds.lock().unwrap().get(&event.wd).map(|x| x.join(name)) else { continue };
+                let is_dir = event.mask & libc::IN_ISDIR != 0;
+
+                if let Some((cookie, from)) = pending_move.take() {
+                    if event.mask & libc::IN_MOVED_TO != 0 && event.cookie == cookie && !self.allows(&path, is_dir) {
+                        self.drop_watches(&from);
+                        let _ = tx.send(WatchEvent::Removed(from));
+                        continue
+                    }
+                    if event.mask & libc::IN_MOVED_TO != 0 && event.cookie == cookie {
+                        for watched in self.wds.lock().unwrap().values_mut() {
+                            if let Ok(rel) = watched.strip_prefix(&from) {
+                                *watched = path.join(rel);
+                            }
+                        }
+                        let _ = tx.send(WatchEvent::Moved(from, path));
+                        continue
+                    }
+                    self.drop_watches(&from);
+                    let _ = tx.send(WatchEvent::Removed(from));
+                }
+                if event.mask & (libc::IN_CREATE | libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 && !self.allows(&path, is_dir) {
+                    continue
+                }
+                if event.mask & libc::IN_MOVED_FROM != 0 {
+                    pending_move = Some((event.cookie, path));
+                } else if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 && is_dir {
+                    for file in self.add_tree(&path) {
+                        let _ = tx.send(WatchEvent::Changed(file));
+                    }
+                } else if event.mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 {
+                    let _ = tx.send(WatchEvent::Changed(path));
+                } else if event.mask & libc::IN_DELETE != 0 {
+                    let _ = tx.send(WatchEvent::Removed(path));
+                }
+            }
+        }
+    }
+}
+
+// keeps entries in step with watch events as they come in, calling on_applied with the new count of images after each
+pub async fn apply_events(db_pool: sqlx::SqlitePool, phash_index: Arc<RwLock<BkTree>>, mut rx: tokio::sync::mpsc::UnboundedReceiver<WatchEvent>, mut on_applied: impl FnMut(&WatchEvent, i64)) {
+    while let Some(event) = rx.recv().await {
+        // an indexer for each event, so files changed after switching hash kinds are hashed the new way
+        let hi = HashIndexer::with_phash_index(db_pool.clone(), phash_index.clone());
+        let mut conn = loop {
+            if let Ok(acquisition) = db_pool.acquire().await {
+                break acquisition;
+            }
+        };
+        let conn = conn.acquire().await.unwrap();
+        match &event {
+            WatchEvent::Changed(path) => { let _ = hi.update(path.to_string_lossy().into()).await; },
+            WatchEvent::Removed(path) => { hi.forget(&indexed_beneath(&mut *conn, path).await, &Journal::begin(&db_pool, "watch_remove")).await; },
+            WatchEvent::Moved(from, to) => {
+                // whatever was at the destination has been replaced
+                hi.forget(&indexed_beneath(&mut *conn, to).await, &Journal::begin(&db_pool, "watch_remove")).await;
+                let moved = sqlx::query("UPDATE entries SET fullpath = ?, filename = ?, dircnt = ? WHERE fullpath = ?")
+                    .bind(to.to_string_lossy()).bind(to.file_name().map(|x| x.to_string_lossy())).bind(to.ancestors().count() as i64).bind(from.to_string_lossy())
+                    .execute(&mut *conn).await.map(|x| x.rows_affected()).unwrap_or(0);
+                let moved_beneath = sqlx::query("UPDATE entries SET fullpath = ? || substr(fullpath, ?), dircnt = dircnt + ? WHERE fullpath LIKE ? ESCAPE '\\'")
+                    .bind(to.to_string_lossy()).bind(from.to_string_lossy().chars().count() as i64 + 1).bind(to.ancestors().count() as i64 - from.ancestors().count() as i64).bind(like_beneath(from))
+                    .execute(&mut *conn).await.map(|x| x.rows_affected()).unwrap_or(0);
+                // moved in from a file not yet indexed, e.g. a temporary file being renamed into place
+                if moved + moved_beneath == 0 && to.is_file() {
+                    let _ = hi.update(to.to_string_lossy().into()).await;
+                }
+            },
+        }
+        let image_count = sqlx::query("SELECT COUNT(*) FROM entries WHERE ignored = 0;").fetch_one(&mut *conn).await.map(|x| x.get::<i64,_>(0)).unwrap_or(0);
+        on_applied(&event, image_count);
+    }
+}
+
+// the entry at path and every entry beneath it, forgotten like any other entry so sets and the journal keep up
+async fn indexed_beneath(conn: &mut sqlx::SqliteConnection, path: &Path) -> Vec<PathBuf> {
+    match sqlx::query("SELECT fullpath FROM entries WHERE fullpath = ? OR fullpath LIKE ? ESCAPE '\\'").bind(path.to_string_lossy()).bind(like_beneath(path)).fetch_all(conn).await {
+        Ok(rows) => rows.iter().map(|x| PathBuf::from(x.get::<String,_>("fullpath"))).collect(),
+        Err(e) => { eprintln!("Could not look up entries for {}: {:?}", path.to_string_lossy(), e); vec![] },
+    }
+}
+
+// LIKE pattern matching every path beneath dir
+pub fn like_beneath(dir: &Path) -> String {
+    let escaped = dir.to_string_lossy().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("{}/%", escaped.trim_end_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn removals_are_forgotten_and_journaled() {
+        let dir = std::env::temp_dir().join(format!("refsto-watcher-test-{}", std::process::id()));
+        let album = dir.join("album");
+        std::fs::create_dir_all(&album).unwrap();
+        let db_pool = crate::test_database(&dir).await;
+        let phash_index = Arc::new(RwLock::new(BkTree::new()));
+        let hi = HashIndexer::with_phash_index(db_pool.clone(), phash_index.clone());
+        let mut entry_ids = vec![];
+        for (idx, name) in ["a.png", "b.png"].iter().enumerate() {
+            let file = album.join(name);
+            image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, idx as u8])).save(&file).unwrap();
+            entry_ids.push(hi.update(file.to_string_lossy().into()).await.ok().unwrap().last_insert_rowid());
+        }
+        sqlx::query("INSERT INTO hash_dupe_sets (hdset_id, hamming_distance) VALUES (1, 0)").execute(&db_pool).await.unwrap();
+        for entry_id in &entry_ids {
+            sqlx::query("INSERT INTO hash_dupe_sets_x_entries (hdset_id, entry_id) VALUES (1, ?)").bind(entry_id).execute(&db_pool).await.unwrap();
+        }
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let _ = tx.send(WatchEvent::Removed(album.clone()));
+        drop(tx);
+        apply_events(db_pool.clone(), phash_index.clone(), rx, |_, image_count| assert_eq!(image_count, 0)).await;
+        let count = |query: &'static str| { let db_pool = db_pool.clone(); async move { sqlx::query(query).fetch_one(&db_pool).await.unwrap().get::<i64,_>(0) } };
+        assert_eq!(count("SELECT COUNT(*) FROM entries").await, 0);
+        assert_eq!(count("SELECT COUNT(*) FROM hash_dupe_sets_x_entries").await, 0);
+        assert_eq!(count("SELECT COUNT(*) FROM hash_dupe_sets").await, 0);
+        assert_eq!(count("SELECT COUNT(*) FROM journal WHERE action = 'forget'").await, 2);
+        assert_eq!(phash_index.read().unwrap().find_within(&[0; 8], 64), vec![]);
+        db_pool.close().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}